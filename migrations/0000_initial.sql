@@ -0,0 +1,17 @@
+-- Tables used by the backend, as created on Supabase
+
+CREATE TABLE IF NOT EXISTS nats (
+    id uuid PRIMARY KEY,
+    nsc_account_id text NOT NULL,
+    creds_admin text NOT NULL,
+    creds_user text NOT NULL,
+    account_jwt text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    api_key_hash text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+-- Set by the reconciler when a row can not be repaired from the database (ex: nsc account lost)
+ALTER TABLE nats ADD COLUMN IF NOT EXISTS broken_reason text;
@@ -0,0 +1,11 @@
+-- One NATS user per receiving device of a user (ex: laptop, phone), under the account of the user.
+-- The default user (nats.creds_user) is kept for the clients registered before the devices.
+CREATE TABLE IF NOT EXISTS nats_devices (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    name text NOT NULL,
+    nsc_username text NOT NULL,
+    creds text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    UNIQUE (user_id, name)
+);
@@ -0,0 +1,2 @@
+-- Plan of the account, setting the limits of its JWT. NULL for the accounts created before the plans, without limits.
+ALTER TABLE nats ADD COLUMN IF NOT EXISTS plan text;
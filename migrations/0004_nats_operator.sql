@@ -0,0 +1,2 @@
+-- Operator of the nsc keystore the account was created under. NULL for the accounts created before, under the default operator.
+ALTER TABLE nats ADD COLUMN IF NOT EXISTS operator_name text;
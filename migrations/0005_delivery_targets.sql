@@ -0,0 +1,9 @@
+-- Where the notifications of a user are delivered besides NATS (ex: webhooks).
+-- config is the JSON of the target, with its type.
+CREATE TABLE IF NOT EXISTS delivery_targets (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    config text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS delivery_targets_user_id ON delivery_targets (user_id);
@@ -0,0 +1,29 @@
+-- Durable queue of the notifications sent with /send: one row per channel (NATS, then each delivery target),
+-- deleted once delivered. locked_until is set while a worker delivers it, so that a crashed worker only delays it.
+CREATE TABLE IF NOT EXISTS outbound_deliveries (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    channel text NOT NULL,
+    target_id uuid,
+    notification text NOT NULL,
+    attempts integer NOT NULL DEFAULT 0,
+    next_attempt_at timestamptz NOT NULL DEFAULT now(),
+    locked_until timestamptz,
+    last_error text,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS outbound_deliveries_next_attempt_at ON outbound_deliveries (next_attempt_at);
+
+-- Deliveries that failed for good (rejected, or out of attempts), kept until they are retried.
+CREATE TABLE IF NOT EXISTS dead_letters (
+    id uuid PRIMARY KEY,
+    user_id uuid NOT NULL,
+    channel text NOT NULL,
+    target_id uuid,
+    notification text NOT NULL,
+    attempts integer NOT NULL,
+    last_error text NOT NULL,
+    created_at timestamptz NOT NULL,
+    failed_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS dead_letters_user_id ON dead_letters (user_id);
@@ -0,0 +1,21 @@
+-- Rules of a user deciding where its notifications are delivered, the first matching one (by position) applies.
+-- rule is the JSON of the conditions and the action.
+CREATE TABLE IF NOT EXISTS routing_rules (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    position integer NOT NULL,
+    rule text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS routing_rules_user_id ON routing_rules (user_id);
+
+-- Every notification sent with /send, with the routing decision (rule_id is null for the default routing).
+CREATE TABLE IF NOT EXISTS notification_history (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    notification text NOT NULL,
+    rule_id uuid,
+    action text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS notification_history_user_id_created_at ON notification_history (user_id, created_at);
@@ -0,0 +1,17 @@
+-- Do-not-disturb window of a user, settings is the JSON of the timezone and of the schedule.
+CREATE TABLE IF NOT EXISTS quiet_hours (
+    user_id uuid PRIMARY KEY,
+    settings text NOT NULL,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- Non-urgent notifications sent during the quiet hours of the user, delivered as a single digest once they end.
+-- claimed_until is set while a server sends the digest, so that a crashed server only delays it.
+CREATE TABLE IF NOT EXISTS held_notifications (
+    id uuid PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id uuid NOT NULL,
+    notification text NOT NULL,
+    claimed_until timestamptz,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS held_notifications_user_id ON held_notifications (user_id);
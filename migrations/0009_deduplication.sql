@@ -0,0 +1,6 @@
+-- Keys of the deduplication and of the grouping of the notifications, duplicates counts the ones dropped as duplicates of it.
+ALTER TABLE notification_history ADD COLUMN IF NOT EXISTS dedup_key text;
+ALTER TABLE notification_history ADD COLUMN IF NOT EXISTS group_key text;
+ALTER TABLE notification_history ADD COLUMN IF NOT EXISTS duplicates integer NOT NULL DEFAULT 0;
+CREATE INDEX IF NOT EXISTS notification_history_dedup_key ON notification_history (user_id, dedup_key, created_at) WHERE dedup_key IS NOT NULL;
+CREATE INDEX IF NOT EXISTS notification_history_group_key ON notification_history (user_id, group_key, created_at) WHERE group_key IS NOT NULL;
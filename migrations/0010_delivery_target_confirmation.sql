@@ -0,0 +1,7 @@
+-- The email targets are only delivered once their address is confirmed with the token sent to it.
+-- confirmation_token_hash is the SHA-256 of the token, cleared once confirmed.
+-- The existing webhooks stay confirmed, the existing email targets have to be created again.
+ALTER TABLE delivery_targets ADD COLUMN IF NOT EXISTS confirmation_token_hash text;
+ALTER TABLE delivery_targets ADD COLUMN IF NOT EXISTS confirmed_at timestamptz;
+UPDATE delivery_targets SET confirmed_at = created_at
+    WHERE confirmed_at IS NULL AND confirmation_token_hash IS NULL AND config::jsonb->>'type' = 'webhook';
@@ -0,0 +1,2 @@
+-- The history older than HISTORY_RETENTION_DAYS is pruned by the server.
+CREATE INDEX IF NOT EXISTS notification_history_created_at ON notification_history (created_at);
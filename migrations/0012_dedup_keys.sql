@@ -0,0 +1,10 @@
+-- Dedup key of a user held by the first notification with it (history_id) until the end of its window.
+-- Claimed with a single INSERT .. ON CONFLICT, so that two concurrent /send with the same key can not both be delivered.
+CREATE TABLE IF NOT EXISTS notification_dedup_keys (
+    user_id uuid NOT NULL,
+    dedup_key text NOT NULL,
+    history_id uuid NOT NULL,
+    expires_at timestamptz NOT NULL,
+    PRIMARY KEY (user_id, dedup_key)
+);
+CREATE INDEX IF NOT EXISTS notification_dedup_keys_expires_at ON notification_dedup_keys (expires_at);
@@ -1,66 +1,313 @@
+use serde::Serialize;
 use uuid::Uuid;
 
-use crate::nsc_accounts_utils::{check_if_creds_exists, create_nsc_account, create_nsc_user, delete_nsc_account, delete_nsc_user, get_account_jwt, get_creds_path};
+use crate::error::{Error, Result};
+use crate::nats_resolver::{push_account_jwt, remove_account_jwt, AccountResolver};
+use crate::nsc_accounts_utils::{check_if_creds_exists, create_nsc_account_signed, create_nsc_user_with_permissions, delete_nsc_account, delete_nsc_user, describe_nsc_account_id, edit_nsc_account_limits, generate_nsc_user_creds, get_account_creds_dir, get_account_jwt, get_creds_path, get_nsc_store_dir, nsc_account_exists, nsc_user_exists, select_nsc_operator, AccountLimits, UserPermissions};
 use crate::postgres::{
-    delete_nsc_user_from_postgres, get_creds_admin, insert_nsc_user, setup_postgres_client, verify_nsc_user_exists
+    add_api_key, delete_api_keys_of_user, delete_delivery_targets_of_user, delete_devices_of_user, delete_nsc_user_from_postgres, delete_outbound_deliveries_of_user, delete_quiet_hours_of_user, delete_routing_of_user, get_creds_admin, get_nsc_account_id, insert_nsc_user, verify_identity_exists, verify_nsc_user_exists
 };
+use crate::plans::Plans;
+use crate::user_policy::UserPolicy;
 
+use std::fs::{File, OpenOptions};
 use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
 
-pub async fn create_and_insert_user(postgres_client: Arc<tokio_postgres::Client>, creds_base_path: &str, operator_name: &str, username: Uuid) -> Result<(), String> {
-    // Assumption: username is not in nats table yet + username in auth table already
+// Serialize the provisioning: the nsc keystore is not safe for concurrent writes, and two
+// concurrent creations of the same user would rollback each other
+static PROVISIONING_LOCK: Mutex<()> = Mutex::const_new(());
+
+// Held from the selection of the nsc operator to the last nsc command: the current operator of nsc is global
+// to the host, so the lock file in the keystore also keeps the admin CLI and the other servers from switching it
+pub(crate) struct ProvisioningGuard {
+    // Unlocked when closed
+    _store_lock: File,
+    _guard: MutexGuard<'static, ()>,
+}
+
+pub(crate) async fn lock_provisioning() -> Result<ProvisioningGuard> {
+    let guard = PROVISIONING_LOCK.lock().await;
+    let lock_path = format!("{}/.provisioning.lock", get_nsc_store_dir());
+
+    // The file lock is blocking
+    let store_lock = tokio::task::spawn_blocking(move || {
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+            .and_then(|file| file.lock().map(|_| file));
+        file.map_err(|err| Error::nsc(format!("Failed to lock the nsc keystore with {}: {}", lock_path, err)))
+    })
+        .await
+        .map_err(|err| Error::Internal(format!("The nsc keystore lock task failed: {}", err)))??;
+    Ok(ProvisioningGuard { _store_lock: store_lock, _guard: guard })
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserProvisioning {
+    Created,
+    AlreadyProvisioned,
+    // The uuid is not known by the identity source (ex: Supabase auth.users)
+    UnknownIdentity,
+}
+
+// Settings of the provisioning, shared by the server and the admin CLI
+pub struct ProvisioningConfig<'a> {
+    pub creds_base_path: &'a str,
+    pub operator_name: &'a str,
+    // Operator signing key of the account JWT, the operator identity key without it
+    pub signing_key: Option<&'a str>,
+    pub account_resolver: &'a AccountResolver,
+    pub identity_query: Option<&'a str>,
+    pub user_policy: &'a UserPolicy,
+    pub plans: &'a Plans,
+}
+
+pub async fn create_and_insert_user(postgres_client: Arc<tokio_postgres::Client>, config: &ProvisioningConfig<'_>, username: Uuid) -> Result<UserProvisioning> {
+    let ProvisioningConfig { creds_base_path, operator_name, account_resolver, identity_query, .. } = *config;
+    if let Some(identity_query) = identity_query {
+        let identity_exists = verify_identity_exists(Arc::clone(&postgres_client), identity_query, username).await?;
+
+        if !identity_exists {
+            return Ok(UserProvisioning::UnknownIdentity);
+        }
+    }
+
+    let _provisioning_guard = lock_provisioning().await?;
+
+    let user_exists = verify_nsc_user_exists(Arc::clone(&postgres_client), username).await?;
+
+    if user_exists {
+        return Ok(UserProvisioning::AlreadyProvisioned);
+    }
 
     let account_name = username.to_string();
 
-    let nsc_account_id = create_nsc_account(&account_name)
-        .map_err(|err| format!("Failed to create nsc account: {}", err))?;
+    select_nsc_operator(operator_name)?;
 
-    create_nsc_user(&account_name, "user_01")
-        .map_err(|err| format!("Failed to create nsc user: {}", err))?;
-    
-    let creds_path_user = get_creds_path(creds_base_path, operator_name, &account_name, "user_01");
+    // Leftovers of a previous attempt that never reached the database
+    if nsc_account_exists(&account_name) {
+        let nsc_account_id = describe_nsc_account_id(&account_name).ok();
+        rollback_user_creation(creds_base_path, operator_name, account_resolver, &account_name, nsc_account_id.as_deref());
+    }
 
-    let creds_user_content = std::fs::read_to_string(&creds_path_user)
-        .map_err(|err| format!("Failed to read creds_user file: {}", err))?;
+    let mut nsc_account_id = None;
 
-    create_nsc_user(&account_name, "admin_01")
-        .map_err(|err| format!("Failed to create nsc user: {}", err))?;
+    let result = provision_user(postgres_client, config, username, &mut nsc_account_id).await;
 
-    let creds_path_admin = get_creds_path(creds_base_path, operator_name, &account_name, "admin_01");
+    if let Err(err) = result {
+        rollback_user_creation(creds_base_path, operator_name, account_resolver, &account_name, nsc_account_id.as_deref());
+        return Err(err);
+    }
 
-    let creds_admin_content = std::fs::read_to_string(&creds_path_admin)
-        .map_err(|err| format!("Failed to read creds_user file: {}", err))?;
+    Ok(UserProvisioning::Created)
+}
 
-    let account_jwt = get_account_jwt(&account_name)
-        .map_err(|err| format!("Failed to get account jwt: {}", err))?;
-    
-    insert_nsc_user(postgres_client, username, &nsc_account_id, &creds_admin_content, &creds_user_content, &account_jwt)
-        .await
-        .map_err(|err| format!("Failed to insert nsc user into the database : {}", err))?;
+async fn provision_user(postgres_client: Arc<tokio_postgres::Client>, config: &ProvisioningConfig<'_>, username: Uuid, nsc_account_id: &mut Option<String>) -> Result<()> {
+    let ProvisioningConfig { creds_base_path, operator_name, signing_key, account_resolver, user_policy, plans, .. } = *config;
+    let account_name = username.to_string();
+
+    let created_account_id = create_nsc_account_signed(&account_name, signing_key)?;
+    let created_account_id = nsc_account_id.insert(created_account_id);
+
+    // Without limits, the account JWT stays the one created by nsc
+    let limits = plans.default_limits();
+    if *limits != AccountLimits::default() {
+        edit_nsc_account_limits(&account_name, limits, signing_key)?;
+    }
+
+    let creds_user_content = create_nsc_user_with_creds(creds_base_path, operator_name, &account_name, "user_01", &user_policy.listener_permissions())?;
+
+    let creds_admin_content = create_nsc_user_with_creds(creds_base_path, operator_name, &account_name, "admin_01", &user_policy.publisher_permissions())?;
+
+    let account_jwt = get_account_jwt(&account_name)?;
+
+    push_account_jwt(account_resolver, created_account_id, &account_jwt).await?;
+
+    insert_nsc_user(postgres_client, username, created_account_id, &creds_admin_content, &creds_user_content, &account_jwt, &plans.default_plan, operator_name).await?;
 
     Ok(())
 }
 
-pub async fn delete_user_everywhere(postgres_client: Arc<tokio_postgres::Client>, creds_base_path: &str, operator_name: &str, username: Uuid) -> Result<(), String> {
+// Returns the content of the creds file, written by nsc
+pub(crate) fn create_nsc_user_with_creds(creds_base_path: &str, operator_name: &str, account_name: &str, username: &str, permissions: &UserPermissions) -> Result<String> {
+    create_nsc_user_with_permissions(account_name, username, permissions)?;
+
+    let creds_path = get_creds_path(creds_base_path, operator_name, account_name, username);
+    // The creds written by `nsc add user` do not have the limits set afterwards
+    if !permissions.edit_user_args().is_empty() {
+        let creds = generate_nsc_user_creds(account_name, username)?;
+        std::fs::write(&creds_path, &creds)
+            .map_err(|err| Error::Internal(format!("Failed to write the creds of {}: {}", username, err)))?;
+        return Ok(creds);
+    }
+    std::fs::read_to_string(&creds_path)
+        .map_err(|err| Error::Internal(format!("Failed to read the creds of {}: {}", username, err)))
+}
+
+pub(crate) fn rollback_user_creation(creds_base_path: &str, operator_name: &str, account_resolver: &AccountResolver, account_name: &str, nsc_account_id: Option<&str>) {
+    // Best effort: the steps that were never reached are expected to fail
+    if let Some(nsc_account_id) = nsc_account_id {
+        let _result = remove_account_jwt(account_resolver, nsc_account_id);
+    }
+    for nsc_username in ["admin_01", "user_01"] {
+        let _result = delete_nsc_user(account_name, nsc_username);
+        let _result = std::fs::remove_file(get_creds_path(creds_base_path, operator_name, account_name, nsc_username));
+    }
+    let _result = delete_nsc_account(account_name);
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum DeletionStatus {
+    Deleted,
+    AlreadyGone,
+    Failed(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletionStep {
+    pub step: String,
+    #[serde(flatten)]
+    pub status: DeletionStatus,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DeletionReport {
+    pub steps: Vec<DeletionStep>,
+}
+
+impl DeletionReport {
+    fn record(&mut self, step: &str, status: DeletionStatus) {
+        self.steps.push(DeletionStep { step: step.to_string(), status });
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failed_steps().next().is_none()
+    }
+
+    pub fn is_total_failure(&self) -> bool {
+        self.steps.iter().all(|step| matches!(step.status, DeletionStatus::Failed(_)))
+    }
+
+    pub fn failed_steps(&self) -> impl Iterator<Item = &DeletionStep> {
+        self.steps.iter().filter(|step| matches!(step.status, DeletionStatus::Failed(_)))
+    }
+}
+
+fn remove_path_status(result: std::io::Result<()>) -> DeletionStatus {
+    match result {
+        Ok(_) => DeletionStatus::Deleted,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => DeletionStatus::AlreadyGone,
+        Err(err) => DeletionStatus::Failed(err.to_string()),
+    }
+}
+
+fn database_deletion_status(result: Result<bool>) -> DeletionStatus {
+    match result {
+        Ok(true) => DeletionStatus::Deleted,
+        Ok(false) => DeletionStatus::AlreadyGone,
+        Err(err) => DeletionStatus::Failed(err.to_string()),
+    }
+}
+
+pub async fn delete_user_everywhere(postgres_client: Arc<tokio_postgres::Client>, creds_base_path: &str, operator_name: &str, account_resolver: &AccountResolver, username: Uuid) -> DeletionReport {
     let account_name = username.to_string();
-    let nsc_username_admin = "admin_01";
-    let nsc_username_user = "user_01";
-
-    let _result = delete_nsc_user(&account_name, nsc_username_admin);
-    let _result = delete_nsc_user(&account_name, nsc_username_user);
-    let _result = delete_nsc_account(&account_name);
-    let _result = std::fs::remove_file(get_creds_path(&creds_base_path, operator_name, &account_name, &nsc_username_admin));
-    let _result = std::fs::remove_file(get_creds_path(&creds_base_path, operator_name, &account_name, &nsc_username_user));
-    let _result = delete_nsc_user_from_postgres(Arc::clone(&postgres_client), username)
-        .await;
-    Ok(())
+    let nsc_usernames = ["admin_01", "user_01"];
+    let mut report = DeletionReport::default();
+
+    let _provisioning_guard = match lock_provisioning().await {
+        Ok(provisioning_guard) => provisioning_guard,
+        Err(err) => {
+            report.record("nsc_lock", DeletionStatus::Failed(err.to_string()));
+            return report;
+        }
+    };
+    // The nsc commands would run against whichever operator is selected (ex: deleting the account of another operator)
+    if let Err(err) = select_nsc_operator(operator_name) {
+        report.record("nsc_operator", DeletionStatus::Failed(err.to_string()));
+        return report;
+    }
+
+    // Needs to run before the database row and the nsc account are gone, as they hold the account id
+    if !matches!(account_resolver, AccountResolver::External) {
+        let nsc_account_id = match get_nsc_account_id(Arc::clone(&postgres_client), username).await {
+            Ok(Some(nsc_account_id)) => Ok(Some(nsc_account_id)),
+            Ok(None) => Ok(describe_nsc_account_id(&account_name).ok()),
+            Err(err) => Err(err.to_string()),
+        };
+        let status = match nsc_account_id {
+            Ok(Some(nsc_account_id)) => match remove_account_jwt(account_resolver, &nsc_account_id) {
+                Ok(_) => DeletionStatus::Deleted,
+                Err(err) => DeletionStatus::Failed(err.to_string()),
+            },
+            Ok(None) => DeletionStatus::AlreadyGone,
+            Err(err) => DeletionStatus::Failed(format!("Failed to get the nsc account id: {}", err)),
+        };
+        report.record("resolver_account_jwt", status);
+    }
+
+    let account_exists = nsc_account_exists(&account_name);
+
+    for nsc_username in nsc_usernames {
+        let status = if !account_exists || !nsc_user_exists(&account_name, nsc_username) {
+            DeletionStatus::AlreadyGone
+        } else {
+            match delete_nsc_user(&account_name, nsc_username) {
+                Ok(_) => DeletionStatus::Deleted,
+                Err(err) => DeletionStatus::Failed(err.to_string()),
+            }
+        };
+        report.record(&format!("nsc_user_{}", nsc_username), status);
+    }
+
+    let status = if !account_exists {
+        DeletionStatus::AlreadyGone
+    } else {
+        match delete_nsc_account(&account_name) {
+            Ok(_) => DeletionStatus::Deleted,
+            Err(err) => DeletionStatus::Failed(err.to_string()),
+        }
+    };
+    report.record("nsc_account", status);
+
+    for nsc_username in nsc_usernames {
+        let status = remove_path_status(std::fs::remove_file(get_creds_path(creds_base_path, operator_name, &account_name, nsc_username)));
+        report.record(&format!("creds_file_{}", nsc_username), status);
+    }
+
+    let status = remove_path_status(std::fs::remove_dir_all(get_account_creds_dir(creds_base_path, operator_name, &account_name)));
+    report.record("creds_account_dir", status);
+
+    let status = database_deletion_status(delete_nsc_user_from_postgres(Arc::clone(&postgres_client), username).await);
+    report.record("database_user", status);
+
+    let status = database_deletion_status(delete_api_keys_of_user(Arc::clone(&postgres_client), username).await.map(|deleted| deleted > 0));
+    report.record("database_api_keys", status);
+
+    // The nsc users and the creds files of the devices are gone with the account
+    let status = database_deletion_status(delete_devices_of_user(Arc::clone(&postgres_client), username).await.map(|deleted| deleted > 0));
+    report.record("database_devices", status);
+
+    let status = database_deletion_status(delete_delivery_targets_of_user(Arc::clone(&postgres_client), username).await.map(|deleted| deleted > 0));
+    report.record("database_delivery_targets", status);
+
+    let status = database_deletion_status(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), username).await.map(|deleted| deleted > 0));
+    report.record("database_outbound_deliveries", status);
+
+    let status = database_deletion_status(delete_routing_of_user(Arc::clone(&postgres_client), username).await.map(|deleted| deleted > 0));
+    report.record("database_routing", status);
+
+    let status = database_deletion_status(delete_quiet_hours_of_user(Arc::clone(&postgres_client), username).await.map(|deleted| deleted > 0));
+    report.record("database_quiet_hours", status);
+
+    report
 }
 
-pub async fn get_admin_creds_if_not_exists(creds_base_path: &str, operator_name: &str, account_name: &str) -> Result<String, String>{
+pub async fn get_admin_creds_if_not_exists(postgres_client: Arc<tokio_postgres::Client>, creds_base_path: &str, operator_name: &str, account_name: &str) -> Result<String> {
     // This function will check if the admin_creds are already downloaded under creds_path/uuid or not, otherwise it will pull them from the database
     
     let user_uuid = Uuid::parse_str(account_name)
-        .map_err(|err| format!("Failed to parse account name as an uuid: {}", err))?;
+        .map_err(|err| Error::InvalidInput(format!("Failed to parse account name as an uuid: {}", err)))?;
 
     let username = "admin_01";
 
@@ -68,16 +315,27 @@ pub async fn get_admin_creds_if_not_exists(creds_base_path: &str, operator_name:
         return Ok(creds_path);
     }
     
-    let postgres_client = Arc::new(setup_postgres_client().await);
-
-    let creds_admin = get_creds_admin(Arc::clone(&postgres_client), user_uuid)
-        .await
-        .map_err(|err| format!("Failed to get creds_admin: {}", err))?;
+    let creds_admin = get_creds_admin(postgres_client, user_uuid).await?;
 
     let creds_path = get_creds_path(creds_base_path, operator_name, account_name, username);
 
+    std::fs::create_dir_all(get_account_creds_dir(creds_base_path, operator_name, account_name))
+        .map_err(|err| Error::Internal(format!("Failed to create the creds directory: {}", err)))?;
+
     std::fs::write(&creds_path, creds_admin)
-        .map_err(|err| format!("Failed to write creds_admin to file: {}", err))?;
+        .map_err(|err| Error::Internal(format!("Failed to write creds_admin to file: {}", err)))?;
 
     Ok(creds_path)
+}
+
+// The api key is only returned here, the database keeps its hash
+pub async fn generate_api_key(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<String> {
+    let user_exists = verify_nsc_user_exists(Arc::clone(&postgres_client), user_id).await?;
+    if !user_exists {
+        return Err(Error::NotFound("User not found".to_string()));
+    }
+
+    let api_key = Uuid::new_v4().to_string();
+    add_api_key(postgres_client, user_id, &api_key).await?;
+    Ok(api_key)
 }
\ No newline at end of file
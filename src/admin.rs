@@ -0,0 +1,325 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::accounts_lifecycle::{lock_provisioning, DeletionReport, DeletionStatus, UserProvisioning};
+use crate::error::{Error, Result};
+use crate::nsc_accounts_utils::{check_if_creds_exists, describe_nsc_account_id, get_creds_path, select_nsc_operator};
+use crate::devices::RegisteredDevice;
+use crate::plans::PlanChange;
+use crate::postgres::{delete_api_key, get_nsc_user_record, list_api_keys, ApiKeyRecord, DeviceRecord, NscUserRecord};
+use crate::reconcile::{AccountDrift, RepairStatus};
+
+use std::sync::Arc;
+
+// Output of the admin commands, for operators on the server host
+
+pub struct Table {
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: Vec<&'static str>) -> Table {
+        Table { headers, rows: Vec::new() }
+    }
+
+    pub fn push_row(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    // Columns aligned with spaces, like the nsc and kubectl listings
+    pub fn render(&self) -> String {
+        let mut widths: Vec<usize> = self.headers.iter().map(|header| header.len()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let render_line = |cells: Vec<&str>| {
+            let line = cells.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            format!("{}\n", line.trim_end())
+        };
+        let mut output = render_line(self.headers.clone());
+        for row in &self.rows {
+            output.push_str(&render_line(row.iter().map(String::as_str).collect()));
+        }
+        output
+    }
+}
+
+pub trait TableOutput {
+    fn to_table(&self) -> Table;
+}
+
+fn or_dash(value: Option<&str>) -> String {
+    value.unwrap_or("-").to_string()
+}
+
+impl TableOutput for Vec<NscUserRecord> {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["USER ID", "NSC ACCOUNT ID", "CREATED AT", "OPERATOR", "PLAN", "API KEYS", "BROKEN REASON"]);
+        for record in self {
+            table.push_row(vec![
+                record.user_id.to_string(),
+                record.nsc_account_id.clone(),
+                record.created_at.clone(),
+                or_dash(record.operator_name.as_deref()),
+                or_dash(record.plan.as_deref()),
+                record.api_keys.to_string(),
+                or_dash(record.broken_reason.as_deref()),
+            ]);
+        }
+        table
+    }
+}
+
+impl TableOutput for Vec<ApiKeyRecord> {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["ID", "USER ID", "CREATED AT"]);
+        for api_key in self {
+            table.push_row(vec![api_key.id.to_string(), api_key.user_id.to_string(), api_key.created_at.clone()]);
+        }
+        table
+    }
+}
+
+impl TableOutput for DeletionReport {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["STEP", "STATUS", "ERROR"]);
+        for step in &self.steps {
+            let (status, error) = match &step.status {
+                DeletionStatus::Deleted => ("deleted", None),
+                DeletionStatus::AlreadyGone => ("already_gone", None),
+                DeletionStatus::Failed(err) => ("failed", Some(err.as_str())),
+            };
+            table.push_row(vec![step.step.clone(), status.to_string(), or_dash(error)]);
+        }
+        table
+    }
+}
+
+impl TableOutput for Vec<AccountDrift> {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["ACCOUNT", "DRIFT", "REPAIR", "ERROR"]);
+        for drift in self {
+            let (repair, error) = match &drift.repair {
+                None => ("-", None),
+                Some(RepairStatus::Repaired) => ("repaired", None),
+                Some(RepairStatus::Failed(err)) => ("failed", Some(err.as_str())),
+            };
+            let kind = serde_json::to_value(&drift.kind)
+                .ok()
+                .and_then(|kind| kind.as_str().map(str::to_string))
+                .unwrap_or_default();
+            table.push_row(vec![drift.account_name.clone(), kind, repair.to_string(), or_dash(error)]);
+        }
+        table
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserDetails {
+    #[serde(flatten)]
+    pub user: NscUserRecord,
+    pub api_key_ids: Vec<Uuid>,
+}
+
+pub async fn get_user_details(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<UserDetails> {
+    let user = get_nsc_user_record(Arc::clone(&postgres_client), user_id)
+        .await?
+        .ok_or(Error::NotFound("User not found".to_string()))?;
+    let api_key_ids = list_api_keys(postgres_client, user_id)
+        .await?
+        .into_iter()
+        .map(|api_key| api_key.id)
+        .collect();
+    Ok(UserDetails { user, api_key_ids })
+}
+
+impl TableOutput for UserDetails {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["FIELD", "VALUE"]);
+        table.push_row(vec!["user_id".to_string(), self.user.user_id.to_string()]);
+        table.push_row(vec!["nsc_account_id".to_string(), self.user.nsc_account_id.clone()]);
+        table.push_row(vec!["created_at".to_string(), self.user.created_at.clone()]);
+        table.push_row(vec!["broken_reason".to_string(), or_dash(self.user.broken_reason.as_deref())]);
+        for api_key_id in &self.api_key_ids {
+            table.push_row(vec!["api_key_id".to_string(), api_key_id.to_string()]);
+        }
+        table
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredsFile {
+    pub username: String,
+    pub path: String,
+    pub present: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountDescription {
+    pub account_name: String,
+    pub operator_name: String,
+    // From the nsc keystore and from the database, they differ when the account was recreated
+    pub nsc_account_id: Option<String>,
+    pub database_account_id: Option<String>,
+    pub broken_reason: Option<String>,
+    pub creds: Vec<CredsFile>,
+}
+
+// State of the account of a user in the nsc keystore, the creds directory and the database
+pub async fn describe_account(postgres_client: Arc<tokio_postgres::Client>, creds_base_path: &str, operator_name: &str, user_id: Uuid) -> Result<AccountDescription> {
+    let account_name = user_id.to_string();
+    let record = get_nsc_user_record(postgres_client, user_id).await?;
+    let nsc_account_id = {
+        // The operator of nsc is shared with the provisioning
+        let _provisioning_guard = lock_provisioning().await?;
+        select_nsc_operator(operator_name)?;
+        describe_nsc_account_id(&account_name).ok()
+    };
+    if record.is_none() && nsc_account_id.is_none() {
+        return Err(Error::NotFound(format!("No account found for {}", user_id)));
+    }
+
+    let creds = ["admin_01", "user_01"].iter()
+        .map(|username| CredsFile {
+            username: username.to_string(),
+            path: get_creds_path(creds_base_path, operator_name, &account_name, username),
+            present: check_if_creds_exists(creds_base_path, operator_name, &account_name, username).is_ok(),
+        })
+        .collect();
+
+    Ok(AccountDescription {
+        account_name,
+        operator_name: operator_name.to_string(),
+        nsc_account_id,
+        database_account_id: record.as_ref().map(|record| record.nsc_account_id.clone()),
+        broken_reason: record.and_then(|record| record.broken_reason),
+        creds,
+    })
+}
+
+impl TableOutput for AccountDescription {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["FIELD", "VALUE"]);
+        table.push_row(vec!["account_name".to_string(), self.account_name.clone()]);
+        table.push_row(vec!["operator_name".to_string(), self.operator_name.clone()]);
+        table.push_row(vec!["nsc_account_id".to_string(), or_dash(self.nsc_account_id.as_deref())]);
+        table.push_row(vec!["database_account_id".to_string(), or_dash(self.database_account_id.as_deref())]);
+        table.push_row(vec!["broken_reason".to_string(), or_dash(self.broken_reason.as_deref())]);
+        for creds in &self.creds {
+            let state = if creds.present { "present" } else { "missing" };
+            table.push_row(vec![format!("creds {}", creds.username), format!("{} ({})", creds.path, state)]);
+        }
+        table
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserCreation {
+    pub user_id: Uuid,
+    pub status: UserProvisioning,
+}
+
+impl TableOutput for UserCreation {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["USER ID", "STATUS"]);
+        let status = match self.status {
+            UserProvisioning::Created => "created",
+            UserProvisioning::AlreadyProvisioned => "already_provisioned",
+            UserProvisioning::UnknownIdentity => "unknown_identity",
+        };
+        table.push_row(vec![self.user_id.to_string(), status.to_string()]);
+        table
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    pub user_id: Uuid,
+    pub api_key: String,
+}
+
+impl TableOutput for CreatedApiKey {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["USER ID", "API KEY"]);
+        table.push_row(vec![self.user_id.to_string(), self.api_key.clone()]);
+        table
+    }
+}
+
+pub async fn revoke_api_key(postgres_client: Arc<tokio_postgres::Client>, api_key_id: Uuid) -> Result<()> {
+    match delete_api_key(postgres_client, api_key_id).await? {
+        true => Ok(()),
+        false => Err(Error::NotFound(format!("Api key {} not found", api_key_id))),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevokedApiKey {
+    pub id: Uuid,
+}
+
+impl TableOutput for RevokedApiKey {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["ID", "STATUS"]);
+        table.push_row(vec![self.id.to_string(), "revoked".to_string()]);
+        table
+    }
+}
+
+impl TableOutput for Vec<DeviceRecord> {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["NAME", "NSC USER", "CREATED AT"]);
+        for device in self {
+            table.push_row(vec![device.name.clone(), device.nsc_username.clone(), device.created_at.clone()]);
+        }
+        table
+    }
+}
+
+impl TableOutput for RegisteredDevice {
+    // The creds are multi-line, only in the JSON output
+    fn to_table(&self) -> Table {
+        vec![self.device.clone()].to_table()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevokedDevice {
+    pub user_id: Uuid,
+    pub name: String,
+}
+
+impl TableOutput for RevokedDevice {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["USER ID", "NAME", "STATUS"]);
+        table.push_row(vec![self.user_id.to_string(), self.name.clone(), "revoked".to_string()]);
+        table
+    }
+}
+
+fn limit_or_unlimited(limit: Option<String>) -> String {
+    limit.unwrap_or_else(|| "unlimited".to_string())
+}
+
+impl TableOutput for PlanChange {
+    fn to_table(&self) -> Table {
+        let mut table = Table::new(vec!["USER ID", "PLAN", "CONNECTIONS", "SUBSCRIPTIONS", "PAYLOAD", "JETSTREAM STORAGE"]);
+        table.push_row(vec![
+            self.user_id.to_string(),
+            self.plan.clone(),
+            limit_or_unlimited(self.limits.max_connections.map(|limit| limit.to_string())),
+            limit_or_unlimited(self.limits.max_subscriptions.map(|limit| limit.to_string())),
+            limit_or_unlimited(self.limits.max_payload.map(|limit| limit.to_string())),
+            limit_or_unlimited(self.limits.jetstream_storage.clone()),
+        ]);
+        table
+    }
+}
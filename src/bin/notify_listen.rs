@@ -0,0 +1,59 @@
+use clap::Parser;
+use command_notifier::listener::{connect, listen, subscribe, DesktopSink, ListenerConfig, NotificationSink, TerminalSink};
+use command_notifier::nats_resolver::get_nats_url;
+use tracing_subscriber::EnvFilter;
+
+use std::sync::atomic::AtomicBool;
+
+#[derive(Parser)]
+#[command(name = "notify-listen", about = "Display the notifications sent to a user")]
+struct Cli {
+    /// User creds file created by /nsc/create
+    #[arg(long)]
+    creds: String,
+    /// Subject of the notifications
+    #[arg(long, default_value = "topic01")]
+    subject: String,
+    /// NATS server (default: $NATS_URL, then localhost:4222)
+    #[arg(long)]
+    nats_url: Option<String>,
+    /// Print the notifications instead of showing desktop notifications
+    #[arg(long)]
+    terminal: bool,
+    /// Name of the JetStream consumer, one per device (default: notify-listen-<hostname>)
+    #[arg(long)]
+    durable_name: Option<String>,
+}
+
+fn run<S: NotificationSink>(config: &ListenerConfig, sink: &mut S) -> command_notifier::error::Result<()> {
+    let connection = connect(config)?;
+    let subscription = subscribe(&connection, config)?;
+    // Stopped with Ctrl+C
+    listen(&subscription, sink, &AtomicBool::new(false))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let hostname = gethostname::gethostname().into_string().unwrap_or_else(|_| "unknown".to_string());
+    let config = ListenerConfig {
+        nats_url: cli.nats_url.unwrap_or_else(get_nats_url),
+        creds_path: Some(cli.creds),
+        subject: cli.subject,
+        durable_name: cli.durable_name.unwrap_or_else(|| format!("notify-listen-{}", hostname)),
+    };
+
+    let result = if cli.terminal {
+        run(&config, &mut TerminalSink::new(std::io::stdout()))
+    } else {
+        run(&config, &mut DesktopSink::new())
+    };
+    if let Err(err) = result {
+        eprintln!("notify-listen: {}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,58 @@
+use clap::Parser;
+use command_notifier::{notification::Notification, notify_run::{load_client_config, run_command, send_notification}};
+
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(name = "notify-run", about = "Run a command and send a notification when it completes")]
+struct Cli {
+    /// Config file with server_url, user_id and api_key (default: ~/.config/command_notifier/notify-run.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Number of lines of stdout and stderr included in the notification
+    #[arg(long, default_value_t = 10)]
+    tail: usize,
+    /// Channel of the notification, matched by the routing rules (ex: backups)
+    #[arg(long)]
+    channel: Option<String>,
+    /// Notifications with the same key are dropped by the server within its deduplication window (ex: the retries of a job)
+    #[arg(long)]
+    dedup_key: Option<String>,
+    /// Each notification of the group replaces the previous one on the devices (ex: the name of a pipeline)
+    #[arg(long)]
+    group: Option<String>,
+    /// Command to run, after `--`
+    #[arg(last = true, required = true)]
+    command: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    // Checked before running the command, to not lose the notification of a long job
+    let config = load_client_config(cli.config.as_deref()).unwrap_or_else(|err| {
+        eprintln!("notify-run: {}", err);
+        std::process::exit(2);
+    });
+
+    let (program, args) = cli.command.split_first().expect("The command is required");
+    let outcome = run_command(program, args, cli.tail).unwrap_or_else(|err| {
+        eprintln!("notify-run: failed to run {}: {}", program, err);
+        std::process::exit(127);
+    });
+
+    let host = gethostname::gethostname().into_string().ok();
+    let notification = Notification {
+        channel: cli.channel,
+        dedup_key: cli.dedup_key,
+        group: cli.group,
+        ..outcome.to_notification(host)
+    };
+    if let Err(err) = send_notification(&config, &notification).await {
+        eprintln!("notify-run: failed to send the notification: {}", err);
+    }
+
+    // Transparent for scripts: same exit code as the command
+    std::process::exit(outcome.exit_code.unwrap_or(1));
+}
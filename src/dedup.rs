@@ -0,0 +1,49 @@
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::notification::Notification;
+use crate::postgres::{find_history_entry_by_group_key, increment_dedup_key_duplicates};
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Deduplication and grouping of the notifications of a user: within the window, a notification with the dedup key
+// of a previous one is dropped (ex: the retries of a flaky job), and one of a group replaces the previous one of the group
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DedupConfig {
+    pub window: Duration,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig { window: Duration::from_secs(600) }
+    }
+}
+
+impl DedupConfig {
+    pub fn from_env() -> Result<DedupConfig> {
+        match env::var("DEDUP_WINDOW_SECS") {
+            Ok(seconds) => seconds.parse()
+                .map(|seconds| DedupConfig { window: Duration::from_secs(seconds) })
+                .map_err(|_| Error::InvalidInput("DEDUP_WINDOW_SECS must be a number".to_string())),
+            Err(_) => Ok(DedupConfig::default()),
+        }
+    }
+}
+
+// Counted in the history of the notification holding the dedup key, see record_history
+pub async fn count_duplicate(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, dedup_key: &str) -> Result<()> {
+    let first_id = increment_dedup_key_duplicates(postgres_client, user_id, dedup_key).await?;
+    tracing::debug!(%user_id, dedup_key, ?first_id, "Duplicate notification dropped");
+    Ok(())
+}
+
+// Id of the previous notification of its group delivered in the window
+pub async fn find_replaced(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, notification: &Notification, window: Duration) -> Result<Option<Uuid>> {
+    match &notification.group {
+        Some(group) => find_history_entry_by_group_key(postgres_client, user_id, group, window.as_millis() as f64).await,
+        None => Ok(None),
+    }
+}
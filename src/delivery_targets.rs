@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::email::{EmailSender, EmailTarget, SmtpConfig};
+use crate::error::{Error, Result, Service};
+use crate::notification::Notification;
+use crate::postgres::{confirm_delivery_target, delete_delivery_target, get_delivery_target, insert_delivery_target, list_delivery_targets, verify_nsc_user_exists, DeliveryTargetRecord};
+use crate::webhook::{send_webhook, webhook_http_client_builder, WebhookTarget};
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Outputs of the notifications of a user besides NATS, configured by the user and stored in the database
+
+const MAX_TARGETS_PER_USER: usize = 20;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TargetConfig {
+    Webhook(WebhookTarget),
+    Email(EmailTarget),
+}
+
+impl TargetConfig {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TargetConfig::Webhook(_) => "webhook",
+            TargetConfig::Email(_) => "email",
+        }
+    }
+
+    pub fn accepts(&self, notification: &Notification) -> bool {
+        match self {
+            TargetConfig::Webhook(_) => true,
+            TargetConfig::Email(email) => email.accepts(notification),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            TargetConfig::Webhook(webhook) => webhook.validate(),
+            TargetConfig::Email(email) => email.validate(),
+        }
+    }
+
+    pub fn redacted(&self) -> TargetConfig {
+        match self {
+            TargetConfig::Webhook(webhook) => TargetConfig::Webhook(webhook.redacted()),
+            TargetConfig::Email(email) => TargetConfig::Email(email.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeliveryTarget {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    #[serde(flatten)]
+    pub config: TargetConfig,
+    pub created_at: String,
+    // The email targets are not delivered until their address is confirmed
+    pub confirmed: bool,
+}
+
+impl DeliveryTarget {
+    fn from_record(record: DeliveryTargetRecord) -> Result<DeliveryTarget> {
+        let config = serde_json::from_str(&record.config)
+            .map_err(|err| Error::Internal(format!("Invalid config for the delivery target {}: {}", record.id, err)))?;
+        Ok(DeliveryTarget { id: record.id, user_id: record.user_id, config, created_at: record.created_at, confirmed: record.confirmed })
+    }
+
+    // What is returned by the API
+    pub fn redacted(&self) -> DeliveryTarget {
+        DeliveryTarget { config: self.config.redacted(), ..self.clone() }
+    }
+}
+
+// Failed attempt of a delivery, queued again after its backoff when retryable
+#[derive(Debug, PartialEq)]
+pub struct DeliveryFailure {
+    pub retryable: bool,
+    pub message: String,
+}
+
+impl DeliveryFailure {
+    pub fn retryable(message: impl Into<String>) -> DeliveryFailure {
+        DeliveryFailure { retryable: true, message: message.into() }
+    }
+
+    pub fn permanent(message: impl Into<String>) -> DeliveryFailure {
+        DeliveryFailure { retryable: false, message: message.into() }
+    }
+}
+
+// The upstream services (ex: NATS, database) may come back, the other errors will fail again
+impl From<Error> for DeliveryFailure {
+    fn from(err: Error) -> DeliveryFailure {
+        DeliveryFailure { retryable: matches!(err, Error::Upstream(_, _)), message: err.to_string() }
+    }
+}
+
+// Attempts of a delivery, the backoff doubling after each failure
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Result<RetryPolicy> {
+        let mut retry_policy = RetryPolicy::default();
+        if let Ok(max_attempts) = env::var("DELIVERY_MAX_ATTEMPTS") {
+            retry_policy.max_attempts = max_attempts.parse()
+                .ok()
+                .filter(|max_attempts| *max_attempts > 0)
+                .ok_or(Error::InvalidInput("DELIVERY_MAX_ATTEMPTS must be a positive number".to_string()))?;
+        }
+        if let Ok(initial_backoff) = env::var("DELIVERY_INITIAL_BACKOFF_MS") {
+            retry_policy.initial_backoff = initial_backoff.parse()
+                .map(Duration::from_millis)
+                .map_err(|_| Error::InvalidInput("DELIVERY_INITIAL_BACKOFF_MS must be a number".to_string()))?;
+        }
+        Ok(retry_policy)
+    }
+
+    // Wait after the failed attempt (starting at 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+// Shared by the deliveries of all the users
+#[derive(Clone, Debug)]
+pub struct DeliveryConfig {
+    pub http_client: reqwest::Client,
+    // The webhooks can reach the loopback, private and link-local addresses (ex: a chat server on the same network)
+    pub allow_private_networks: bool,
+    pub retry_policy: RetryPolicy,
+    // None without SMTP server
+    pub email_sender: Option<EmailSender>,
+}
+
+impl DeliveryConfig {
+    pub fn from_env() -> Result<DeliveryConfig> {
+        let allow_private_networks = match env::var("WEBHOOK_ALLOW_PRIVATE_NETWORKS").as_deref() {
+            Err(_) | Ok("false") => false,
+            Ok("true") => true,
+            Ok(_) => return Err(Error::InvalidInput("WEBHOOK_ALLOW_PRIVATE_NETWORKS must be true or false".to_string())),
+        };
+        let http_client = webhook_http_client_builder(allow_private_networks)
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .map_err(|err| Error::Internal(format!("Failed to build the HTTP client: {}", err)))?;
+        let email_sender = SmtpConfig::from_env()?
+            .map(|smtp_config| EmailSender::new(&smtp_config))
+            .transpose()?;
+        Ok(DeliveryConfig { http_client, allow_private_networks, retry_policy: RetryPolicy::from_env()?, email_sender })
+    }
+}
+
+// The email targets are created unconfirmed, with a confirmation token sent to their address (see confirm_email_target):
+// the server does not send the notifications to an address that did not accept them
+pub async fn create_delivery_target(postgres_client: Arc<tokio_postgres::Client>, email_sender: Option<&EmailSender>, user_id: Uuid, config: &TargetConfig) -> Result<DeliveryTarget> {
+    config.validate()?;
+    if !verify_nsc_user_exists(Arc::clone(&postgres_client), user_id).await? {
+        return Err(Error::NotFound("User not found".to_string()));
+    }
+    if list_delivery_targets(Arc::clone(&postgres_client), user_id).await?.len() >= MAX_TARGETS_PER_USER {
+        return Err(Error::Conflict(format!("A user can not have more than {} delivery targets", MAX_TARGETS_PER_USER)));
+    }
+
+    let serialized_config = serde_json::to_string(config)
+        .map_err(|err| Error::Internal(format!("Failed to serialize the delivery target: {}", err)))?;
+    let TargetConfig::Email(email) = config else {
+        return DeliveryTarget::from_record(insert_delivery_target(postgres_client, user_id, &serialized_config, None).await?);
+    };
+    let email_sender = email_sender
+        .ok_or(Error::InvalidInput("The email targets are not available, no SMTP server is configured".to_string()))?;
+
+    let confirmation_token = Uuid::new_v4().simple().to_string();
+    let target = DeliveryTarget::from_record(insert_delivery_target(Arc::clone(&postgres_client), user_id, &serialized_config, Some(&confirmation_token)).await?)?;
+    if let Err(failure) = email_sender.send_confirmation(email, user_id, target.id, &confirmation_token).await {
+        delete_delivery_target(postgres_client, user_id, target.id).await?;
+        return Err(match failure.retryable {
+            true => Error::Upstream(Service::Smtp, failure.message),
+            false => Error::InvalidInput(format!("The confirmation email was rejected: {}", failure.message)),
+        });
+    }
+    Ok(target)
+}
+
+pub async fn confirm_email_target(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, target_id: Uuid, confirmation_token: &str) -> Result<DeliveryTarget> {
+    let target = find_delivery_target(Arc::clone(&postgres_client), user_id, target_id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("Delivery target {} not found", target_id)))?;
+    if target.confirmed {
+        return Err(Error::Conflict(format!("Delivery target {} is already confirmed", target_id)));
+    }
+    if !confirm_delivery_target(postgres_client, user_id, target_id, confirmation_token).await? {
+        return Err(Error::InvalidInput("Invalid confirmation token".to_string()));
+    }
+    Ok(DeliveryTarget { confirmed: true, ..target })
+}
+
+pub async fn get_delivery_targets(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<DeliveryTarget>> {
+    list_delivery_targets(postgres_client, user_id)
+        .await?
+        .into_iter()
+        .map(DeliveryTarget::from_record)
+        .collect()
+}
+
+pub async fn remove_delivery_target(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, target_id: Uuid) -> Result<()> {
+    if !delete_delivery_target(postgres_client, user_id, target_id).await? {
+        return Err(Error::NotFound(format!("Delivery target {} not found", target_id)));
+    }
+    Ok(())
+}
+
+pub async fn find_delivery_target(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, target_id: Uuid) -> Result<Option<DeliveryTarget>> {
+    get_delivery_target(postgres_client, user_id, target_id)
+        .await?
+        .map(DeliveryTarget::from_record)
+        .transpose()
+}
+
+// One attempt, the retries are done by the outbound queue
+pub async fn deliver_to_target(delivery_config: &DeliveryConfig, target: &DeliveryTarget, notification: &Notification) -> std::result::Result<(), DeliveryFailure> {
+    if !target.confirmed {
+        return Err(DeliveryFailure::permanent("The delivery target is not confirmed"));
+    }
+    match &target.config {
+        TargetConfig::Webhook(webhook) => send_webhook(&delivery_config.http_client, delivery_config.allow_private_networks, webhook, target.user_id, notification).await,
+        TargetConfig::Email(email) => match &delivery_config.email_sender {
+            Some(email_sender) => email_sender.send(email, target.user_id, notification).await,
+            None => Err(DeliveryFailure::permanent("SMTP is not configured")),
+        },
+    }
+}
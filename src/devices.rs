@@ -0,0 +1,104 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::accounts_lifecycle::{create_nsc_user_with_creds, lock_provisioning};
+use crate::error::{Error, Result};
+use crate::nats_resolver::{push_account_jwt, AccountResolver};
+use crate::nsc_accounts_utils::{delete_nsc_user, get_account_jwt, get_creds_path, revoke_nsc_user, select_nsc_operator};
+use crate::postgres::{delete_device, get_device, get_nsc_account_id, insert_device, update_account_jwt, verify_nsc_user_exists, DeviceRecord};
+use crate::user_policy::UserPolicy;
+
+use std::sync::Arc;
+
+// Every receiving device of a user (ex: laptop, phone, tray app) gets its own NATS user under the account of the user,
+// so that a lost device is revoked alone.
+
+const MAX_DEVICE_NAME_LENGTH: usize = 32;
+
+// The name is part of the nsc username and of the creds file name
+pub fn validate_device_name(name: &str) -> Result<()> {
+    let valid_characters = name.chars().all(|character| character.is_ascii_lowercase() || character.is_ascii_digit() || character == '-');
+    if name.is_empty() || name.len() > MAX_DEVICE_NAME_LENGTH || !valid_characters {
+        return Err(Error::InvalidInput(format!(
+            "Invalid device name, it should have 1 to {} characters among a-z, 0-9 and -", MAX_DEVICE_NAME_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+// Prefixed to never collide with the users created with the account (admin_01, user_01)
+pub fn get_device_nsc_username(name: &str) -> String {
+    format!("device_{}", name)
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegisteredDevice {
+    pub device: DeviceRecord,
+    // Only returned at the registration, to be copied on the device
+    pub creds: String,
+}
+
+pub async fn register_device(postgres_client: Arc<tokio_postgres::Client>, creds_base_path: &str, operator_name: &str, user_policy: &UserPolicy, user_id: Uuid, name: &str) -> Result<RegisteredDevice> {
+    validate_device_name(name)?;
+
+    let _provisioning_guard = lock_provisioning().await?;
+
+    if !verify_nsc_user_exists(Arc::clone(&postgres_client), user_id).await? {
+        return Err(Error::NotFound("User not found".to_string()));
+    }
+    if get_device(Arc::clone(&postgres_client), user_id, name).await?.is_some() {
+        return Err(Error::Conflict(format!("The device {} already exists", name)));
+    }
+    select_nsc_operator(operator_name)?;
+
+    let account_name = user_id.to_string();
+    let nsc_username = get_device_nsc_username(name);
+    // Leftovers of a previous attempt that never reached the database
+    let _result = delete_nsc_user(&account_name, &nsc_username);
+
+    let result = provision_device(postgres_client, creds_base_path, operator_name, user_policy, user_id, name).await;
+    if result.is_err() {
+        let _result = delete_nsc_user(&account_name, &nsc_username);
+        let _result = std::fs::remove_file(get_creds_path(creds_base_path, operator_name, &account_name, &nsc_username));
+    }
+    result
+}
+
+async fn provision_device(postgres_client: Arc<tokio_postgres::Client>, creds_base_path: &str, operator_name: &str, user_policy: &UserPolicy, user_id: Uuid, name: &str) -> Result<RegisteredDevice> {
+    let account_name = user_id.to_string();
+    let nsc_username = get_device_nsc_username(name);
+
+    let creds = create_nsc_user_with_creds(creds_base_path, operator_name, &account_name, &nsc_username, &user_policy.listener_permissions())?;
+
+    let device = insert_device(postgres_client, user_id, name, &nsc_username, &creds).await?;
+    Ok(RegisteredDevice { device, creds })
+}
+
+// Revoked in the account JWT first: a copy of the creds left on the device can not connect anymore
+pub async fn revoke_device(postgres_client: Arc<tokio_postgres::Client>, creds_base_path: &str, operator_name: &str, signing_key: Option<&str>, account_resolver: &AccountResolver, user_id: Uuid, name: &str) -> Result<()> {
+    let _provisioning_guard = lock_provisioning().await?;
+
+    let device = get_device(Arc::clone(&postgres_client), user_id, name)
+        .await?
+        .ok_or(Error::NotFound(format!("Device {} not found", name)))?;
+    let nsc_account_id = get_nsc_account_id(Arc::clone(&postgres_client), user_id)
+        .await?
+        .ok_or(Error::NotFound("User not found".to_string()))?;
+    let account_name = user_id.to_string();
+
+    select_nsc_operator(operator_name)?;
+    revoke_nsc_user(&account_name, &device.nsc_username, signing_key)?;
+    let account_jwt = get_account_jwt(&account_name)?;
+    push_account_jwt(account_resolver, &nsc_account_id, &account_jwt).await?;
+    update_account_jwt(Arc::clone(&postgres_client), user_id, &account_jwt).await?;
+
+    delete_nsc_user(&account_name, &device.nsc_username)?;
+    match std::fs::remove_file(get_creds_path(creds_base_path, operator_name, &account_name, &device.nsc_username)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(Error::Internal(format!("Failed to remove the creds of the device: {}", err)));
+        }
+        _ => {}
+    }
+    delete_device(postgres_client, user_id, name).await?;
+    Ok(())
+}
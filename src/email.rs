@@ -0,0 +1,201 @@
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::{authentication::Credentials, client::{Tls, TlsParameters}},
+    Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::delivery_targets::DeliveryFailure;
+use crate::error::{Error, Result};
+use crate::notification::{Notification, Priority};
+
+use std::env;
+use std::time::Duration;
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_SUBJECT_LENGTH: usize = 120;
+
+fn default_min_priority() -> Priority {
+    Priority::High
+}
+
+// Notification sent by email, only from its minimum priority (high by default)
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EmailTarget {
+    pub address: String,
+    #[serde(default = "default_min_priority")]
+    pub min_priority: Priority,
+    // See Notification::render_template, the title (or the first line of the message) without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_template: Option<String>,
+    // The message and the host without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_template: Option<String>,
+}
+
+// A subject is a single line
+fn escape_subject(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+fn escape_body(value: &str) -> String {
+    value.to_string()
+}
+
+impl EmailTarget {
+    pub fn validate(&self) -> Result<()> {
+        self.address.parse::<Address>()
+            .map_err(|err| Error::InvalidInput(format!("Invalid email address: {}", err)))?;
+        Ok(())
+    }
+
+    pub fn accepts(&self, notification: &Notification) -> bool {
+        notification.priority >= self.min_priority
+    }
+
+    pub fn render_subject(&self, user_id: Uuid, notification: &Notification) -> String {
+        let subject = match &self.subject_template {
+            Some(template) => notification.render_template(template, user_id, escape_subject),
+            None => notification.title.clone()
+                .unwrap_or_else(|| notification.message.lines().next().unwrap_or_default().to_string()),
+        };
+        escape_subject(&subject).chars().take(MAX_SUBJECT_LENGTH).collect()
+    }
+
+    pub fn render_body(&self, user_id: Uuid, notification: &Notification) -> String {
+        match &self.body_template {
+            Some(template) => notification.render_template(template, user_id, escape_body),
+            None => match &notification.host {
+                Some(host) => format!("{}\n\nSent from {}", notification.message, host),
+                None => notification.message.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmtpTls {
+    // Only for a relay on the same machine or network (ex: a local SMTP sink)
+    None,
+    StartTls,
+    Tls,
+}
+
+// SMTP server the emails are sent through, the email targets can not be created without it
+#[derive(Clone, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: Mailbox,
+}
+
+impl SmtpConfig {
+    // None when SMTP_HOST is not set
+    pub fn from_env() -> Result<Option<SmtpConfig>> {
+        let Ok(host) = env::var("SMTP_HOST") else {
+            return Ok(None);
+        };
+        let tls = match env::var("SMTP_TLS").as_deref() {
+            Ok("none") => SmtpTls::None,
+            Ok("starttls") | Err(_) => SmtpTls::StartTls,
+            Ok("tls") => SmtpTls::Tls,
+            Ok(other) => return Err(Error::InvalidInput(format!("SMTP_TLS must be none, starttls or tls, not {}", other))),
+        };
+        let port = match env::var("SMTP_PORT") {
+            Ok(port) => port.parse()
+                .map_err(|_| Error::InvalidInput("SMTP_PORT must be a port number".to_string()))?,
+            Err(_) if tls == SmtpTls::Tls => 465,
+            Err(_) => 587,
+        };
+        let from = env::var("SMTP_FROM")
+            .map_err(|_| Error::InvalidInput("SMTP_FROM must be set with SMTP_HOST".to_string()))?
+            .parse()
+            .map_err(|err| Error::InvalidInput(format!("Invalid SMTP_FROM: {}", err)))?;
+
+        Ok(Some(SmtpConfig {
+            host,
+            port,
+            tls,
+            username: env::var("SMTP_USERNAME").ok(),
+            password: env::var("SMTP_PASSWORD").ok(),
+            from,
+        }))
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+        let tls_parameters = || TlsParameters::new(self.host.clone())
+            .map_err(|err| Error::InvalidInput(format!("Invalid SMTP TLS configuration: {}", err)));
+        let tls = match self.tls {
+            SmtpTls::None => Tls::None,
+            SmtpTls::StartTls => Tls::Required(tls_parameters()?),
+            SmtpTls::Tls => Tls::Wrapper(tls_parameters()?),
+        };
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+            .port(self.port)
+            .tls(tls)
+            .timeout(Some(SMTP_TIMEOUT));
+        if let Some(username) = &self.username {
+            builder = builder.credentials(Credentials::new(username.clone(), self.password.clone().unwrap_or_default()));
+        }
+        Ok(builder.build())
+    }
+}
+
+// Sends the emails of the email targets, one attempt each, the retries being done by the outbound queue
+#[derive(Clone, Debug)]
+pub struct EmailSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl EmailSender {
+    pub fn new(smtp_config: &SmtpConfig) -> Result<EmailSender> {
+        Ok(EmailSender { transport: smtp_config.transport()?, from: smtp_config.from.clone() })
+    }
+
+    // Temporary SMTP failures (4xx) and connection errors are retryable, the rejected emails are not
+    pub async fn send(&self, target: &EmailTarget, user_id: Uuid, notification: &Notification) -> std::result::Result<(), DeliveryFailure> {
+        let to: Mailbox = target.address.parse()
+            .map_err(|err| DeliveryFailure::permanent(format!("Invalid email address: {}", err)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(target.render_subject(user_id, notification))
+            .header(ContentType::TEXT_PLAIN)
+            .body(target.render_body(user_id, notification))
+            .map_err(|err| DeliveryFailure::permanent(format!("Failed to build the email: {}", err)))?;
+
+        self.deliver(message).await
+    }
+
+    // Sent once when the email target is created, the target being only delivered once the token comes back
+    pub async fn send_confirmation(&self, target: &EmailTarget, user_id: Uuid, target_id: Uuid, confirmation_token: &str) -> std::result::Result<(), DeliveryFailure> {
+        let to: Mailbox = target.address.parse()
+            .map_err(|err| DeliveryFailure::permanent(format!("Invalid email address: {}", err)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject("Confirm the notifications sent to this address")
+            .header(ContentType::TEXT_PLAIN)
+            .body(format!(
+                "The notifications of the user {} were requested to be sent to this address.\n\n\
+                To receive them, confirm the delivery target {} with the token:\n\n{}\n\n\
+                Nothing is sent to this address without it, ignore this email if you did not ask for it.",
+                user_id, target_id, confirmation_token))
+            .map_err(|err| DeliveryFailure::permanent(format!("Failed to build the email: {}", err)))?;
+
+        self.deliver(message).await
+    }
+
+    async fn deliver(&self, message: Message) -> std::result::Result<(), DeliveryFailure> {
+        self.transport.send(message)
+            .await
+            .map(|_| ())
+            .map_err(|err| DeliveryFailure { retryable: !err.is_permanent() && !err.is_client(), message: err.to_string() })
+    }
+}
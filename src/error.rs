@@ -0,0 +1,211 @@
+use axum::{
+    extract::{rejection::{JsonRejection, PathRejection, QueryRejection}, FromRequest, FromRequestParts, Request},
+    http::{header::CONTENT_TYPE, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use std::fmt;
+
+use crate::metrics::record_database_error;
+use crate::request_id::current_request_id;
+
+// External component an error comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Service {
+    Nsc,
+    Nats,
+    Database,
+    IdentityProvider,
+    Smtp,
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Service::Nsc => write!(f, "nsc"),
+            Service::Nats => write!(f, "NATS"),
+            Service::Database => write!(f, "database"),
+            Service::IdentityProvider => write!(f, "identity provider"),
+            Service::Smtp => write!(f, "SMTP server"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    InvalidInput(String),
+    NotFound(String),
+    Conflict(String),
+    Unauthorized(String),
+    Forbidden(String),
+    MethodNotAllowed(String),
+    Upstream(Service, String),
+    Internal(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn nsc(message: impl Into<String>) -> Error {
+        Error::Upstream(Service::Nsc, message.into())
+    }
+
+    pub fn nats(message: impl Into<String>) -> Error {
+        Error::Upstream(Service::Nats, message.into())
+    }
+
+    pub fn database(message: impl Into<String>) -> Error {
+        Error::Upstream(Service::Database, message.into())
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Error::Upstream(_, _) => StatusCode::BAD_GATEWAY,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidInput(_) => "invalid_input",
+            Error::NotFound(_) => "not_found",
+            Error::Conflict(_) => "conflict",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::MethodNotAllowed(_) => "method_not_allowed",
+            Error::Upstream(_, _) => "upstream_error",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+
+    // Message that can be sent to the client, the details of upstream and internal errors are only logged
+    pub fn public_message(&self) -> String {
+        match self {
+            Error::InvalidInput(message) | Error::NotFound(message) | Error::Conflict(message) | Error::Unauthorized(message) | Error::Forbidden(message)
+            | Error::MethodNotAllowed(message) => message.clone(),
+            Error::Upstream(service, _) => format!("The {} is unavailable, contact administrator", service),
+            Error::Internal(_) => "Internal error, contact administrator".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidInput(message) => write!(f, "Invalid input: {}", message),
+            Error::NotFound(message) => write!(f, "Not found: {}", message),
+            Error::Conflict(message) => write!(f, "Conflict: {}", message),
+            Error::Unauthorized(message) => write!(f, "Unauthorized: {}", message),
+            Error::Forbidden(message) => write!(f, "Forbidden: {}", message),
+            Error::MethodNotAllowed(message) => write!(f, "Method not allowed: {}", message),
+            Error::Upstream(service, message) => write!(f, "{} error: {}", service, message),
+            Error::Internal(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<tokio_postgres::Error> for Error {
+    fn from(err: tokio_postgres::Error) -> Error {
+        record_database_error(&err);
+        Error::database(err.to_string())
+    }
+}
+
+// Rejections of the extractors of axum (ex: a body that is not valid JSON), with the message of axum
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Error {
+        Error::InvalidInput(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for Error {
+    fn from(rejection: PathRejection) -> Error {
+        Error::InvalidInput(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for Error {
+    fn from(rejection: QueryRejection) -> Error {
+        Error::InvalidInput(rejection.body_text())
+    }
+}
+
+// Same as the extractors of axum, rejecting with the JSON error of the API instead of plain text
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(Error))]
+pub struct JsonBody<T>(pub T);
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(Error))]
+pub struct PathParams<T>(pub T);
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(Error))]
+pub struct QueryParams<T>(pub T);
+
+// Fallback of the router, for the paths without a route
+pub async fn route_not_found() -> Error {
+    Error::NotFound("Route not found".to_string())
+}
+
+// The routes answer a method they do not handle with an empty 405, replaced by the JSON error
+pub async fn method_not_allowed_middleware(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    if response.status() == StatusCode::METHOD_NOT_ALLOWED && !response.headers().contains_key(CONTENT_TYPE) {
+        return Error::MethodNotAllowed("Method not allowed for this route".to_string()).into_response();
+    }
+    response
+}
+
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let request_id = current_request_id();
+        match self {
+            Error::Upstream(_, _) | Error::Internal(_) => tracing::error!(code = self.code(), "{}", self),
+            _ => tracing::info!(code = self.code(), "{}", self),
+        }
+        let body = ErrorResponse {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.public_message(),
+                request_id,
+            },
+        };
+        (self.status_code(), Json(body)).into_response()
+    }
+}
+
+// Response of a request whose handler panicked, see tower_http::catch_panic
+pub fn panic_response(panic: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    let details = if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else {
+        "unknown panic payload".to_string()
+    };
+    Error::Internal(format!("Handler panicked: {}", details)).into_response()
+}
@@ -0,0 +1,149 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::metrics::run_nsc;
+
+use std::env;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    // Nothing to check with the current configuration
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthCheck {
+    pub check: String,
+    #[serde(flatten)]
+    pub status: CheckStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl ReadinessReport {
+    fn record(&mut self, check: &str, status: CheckStatus) {
+        let failed = matches!(status, CheckStatus::Failed(_));
+        self.checks.push(HealthCheck { check: check.to_string(), status });
+        self.ready = self.ready && !failed;
+    }
+
+    pub fn failed_checks(&self) -> impl Iterator<Item = &HealthCheck> {
+        self.checks.iter().filter(|check| matches!(check.status, CheckStatus::Failed(_)))
+    }
+}
+
+pub struct ReadinessConfig<'a> {
+    pub creds_base_path: &'a str,
+    pub operator_names: Vec<&'a str>,
+    pub nsc_store_dir: &'a str,
+    pub nats_url: &'a str,
+    pub nats_probe_creds_path: Option<&'a str>,
+}
+
+// Creds used to check that NATS is reachable: a dedicated probe user, or the system account
+pub fn get_nats_probe_creds_path() -> Option<String> {
+    env::var("NATS_PROBE_CREDS_PATH")
+        .or_else(|_| env::var("NATS_SYSTEM_CREDS_PATH"))
+        .ok()
+}
+
+fn status_of(result: Result<(), String>) -> CheckStatus {
+    match result {
+        Ok(_) => CheckStatus::Ok,
+        Err(err) => CheckStatus::Failed(err),
+    }
+}
+
+pub async fn check_database(postgres_client: Arc<tokio_postgres::Client>) -> CheckStatus {
+    match tokio::time::timeout(CHECK_TIMEOUT, postgres_client.simple_query("SELECT 1")).await {
+        Ok(Ok(_)) => CheckStatus::Ok,
+        Ok(Err(err)) => CheckStatus::Failed(format!("Failed to query the database: {}", err)),
+        Err(_) => CheckStatus::Failed("The database did not answer in time".to_string()),
+    }
+}
+
+pub async fn check_nats(nats_url: &str, nats_probe_creds_path: Option<&str>) -> CheckStatus {
+    let Some(creds_path) = nats_probe_creds_path else {
+        return CheckStatus::Skipped("No NATS_PROBE_CREDS_PATH or NATS_SYSTEM_CREDS_PATH configured".to_string());
+    };
+    let nats_url = nats_url.to_string();
+    let creds_path = creds_path.to_string();
+
+    // The nats client is blocking
+    let probe = tokio::task::spawn_blocking(move || {
+        let nats_client = nats::Options::with_credentials(&creds_path)
+            .connect(&nats_url)
+            .map_err(|err| format!("Failed to connect to NATS at {}: {}", nats_url, err))?;
+        // Round trip with the server
+        nats_client.flush_timeout(CHECK_TIMEOUT)
+            .map_err(|err| format!("NATS did not answer: {}", err))
+    });
+    match tokio::time::timeout(CHECK_TIMEOUT, probe).await {
+        Ok(Ok(result)) => status_of(result),
+        Ok(Err(err)) => CheckStatus::Failed(format!("The NATS probe failed: {}", err)),
+        Err(_) => CheckStatus::Failed("NATS did not answer in time".to_string()),
+    }
+}
+
+pub async fn check_nsc_binary() -> CheckStatus {
+    // The command is blocking
+    let version = tokio::task::spawn_blocking(|| run_nsc(Command::new("nsc").arg("--version")));
+    match tokio::time::timeout(CHECK_TIMEOUT, version).await {
+        Ok(Ok(Ok(output))) if output.status.success() => CheckStatus::Ok,
+        Ok(Ok(Ok(output))) => CheckStatus::Failed(format!("nsc --version failed: {}", String::from_utf8_lossy(&output.stderr).trim())),
+        Ok(Ok(Err(err))) => CheckStatus::Failed(format!("Failed to run nsc: {}", err)),
+        Ok(Err(err)) => CheckStatus::Failed(format!("The nsc check failed: {}", err)),
+        Err(_) => CheckStatus::Failed("nsc did not answer in time".to_string()),
+    }
+}
+
+pub fn check_operator_keystore(nsc_store_dir: &str, operator_name: &str) -> CheckStatus {
+    // Layout of the keystore: <store>/<operator>/<operator>.jwt
+    let operator_jwt_path = format!("{}/{}/{}.jwt", nsc_store_dir, operator_name, operator_name);
+    if std::path::Path::new(&operator_jwt_path).is_file() {
+        CheckStatus::Ok
+    } else {
+        CheckStatus::Failed(format!("Operator {} not found in the keystore: {}", operator_name, operator_jwt_path))
+    }
+}
+
+pub fn check_creds_writable(creds_base_path: &str) -> CheckStatus {
+    let probe_path = format!("{}/.readyz-{}", creds_base_path, Uuid::new_v4());
+    let result = std::fs::write(&probe_path, "")
+        .and_then(|_| std::fs::remove_file(&probe_path))
+        .map_err(|err| format!("{} is not writable: {}", creds_base_path, err));
+    status_of(result)
+}
+
+pub async fn check_readiness(postgres_client: Arc<tokio_postgres::Client>, config: &ReadinessConfig<'_>) -> ReadinessReport {
+    let mut report = ReadinessReport { ready: true, checks: Vec::new() };
+
+    report.record("database", check_database(postgres_client).await);
+    // The notifications go through NATS: without probe creds its state is unknown, so the server is not ready
+    let nats_status = check_nats(config.nats_url, config.nats_probe_creds_path).await;
+    let nats_skipped = matches!(nats_status, CheckStatus::Skipped(_));
+    report.record("nats", nats_status);
+    report.ready = report.ready && !nats_skipped;
+    report.record("nsc_binary", check_nsc_binary().await);
+    // A single check for all the operators, failed by the first missing one
+    let operator_status = config.operator_names.iter()
+        .map(|operator_name| check_operator_keystore(config.nsc_store_dir, operator_name))
+        .find(|status| matches!(status, CheckStatus::Failed(_)))
+        .unwrap_or(CheckStatus::Ok);
+    report.record("nsc_operator_keystore", operator_status);
+    report.record("creds_base_path_writable", check_creds_writable(config.creds_base_path));
+
+    report
+}
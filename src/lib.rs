@@ -1,3 +1,28 @@
 pub mod nsc_accounts_utils;
 pub mod postgres;
-pub mod accounts_lifecycle;
\ No newline at end of file
+pub mod accounts_lifecycle;
+pub mod nats_resolver;
+pub mod reconcile;
+pub mod oidc;
+pub mod error;
+pub mod request_id;
+pub mod nats_publisher;
+pub mod metrics;
+pub mod health;
+pub mod tls;
+pub mod shutdown;
+pub mod notification;
+pub mod notify_run;
+pub mod listener;
+pub mod admin;
+pub mod devices;
+pub mod user_policy;
+pub mod plans;
+pub mod operators;
+pub mod webhook;
+pub mod delivery_targets;
+pub mod email;
+pub mod outbound;
+pub mod routing;
+pub mod quiet_hours;
+pub mod dedup;
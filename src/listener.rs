@@ -0,0 +1,227 @@
+use nats::jetstream::{JetStreamOptions, SubscribeOptions};
+
+use crate::error::{Error, Result};
+use crate::notification::Notification;
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use uuid::Uuid;
+
+// How often the listener checks if it must stop while no message arrives
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Desktop notifications are grouped under this name
+const APP_NAME: &str = "command_notifier";
+
+// Where the received notifications are displayed
+pub trait NotificationSink {
+    fn display(&mut self, notification: &Notification) -> Result<()>;
+}
+
+pub struct TerminalSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TerminalSink<W> {
+    pub fn new(writer: W) -> TerminalSink<W> {
+        TerminalSink { writer }
+    }
+}
+
+impl<W: Write> NotificationSink for TerminalSink<W> {
+    fn display(&mut self, notification: &Notification) -> Result<()> {
+        // Printed lines cannot be updated, the replacement of a notification of a group is marked as such
+        let marker = if notification.replaces.is_some() { "(update) " } else { "" };
+        writeln!(self.writer, "{}{}\n", marker, format_notification(notification))
+            .and_then(|_| self.writer.flush())
+            .map_err(|err| Error::Internal(format!("Failed to print the notification: {}", err)))
+    }
+}
+
+// Ids the notification server gave to the shown notifications of a group, so that the next one of the group updates
+// the shown one instead of showing a new one
+#[derive(Debug, Default)]
+pub struct GroupedNotifications {
+    shown_ids: HashMap<Uuid, u32>,
+}
+
+impl GroupedNotifications {
+    // The replacement takes over the shown notification, only the last one of a group is kept
+    pub fn take_replaced(&mut self, notification: &Notification) -> Option<u32> {
+        notification.replaces.and_then(|replaces| self.shown_ids.remove(&replaces))
+    }
+
+    pub fn shown(&mut self, notification: &Notification, shown_id: u32) {
+        if let (Some(id), Some(_)) = (notification.id, &notification.group) {
+            self.shown_ids.insert(id, shown_id);
+        }
+    }
+}
+
+// Freedesktop notifications over D-Bus, printed to the terminal when no notification server answers (ex: SSH session)
+pub struct DesktopSink {
+    fallback: TerminalSink<std::io::Stdout>,
+    grouped: GroupedNotifications,
+}
+
+impl DesktopSink {
+    pub fn new() -> DesktopSink {
+        DesktopSink { fallback: TerminalSink::new(std::io::stdout()), grouped: GroupedNotifications::default() }
+    }
+}
+
+impl Default for DesktopSink {
+    fn default() -> Self {
+        DesktopSink::new()
+    }
+}
+
+impl NotificationSink for DesktopSink {
+    fn display(&mut self, notification: &Notification) -> Result<()> {
+        let mut desktop_notification = notify_rust::Notification::new();
+        desktop_notification
+            .appname(APP_NAME)
+            .summary(&summary_of(notification))
+            .body(&notification.message);
+        if let Some(replaced_id) = self.grouped.take_replaced(notification) {
+            desktop_notification.id(replaced_id);
+        }
+        // Only the freedesktop servers give back the id of the shown notification
+        match desktop_notification.show() {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Ok(handle) => self.grouped.shown(notification, handle.id()),
+            #[cfg(not(all(unix, not(target_os = "macos"))))]
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!("Failed to show the desktop notification, printing it instead: {}", err);
+                return self.fallback.display(notification);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn summary_of(notification: &Notification) -> String {
+    let title = notification.title.as_deref().unwrap_or("Notification");
+    match &notification.host {
+        Some(host) => format!("{} ({})", title, host),
+        None => title.to_string(),
+    }
+}
+
+pub fn format_notification(notification: &Notification) -> String {
+    // notify-run repeats its title as the first line of the message
+    let header = match (&notification.title, &notification.host) {
+        (Some(title), _) if !notification.message.starts_with(title.as_str()) => Some(summary_of(notification)),
+        (_, Some(host)) => Some(format!("[{}]", host)),
+        _ => None,
+    };
+    match header {
+        Some(header) => format!("{}\n{}", header, notification.message),
+        None => notification.message.clone(),
+    }
+}
+
+// The server publishes JSON notifications, other publishers (ex: `nats pub`) may send plain text
+pub fn parse_notification(payload: &[u8]) -> Notification {
+    serde_json::from_slice(payload).unwrap_or_else(|_| Notification {
+        message: String::from_utf8_lossy(payload).into_owned(),
+        ..Default::default()
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    pub nats_url: String,
+    // User creds created by /nsc/create, None for a NATS server without authentication
+    pub creds_path: Option<String>,
+    pub subject: String,
+    // Name of the JetStream consumer, to receive the notifications sent while the listener was offline
+    pub durable_name: String,
+}
+
+// Names of JetStream consumers cannot contain dots or wildcards (ex: from a hostname)
+pub fn sanitize_durable_name(name: &str) -> String {
+    name.chars()
+        .map(|character| if character.is_ascii_alphanumeric() || character == '-' || character == '_' { character } else { '-' })
+        .collect()
+}
+
+// Once connected, reconnects forever and the client restores the subscriptions
+pub fn connect(config: &ListenerConfig) -> Result<nats::Connection> {
+    let options = match &config.creds_path {
+        Some(creds_path) => nats::Options::with_credentials(creds_path),
+        None => nats::Options::new(),
+    };
+    options
+        .with_name("notify-listen")
+        .max_reconnects(None)
+        .disconnect_callback(|| tracing::warn!("Disconnected from NATS, reconnecting"))
+        .reconnect_callback(|| tracing::info!("Reconnected to NATS"))
+        .connect(&config.nats_url)
+        .map_err(|err| Error::nats(format!("Failed to connect to NATS at {}: {}", config.nats_url, err)))
+}
+
+pub enum ListenerSubscription {
+    // No stream captures the subject: only the notifications sent while connected are received
+    Core(nats::Subscription),
+    JetStream(nats::jetstream::PushSubscription),
+}
+
+impl ListenerSubscription {
+    fn next_timeout(&self, timeout: Duration) -> std::io::Result<nats::Message> {
+        match self {
+            ListenerSubscription::Core(subscription) => subscription.next_timeout(timeout),
+            ListenerSubscription::JetStream(subscription) => subscription.next_timeout(timeout),
+        }
+    }
+
+    pub fn is_jetstream(&self) -> bool {
+        matches!(self, ListenerSubscription::JetStream(_))
+    }
+}
+
+// Catch up from a JetStream stream when one captures the subject, plain subscription otherwise
+pub fn subscribe(connection: &nats::Connection, config: &ListenerConfig) -> Result<ListenerSubscription> {
+    let jetstream = nats::jetstream::JetStream::new(connection.clone(), JetStreamOptions::new());
+    let options = SubscribeOptions::new()
+        .durable_name(sanitize_durable_name(&config.durable_name))
+        .deliver_all()
+        .ack_explicit();
+    match jetstream.subscribe_with_options(&config.subject, &options) {
+        Ok(subscription) => {
+            tracing::info!(subject = config.subject, "Subscribed with JetStream, catching up on the missed notifications");
+            return Ok(ListenerSubscription::JetStream(subscription));
+        }
+        Err(err) => tracing::info!(subject = config.subject, "JetStream not available, subscribing without catch-up: {}", err),
+    }
+
+    connection.subscribe(&config.subject)
+        .map(ListenerSubscription::Core)
+        .map_err(|err| Error::nats(format!("Failed to subscribe to {}: {}", config.subject, err)))
+}
+
+// Display the notifications until `stop` is set or the subscription is closed
+pub fn listen<S: NotificationSink>(subscription: &ListenerSubscription, sink: &mut S, stop: &AtomicBool) -> Result<()> {
+    while !stop.load(Ordering::Relaxed) {
+        let message = match subscription.next_timeout(POLL_INTERVAL) {
+            Ok(message) => message,
+            Err(err) if err.kind() == ErrorKind::TimedOut => continue,
+            Err(err) => return Err(Error::nats(format!("The subscription was closed: {}", err))),
+        };
+
+        // A notification that cannot be displayed is not acked, JetStream delivers it again
+        if let Err(err) = sink.display(&parse_notification(&message.data)) {
+            tracing::error!("{}", err);
+            continue;
+        }
+        if subscription.is_jetstream() {
+            if let Err(err) = message.ack() {
+                tracing::warn!("Failed to ack the notification: {}", err);
+            }
+        }
+    }
+    Ok(())
+}
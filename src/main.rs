@@ -1,116 +1,163 @@
 use axum::{
-    debug_handler, extract::{Extension, Path, Request, State}, http::StatusCode, response::IntoResponse, routing::{get, post}, Json, Router,
-    body::Body,
+    debug_handler, extract::{Request, State}, http::{header::CONTENT_TYPE, StatusCode}, response::IntoResponse, routing::{get, post}, Json, Router,
 };
 
-use command_notifier::{accounts_lifecycle::{create_and_insert_user, delete_user_everywhere, get_admin_creds_if_not_exists}, postgres::{self, add_api_key, setup_postgres_client, verify_api_key, verify_nsc_user_exists}};
+use clap::{Parser, Subcommand, ValueEnum};
+use command_notifier::{admin::{describe_account, get_user_details, revoke_api_key, CreatedApiKey, RevokedApiKey, RevokedDevice, TableOutput, UserCreation}, dedup::DedupConfig, delivery_targets::{confirm_email_target, create_delivery_target, get_delivery_targets, remove_delivery_target, DeliveryConfig, DeliveryTarget, TargetConfig}, devices::{register_device, revoke_device}, email::EmailSender, error::{method_not_allowed_middleware, panic_response, route_not_found, Error, JsonBody, PathParams, QueryParams}, health::{check_readiness, get_nats_probe_creds_path, ReadinessConfig}, metrics::{encode_metrics, metrics_middleware, record_message_sent}, notification::Notification, outbound::{enqueue_notification, get_dead_letters, retry_dead_letter, NatsPayload, OutboundQueueConfig, OutboundWorker}, request_id::{request_id_middleware, REQUEST_ID_HEADER}, shutdown::{broadcast_shutdown, join_workers, shutdown_signal, spawn_graceful_shutdown, wait_for_shutdown, ShutdownConfig}, tls::{load_server_config, require_client_certificate, spawn_tls_reload, ClientCertAcceptor, TlsConfig, TLS_RELOAD_INTERVAL}, user_policy::UserPolicy, accounts_lifecycle::{create_and_insert_user, delete_user_everywhere, generate_api_key, ProvisioningConfig, UserProvisioning}, nats_resolver::{get_nats_url, AccountResolver}, nsc_accounts_utils::get_nsc_store_dir, oidc::{is_jwt, OidcValidator}, operators::{get_user_operator, Operators}, plans::{change_account_plan, Plans}, postgres::{get_identity_query, list_api_keys, list_devices, list_nsc_user_records, setup_postgres_client, verify_api_key, verify_nsc_user_exists}, quiet_hours::{get_quiet_hours, remove_quiet_hours, set_quiet_hours, DigestScheduler, DigestSchedulerConfig, QuietHours}, reconcile::{reconcile_operators, RepairStatus}, routing::{create_routing_rule, get_history, get_routing_rules, remove_routing_rule, replace_routing_rule, HistoryPruner, HistoryRetention, RoutingRuleRequest}};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::{env, net::SocketAddr};
-use serde::Deserialize;
 use std::sync::Arc;
-use std::path;
-use std::io::{self, Read};
-use std::process::Command;
-use std::fs;
-use tokio_postgres::{Client, NoTls};
+use std::time::Instant;
 use uuid::Uuid;
-use tower::ServiceBuilder;
-
-use tower_http::auth::AddAuthorization;
 
 use axum::middleware::from_fn;
-
-#[derive(Deserialize)]
-struct SendMessage {
-    message: String,
-}
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use tower_http::{catch_panic::CatchPanicLayer, trace::{DefaultOnResponse, TraceLayer}};
+use tracing::Level;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
 
 #[derive(Clone)]
 struct AppState {
     creds_base_path: String,
-    operator_name: String,
+    operators: Operators,
     postgres_client: Arc<tokio_postgres::Client>,
     main_topic: String,
-    nats_url: String
+    nats_url: String,
+    account_resolver: AccountResolver,
+    identity_query: Option<String>,
+    oidc_validator: Option<Arc<OidcValidator>>,
+    nsc_store_dir: String,
+    nats_probe_creds_path: Option<String>,
+    user_policy: UserPolicy,
+    plans: Plans,
+    // Sends the confirmations of the email targets, None without SMTP server
+    email_sender: Option<EmailSender>,
+    dedup: DedupConfig
+}
+
+fn parse_user_id(user_id: &str) -> Result<Uuid, Error> {
+    Uuid::parse_str(user_id)
+        .map_err(|_| Error::InvalidInput("Invalid user id, it should be an uuid".to_string()))
 }
 
 #[debug_handler]
 async fn send_message(
-    Path(user_id): Path<String>, 
+    PathParams(user_id): PathParams<String>, 
     State(state): State<AppState>,
-    Json(payload): Json<SendMessage>
-) -> impl IntoResponse {
+    JsonBody(notification): JsonBody<Notification>
+) -> Result<impl IntoResponse, Error> {
+    let started_at = Instant::now();
+    let result = send_to_user(state, &user_id, &notification).await;
+    record_message_sent(result.as_ref().map_or_else(|e| e.code(), |_| "success"), started_at);
+    result?;
 
-    // Verify if user exists in the database
+    Ok((StatusCode::ACCEPTED, "Queued for delivery"))
+}
 
+// Only queued, the workers of the outbound queue deliver it to NATS and to the delivery targets
+async fn send_to_user(state: AppState, user_id: &str, notification: &Notification) -> Result<(), Error> {
     let AppState {
-        creds_base_path,
-        operator_name,
+        creds_base_path: _,
+        operators: _,
         postgres_client,
-        main_topic,
-        nats_url
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup
     } = state;
 
-    let user_uuid = Uuid::parse_str(&user_id);
-
-    if user_uuid.is_err() {
-        return (StatusCode::BAD_REQUEST, "Invalid user id, it should be an uuid").into_response();
-    }
-    let user_uuid = user_uuid.unwrap();
-    let account_name = &user_id;
-    let user_exists = verify_nsc_user_exists(Arc::clone(&postgres_client), user_uuid).await.unwrap();
+    let user_uuid = parse_user_id(user_id)?;
+    let user_exists = verify_nsc_user_exists(Arc::clone(&postgres_client), user_uuid).await?;
     if !user_exists {
-        return (StatusCode::NOT_FOUND, "User not found").into_response();
-    }
-    let creds_admin_path = get_admin_creds_if_not_exists(&creds_base_path, &operator_name, &account_name).await;
-    if let Err(e) = creds_admin_path {
-        // Log the error using a logging library or custom logging mechanism
-        println!("Failed to get the admin credentials of the user: {:?}", e);
-        
-        // Return an internal server error response
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to get the admin credentials of the user, contact administrator").into_response();
+        return Err(Error::NotFound("User not found".to_string()));
     }
-    let creds_admin_path = creds_admin_path.unwrap();
-    println!("Creds admin path: {:?}", creds_admin_path);
-    // TODO: Put an error message at this unwrap, as it's often due to the docker service not being up
-    let nats_client = nats::Options::with_credentials(creds_admin_path).connect(nats_url).unwrap();
-
-    let _ = match nats_client.publish(&main_topic, &payload.message) {
-        Ok(_) => (
-            StatusCode::OK,
-            format!("Sent to account {} : {}", account_name, &payload.message)
-        ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error sending message to account {}: {}", account_name, e)
-        )
+    enqueue_notification(postgres_client, &dedup, user_uuid, notification).await?;
+    Ok(())
+}
+
+async fn healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let AppState {
+        creds_base_path,
+        operators,
+        postgres_client,
+        main_topic: _,
+        nats_url,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir,
+        nats_probe_creds_path,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let config = ReadinessConfig {
+        creds_base_path: &creds_base_path,
+        operator_names: operators.names().collect(),
+        nsc_store_dir: &nsc_store_dir,
+        nats_url: &nats_url,
+        nats_probe_creds_path: nats_probe_creds_path.as_deref(),
     };
-    
-    (StatusCode::OK, "Sent to user").into_response()
+    let report = check_readiness(postgres_client, &config).await;
+
+    for check in report.failed_checks() {
+        tracing::warn!(check = check.check, status = ?check.status, "Readiness check failed");
+    }
+    let status_code = if report.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status_code, Json(report))
 }
 
-async fn auth_middleware<Body>(
+async fn metrics() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], encode_metrics())
+}
+
+async fn auth_middleware(
     State(state): State<AppState>,
-    Path(user_id): Path<String>,
+    PathParams(path_params): PathParams<HashMap<String, String>>,
     request: Request<axum::body::Body>,
     next: axum::middleware::Next,
 ) -> axum::response::Response {
 
     let AppState {
         creds_base_path: _,
-        operator_name: _,
+        operators: _,
         postgres_client,
         main_topic: _,
-        nats_url: _
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
     } = state;
 
-    let user_uuid = Uuid::parse_str(&user_id);
-    if user_uuid.is_err() {
-        return (StatusCode::BAD_REQUEST, "Invalid user id, it should be an uuid").into_response();
-    }
-    let user_uuid = user_uuid.unwrap();
-    let user_exists = verify_nsc_user_exists(Arc::clone(&postgres_client), user_uuid).await.unwrap();
-    if !user_exists {
-        return (StatusCode::NOT_FOUND, "User not found").into_response();
+    let user_id = path_params.get("user_id").map(String::as_str).unwrap_or_default();
+    let user_uuid = match parse_user_id(user_id) {
+        Ok(user_uuid) => user_uuid,
+        Err(e) => return e.into_response(),
+    };
+    match verify_nsc_user_exists(Arc::clone(&postgres_client), user_uuid).await {
+        Ok(true) => {}
+        Ok(false) => return Error::NotFound("User not found".to_string()).into_response(),
+        Err(e) => return e.into_response(),
     }
     
     let auth_header = request
@@ -118,151 +165,1067 @@ async fn auth_middleware<Body>(
         .get(axum::http::header::AUTHORIZATION)
         .and_then(|header| header.to_str().ok());
 
-    // verify_api_key(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, api_key_input: &str)
-    match auth_header {
-        Some(auth_header) if verify_api_key(postgres_client, user_uuid, auth_header).await.unwrap() => {
-            next.run(request).await
+    // Logged-in users (ex: web dashboard) send the JWT of the OIDC provider instead of an api key
+    let bearer_token = auth_header.and_then(|header| header.strip_prefix("Bearer "));
+    if let (Some(oidc_validator), Some(token)) = (oidc_validator, bearer_token) {
+        if is_jwt(token) {
+            return match oidc_validator.validate_token(token).await {
+                Ok(subject) if subject == user_uuid => next.run(request).await,
+                Ok(_) => Error::Forbidden("The token belongs to another user".to_string()).into_response(),
+                Err(e) => e.into_response(),
+            };
         }
-        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+
+    // verify_api_key(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, api_key_input: &str)
+    let Some(auth_header) = auth_header else {
+        return Error::Unauthorized("Missing or invalid api key".to_string()).into_response();
+    };
+    match verify_api_key(postgres_client, user_uuid, auth_header).await {
+        Ok(true) => next.run(request).await,
+        Ok(false) => Error::Unauthorized("Missing or invalid api key".to_string()).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
+#[derive(Deserialize)]
+struct CreateUserQuery {
+    operator: Option<String>,
+}
+
 async fn create_nsc_user(
     State(state): State<AppState>,
-    Path(user_id): Path<String>,
-)-> impl IntoResponse {
+    PathParams(user_id): PathParams<String>,
+    QueryParams(query): QueryParams<CreateUserQuery>,
+) -> Result<impl IntoResponse, Error> {
     let AppState {
         creds_base_path,
-        operator_name,
+        operators,
         postgres_client,
         main_topic: _,
-        nats_url: _
+        nats_url: _,
+        account_resolver,
+        identity_query,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy,
+        plans,
+        email_sender: _,
+        dedup: _
     } = state;
 
-    let user_uuid = Uuid::parse_str(&user_id);
-    if user_uuid.is_err() {
-        return (StatusCode::BAD_REQUEST, "Invalid user id, it should be an uuid").into_response();
-    }
-    let user_uuid = user_uuid.unwrap();
-
-    // TODO: Test if user exists in the auth table
+    let user_uuid = parse_user_id(&user_id)?;
+    let operator_name = query.operator.unwrap_or_else(|| operators.default_operator.clone());
 
-    let result = create_and_insert_user(postgres_client, &creds_base_path, &operator_name, user_uuid).await;
+    let config = ProvisioningConfig {
+        creds_base_path: &creds_base_path,
+        operator_name: &operator_name,
+        signing_key: operators.signing_key(&operator_name)?,
+        account_resolver: &account_resolver,
+        identity_query: identity_query.as_deref(),
+        user_policy: &user_policy,
+        plans: &plans,
+    };
+    let result = create_and_insert_user(postgres_client, &config, user_uuid).await;
 
-    match result {
-        Ok(_) => return (StatusCode::OK, "User created").into_response(),
-        Err(e) => {
-            println!("Error when inserting user: {:?}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Error to create user").into_response()
-        }
+    match result? {
+        UserProvisioning::Created => Ok((StatusCode::OK, "User created")),
+        UserProvisioning::AlreadyProvisioned => Ok((StatusCode::OK, "User already exists")),
+        UserProvisioning::UnknownIdentity => Err(Error::NotFound("User not found".to_string())),
     }
 
 }
 
 async fn create_api_key(
     State(state): State<AppState>,
-    Path(user_id): Path<String>
-) -> impl IntoResponse {
+    PathParams(user_id): PathParams<String>
+) -> Result<impl IntoResponse, Error> {
 
     let AppState {
         creds_base_path: _,
-        operator_name: _,
+        operators: _,
         postgres_client,
         main_topic: _,
-        nats_url: _
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
     } = state;
 
-    let user_uuid = Uuid::parse_str(&user_id);
-    if user_uuid.is_err() {
-        return (StatusCode::BAD_REQUEST, "Invalid user id, it should be an uuid").into_response();
-    }
-    let user_uuid = user_uuid.unwrap();
-    let user_exists = verify_nsc_user_exists(Arc::clone(&postgres_client), user_uuid).await.unwrap();
-    if !user_exists {
-        return (StatusCode::NOT_FOUND, "User not found").into_response();
-    }
+    let user_uuid = parse_user_id(&user_id)?;
+    let api_key = generate_api_key(postgres_client, user_uuid).await?;
+    Ok((StatusCode::OK, api_key))
+}
+
+async fn delete_nsc_user(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path,
+        operators,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+
+    let operator_name = get_user_operator(Arc::clone(&postgres_client), &operators, user_uuid).await?;
+    let report = delete_user_everywhere(postgres_client, &creds_base_path, &operator_name, &account_resolver, user_uuid).await;
 
-    // Generate a new random UUID
-    let api_key = Uuid::new_v4().to_string();
-    let result = add_api_key(postgres_client, user_uuid, &api_key).await;
-    if let Err(e) = result {
-        println!("Failed to add the api key: {:?}", e);
-        // Return an internal server error response
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to add the api key, contact administrator").into_response();
+    let status_code = if report.is_success() {
+        StatusCode::OK
+    } else if report.is_total_failure() {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    for step in report.failed_steps() {
+        tracing::error!(user_id, step = step.step, status = ?step.status, "Failed to delete the user");
     }
-    (StatusCode::OK, api_key).into_response()
+    Ok((status_code, Json(report)))
 }
 
-async fn delete_nsc_user(
+#[derive(Deserialize)]
+struct CreateDeviceRequest {
+    name: String,
+}
+
+async fn create_device(
     State(state): State<AppState>,
-    Path(user_id): Path<String>
-) -> impl IntoResponse {
-    // TODO: no need to run it again if already deleted
+    PathParams(user_id): PathParams<String>,
+    JsonBody(body): JsonBody<CreateDeviceRequest>,
+) -> Result<impl IntoResponse, Error> {
     let AppState {
         creds_base_path,
-        operator_name,
+        operators,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let operator_name = get_user_operator(Arc::clone(&postgres_client), &operators, user_uuid).await?;
+    let registered_device = register_device(postgres_client, &creds_base_path, &operator_name, &user_policy, user_uuid, &body.name).await?;
+    Ok((StatusCode::CREATED, Json(registered_device)))
+}
+
+async fn list_user_devices(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    Ok(Json(list_devices(postgres_client, user_uuid).await?))
+}
+
+async fn revoke_user_device(
+    State(state): State<AppState>,
+    PathParams((user_id, device_name)): PathParams<(String, String)>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path,
+        operators,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let operator_name = get_user_operator(Arc::clone(&postgres_client), &operators, user_uuid).await?;
+    revoke_device(postgres_client, &creds_base_path, &operator_name, operators.signing_key(&operator_name)?, &account_resolver, user_uuid, &device_name).await?;
+    Ok((StatusCode::OK, "Device revoked"))
+}
+
+async fn list_user_targets(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let targets = get_delivery_targets(postgres_client, user_uuid).await?;
+    Ok(Json(targets.iter().map(DeliveryTarget::redacted).collect::<Vec<_>>()))
+}
+
+async fn create_user_target(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+    JsonBody(config): JsonBody<TargetConfig>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let target = create_delivery_target(postgres_client, email_sender.as_ref(), user_uuid, &config).await?;
+    tracing::info!(%user_uuid, target_id = %target.id, kind = target.config.kind(), "Delivery target created");
+    Ok((StatusCode::CREATED, Json(target.redacted())))
+}
+
+async fn delete_user_target(
+    State(state): State<AppState>,
+    PathParams((user_id, target_id)): PathParams<(String, String)>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let target_id = Uuid::parse_str(&target_id)
+        .map_err(|_| Error::InvalidInput("Invalid target id, it should be an uuid".to_string()))?;
+    remove_delivery_target(postgres_client, user_uuid, target_id).await?;
+    Ok((StatusCode::OK, "Delivery target deleted"))
+}
+
+#[derive(Deserialize)]
+struct ConfirmTargetRequest {
+    token: String,
+}
+
+// The token is the one sent to the address of the email target
+async fn confirm_user_target(
+    State(state): State<AppState>,
+    PathParams((user_id, target_id)): PathParams<(String, String)>,
+    JsonBody(body): JsonBody<ConfirmTargetRequest>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let target_id = Uuid::parse_str(&target_id)
+        .map_err(|_| Error::InvalidInput("Invalid target id, it should be an uuid".to_string()))?;
+    let target = confirm_email_target(postgres_client, user_uuid, target_id, &body.token).await?;
+    tracing::info!(%user_uuid, target_id = %target.id, "Delivery target confirmed");
+    Ok(Json(target.redacted()))
+}
+
+async fn list_user_dead_letters(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    Ok(Json(get_dead_letters(postgres_client, user_uuid).await?))
+}
+
+async fn retry_user_dead_letter(
+    State(state): State<AppState>,
+    PathParams((user_id, dead_letter_id)): PathParams<(String, String)>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let dead_letter_id = Uuid::parse_str(&dead_letter_id)
+        .map_err(|_| Error::InvalidInput("Invalid dead letter id, it should be an uuid".to_string()))?;
+    retry_dead_letter(postgres_client, user_uuid, dead_letter_id).await?;
+    Ok((StatusCode::ACCEPTED, "Queued for delivery"))
+}
+
+async fn list_user_routing_rules(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    Ok(Json(get_routing_rules(postgres_client, user_uuid).await?))
+}
+
+async fn create_user_routing_rule(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+    JsonBody(request): JsonBody<RoutingRuleRequest>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let rule = create_routing_rule(postgres_client, user_uuid, &request).await?;
+    tracing::info!(%user_uuid, rule_id = %rule.id, action = rule.rule.action.name(), "Routing rule created");
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+fn parse_rule_id(rule_id: &str) -> Result<Uuid, Error> {
+    Uuid::parse_str(rule_id)
+        .map_err(|_| Error::InvalidInput("Invalid rule id, it should be an uuid".to_string()))
+}
+
+async fn update_user_routing_rule(
+    State(state): State<AppState>,
+    PathParams((user_id, rule_id)): PathParams<(String, String)>,
+    JsonBody(request): JsonBody<RoutingRuleRequest>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let rule = replace_routing_rule(postgres_client, user_uuid, parse_rule_id(&rule_id)?, &request).await?;
+    tracing::info!(%user_uuid, rule_id = %rule.id, action = rule.rule.action.name(), "Routing rule updated");
+    Ok(Json(rule))
+}
+
+async fn delete_user_routing_rule(
+    State(state): State<AppState>,
+    PathParams((user_id, rule_id)): PathParams<(String, String)>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
         postgres_client,
         main_topic: _,
-        nats_url: _
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
     } = state;
 
-    let user_uuid = Uuid::parse_str(&user_id);
-    if user_uuid.is_err() {
-        return (StatusCode::BAD_REQUEST, "Invalid user id, it should be an uuid").into_response();
+    let user_uuid = parse_user_id(&user_id)?;
+    remove_routing_rule(postgres_client, user_uuid, parse_rule_id(&rule_id)?).await?;
+    Ok((StatusCode::OK, "Routing rule deleted"))
+}
+
+async fn list_user_history(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    Ok(Json(get_history(postgres_client, user_uuid).await?))
+}
+
+async fn get_user_quiet_hours(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let quiet_hours = get_quiet_hours(postgres_client, user_uuid)
+        .await?
+        .ok_or_else(|| Error::NotFound("No quiet hours set".to_string()))?;
+    Ok(Json(quiet_hours))
+}
+
+async fn update_user_quiet_hours(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+    JsonBody(quiet_hours): JsonBody<QuietHours>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    set_quiet_hours(postgres_client, user_uuid, &quiet_hours).await?;
+    tracing::info!(%user_uuid, timezone = quiet_hours.timezone, "Quiet hours set");
+    Ok((StatusCode::OK, "Quiet hours set"))
+}
+
+async fn delete_user_quiet_hours(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators: _,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver: _,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans: _,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    remove_quiet_hours(postgres_client, user_uuid).await?;
+    Ok((StatusCode::OK, "Quiet hours removed"))
+}
+
+#[derive(Deserialize)]
+struct ChangePlanRequest {
+    plan: String,
+}
+
+async fn change_plan(
+    State(state): State<AppState>,
+    PathParams(user_id): PathParams<String>,
+    JsonBody(body): JsonBody<ChangePlanRequest>,
+) -> Result<impl IntoResponse, Error> {
+    let AppState {
+        creds_base_path: _,
+        operators,
+        postgres_client,
+        main_topic: _,
+        nats_url: _,
+        account_resolver,
+        identity_query: _,
+        oidc_validator: _,
+        nsc_store_dir: _,
+        nats_probe_creds_path: _,
+        user_policy: _,
+        plans,
+        email_sender: _,
+        dedup: _
+    } = state;
+
+    let user_uuid = parse_user_id(&user_id)?;
+    let operator_name = get_user_operator(Arc::clone(&postgres_client), &operators, user_uuid).await?;
+    let signing_key = operators.signing_key(&operator_name)?;
+    let plan_change = change_account_plan(postgres_client, &operator_name, signing_key, &account_resolver, &plans, user_uuid, &body.plan).await?;
+    Ok((StatusCode::OK, Json(plan_change)))
+}
+
+#[derive(Parser)]
+#[command(about = "Backend of the command notifier")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Start the HTTP server (default)
+    Serve,
+    /// Detect the drift between the nsc keystore, the creds directory and the database
+    Reconcile {
+        /// Repair the drift instead of only reporting it
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Manage the users, api keys and accounts without going through the HTTP server
+    Admin {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table, global = true)]
+        output: OutputFormat,
+        #[command(subcommand)]
+        command: AdminCommands,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum AdminCommands {
+    /// Users provisioned with an nsc account
+    #[command(subcommand)]
+    Users(UserCommands),
+    /// Api keys used to call /send
+    #[command(subcommand)]
+    Keys(KeyCommands),
+    /// Receiving devices of the users, each with its own NATS user
+    #[command(subcommand)]
+    Devices(DeviceCommands),
+    /// Nsc accounts of the users
+    #[command(subcommand)]
+    Accounts(AccountCommands),
+    /// Same as the reconcile command
+    Reconcile {
+        /// Repair the drift instead of only reporting it
+        #[arg(long)]
+        repair: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserCommands {
+    /// Create the nsc account and the creds of a user, like /nsc/create
+    Create {
+        user_id: Uuid,
+        /// Operator of the account (default: the default operator)
+        #[arg(long)]
+        operator: Option<String>,
+    },
+    /// Delete the user from nsc, NATS, the creds directory and the database, like /nsc/delete
+    Delete { user_id: Uuid },
+    /// List the users of the database
+    List,
+    /// Show a user and the ids of its api keys
+    Show { user_id: Uuid },
+}
+
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// Generate an api key, printed only once
+    Create { user_id: Uuid },
+    /// List the api keys of a user
+    List { user_id: Uuid },
+    /// Delete an api key, by its id
+    Revoke { api_key_id: Uuid },
+}
+
+#[derive(Subcommand)]
+enum DeviceCommands {
+    /// Create the NATS user of a device, its creds are written in the creds directory and printed with --output json
+    Create { user_id: Uuid, name: String },
+    /// List the devices of a user
+    List { user_id: Uuid },
+    /// Revoke the NATS user of a device and delete its creds
+    Revoke { user_id: Uuid, name: String },
+}
+
+#[derive(Subcommand)]
+enum AccountCommands {
+    /// Compare the account of a user in the nsc keystore, the creds directory and the database
+    Describe { user_id: Uuid },
+    /// Apply the limits of a plan to the account of a user, and push its JWT again
+    SetPlan { user_id: Uuid, plan: String },
+}
+
+fn print_output<T: Serialize + TableOutput>(output: OutputFormat, value: &T) {
+    match output {
+        OutputFormat::Table => print!("{}", value.to_table().render()),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value).expect("The output is serializable")),
     }
-    let user_uuid = user_uuid.unwrap();
+}
 
-    let result = delete_user_everywhere(postgres_client, &creds_base_path, &operator_name, user_uuid).await;
+fn exit_on_error<T>(result: Result<T, Error>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(2);
+    })
+}
 
-    match result {
-        Ok(_) => return (StatusCode::OK, "User deleted").into_response(),
-        Err(e) => {
-            println!("Error when deleting user: {:?}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Error to delete user").into_response()
+async fn reconcile_command(repair: bool, output: OutputFormat) {
+    // The report is printed on stdout
+    init_tracing(std::io::stderr);
+
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operators = exit_on_error(Operators::from_env());
+    let nsc_store_dir = get_nsc_store_dir();
+    let account_resolver = AccountResolver::from_env();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    let drifts = exit_on_error(reconcile_operators(postgres_client, &operators, &creds_base_path, &nsc_store_dir, &account_resolver, repair).await);
+
+    if drifts.is_empty() && matches!(output, OutputFormat::Table) {
+        println!("No drift detected");
+        return;
+    }
+    print_output(output, &drifts);
+
+    let all_repaired = drifts.iter().all(|drift| drift.repair == Some(RepairStatus::Repaired));
+    if !all_repaired {
+        std::process::exit(1);
+    }
+}
+
+// Exit code: 0 on success, 1 when the operation was only partially done, 2 on error
+async fn admin_command(command: AdminCommands, output: OutputFormat) {
+    if let AdminCommands::Reconcile { repair } = command {
+        return reconcile_command(repair, output).await;
+    }
+    // The output is printed on stdout
+    init_tracing(std::io::stderr);
+
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operators = exit_on_error(Operators::from_env());
+    let account_resolver = AccountResolver::from_env();
+    let user_policy = exit_on_error(UserPolicy::from_env());
+    let plans = exit_on_error(Plans::from_env());
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    match command {
+        AdminCommands::Users(UserCommands::Create { user_id, operator }) => {
+            let identity_query = get_identity_query();
+            let operator_name = operator.unwrap_or_else(|| operators.default_operator.clone());
+            let config = ProvisioningConfig {
+                creds_base_path: &creds_base_path,
+                operator_name: &operator_name,
+                signing_key: exit_on_error(operators.signing_key(&operator_name)),
+                account_resolver: &account_resolver,
+                identity_query: identity_query.as_deref(),
+                user_policy: &user_policy,
+                plans: &plans,
+            };
+            let status = exit_on_error(create_and_insert_user(postgres_client, &config, user_id).await);
+            let unknown_identity = status == UserProvisioning::UnknownIdentity;
+            print_output(output, &UserCreation { user_id, status });
+            if unknown_identity {
+                std::process::exit(1);
+            }
+        }
+        AdminCommands::Users(UserCommands::Delete { user_id }) => {
+            let operator_name = exit_on_error(get_user_operator(Arc::clone(&postgres_client), &operators, user_id).await);
+            let report = delete_user_everywhere(postgres_client, &creds_base_path, &operator_name, &account_resolver, user_id).await;
+            print_output(output, &report);
+            if !report.is_success() {
+                std::process::exit(1);
+            }
+        }
+        AdminCommands::Users(UserCommands::List) => {
+            print_output(output, &exit_on_error(list_nsc_user_records(postgres_client).await));
+        }
+        AdminCommands::Users(UserCommands::Show { user_id }) => {
+            print_output(output, &exit_on_error(get_user_details(postgres_client, user_id).await));
+        }
+        AdminCommands::Keys(KeyCommands::Create { user_id }) => {
+            let api_key = exit_on_error(generate_api_key(postgres_client, user_id).await);
+            print_output(output, &CreatedApiKey { user_id, api_key });
         }
+        AdminCommands::Keys(KeyCommands::List { user_id }) => {
+            print_output(output, &exit_on_error(list_api_keys(postgres_client, user_id).await));
+        }
+        AdminCommands::Keys(KeyCommands::Revoke { api_key_id }) => {
+            exit_on_error(revoke_api_key(postgres_client, api_key_id).await);
+            print_output(output, &RevokedApiKey { id: api_key_id });
+        }
+        AdminCommands::Devices(DeviceCommands::Create { user_id, name }) => {
+            let operator_name = exit_on_error(get_user_operator(Arc::clone(&postgres_client), &operators, user_id).await);
+            print_output(output, &exit_on_error(register_device(postgres_client, &creds_base_path, &operator_name, &user_policy, user_id, &name).await));
+        }
+        AdminCommands::Devices(DeviceCommands::List { user_id }) => {
+            print_output(output, &exit_on_error(list_devices(postgres_client, user_id).await));
+        }
+        AdminCommands::Devices(DeviceCommands::Revoke { user_id, name }) => {
+            let operator_name = exit_on_error(get_user_operator(Arc::clone(&postgres_client), &operators, user_id).await);
+            let signing_key = exit_on_error(operators.signing_key(&operator_name));
+            exit_on_error(revoke_device(postgres_client, &creds_base_path, &operator_name, signing_key, &account_resolver, user_id, &name).await);
+            print_output(output, &RevokedDevice { user_id, name });
+        }
+        AdminCommands::Accounts(AccountCommands::Describe { user_id }) => {
+            let operator_name = exit_on_error(get_user_operator(Arc::clone(&postgres_client), &operators, user_id).await);
+            print_output(output, &exit_on_error(describe_account(postgres_client, &creds_base_path, &operator_name, user_id).await));
+        }
+        AdminCommands::Accounts(AccountCommands::SetPlan { user_id, plan }) => {
+            let operator_name = exit_on_error(get_user_operator(Arc::clone(&postgres_client), &operators, user_id).await);
+            let signing_key = exit_on_error(operators.signing_key(&operator_name));
+            print_output(output, &exit_on_error(change_account_plan(postgres_client, &operator_name, signing_key, &account_resolver, &plans, user_id, &plan).await));
+        }
+        AdminCommands::Reconcile { .. } => unreachable!("Handled above"),
     }
 }
 
+// JSON logs, the level being set with RUST_LOG (default: info)
+fn init_tracing<W>(writer: W)
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(writer)
+        .init();
+}
+
 #[tokio::main]
 async fn main() {
-    
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Commands::Serve) {
+        Commands::Serve => serve().await,
+        Commands::Reconcile { repair } => reconcile_command(repair, OutputFormat::Table).await,
+        Commands::Admin { output, command } => admin_command(command, output).await,
+    }
+}
+
+async fn serve() {
+    init_tracing(std::io::stdout);
+
     let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
-    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let operators = Operators::from_env().expect("Invalid operators");
+    let nsc_store_dir = get_nsc_store_dir();
+    let tls_config = TlsConfig::from_env().expect("Invalid TLS configuration");
+    let user_policy = UserPolicy::from_env().expect("Invalid user JWT policy");
+    let plans = Plans::from_env().expect("Invalid account plans");
+    let delivery = DeliveryConfig::from_env().expect("Invalid delivery configuration");
+    let outbound_queue_config = OutboundQueueConfig::from_env().expect("Invalid outbound queue configuration");
+    let nats_payload = NatsPayload::from_env().expect("Invalid NATS payload");
+    let digest_scheduler_config = DigestSchedulerConfig::from_env().expect("Invalid digest scheduler configuration");
+    let history_retention = HistoryRetention::from_env().expect("Invalid history retention");
+    let dedup = DedupConfig::from_env().expect("Invalid deduplication window");
+    let shutdown_config = ShutdownConfig::from_env().expect("Invalid shutdown timeout");
     
     let postgres_client = setup_postgres_client().await;
     let postgres_client = Arc::new(postgres_client);
 
+    let oidc_validator = OidcValidator::from_env().await.expect("Failed to load the OIDC JWKS");
+
     let state = AppState {
-        creds_base_path: creds_base_path,
-        operator_name: operator_name,
+        creds_base_path,
+        operators,
         postgres_client: Arc::clone(&postgres_client),
-        // TODO: Pass the main topic as env
-        main_topic: "topic01".to_string(),
-        // TODO: Pass the nats url as env
-        nats_url: "localhost:4222".to_string()
+        main_topic: user_policy.notification_subject.clone(),
+        nats_url: get_nats_url(),
+        account_resolver: AccountResolver::from_env(),
+        identity_query: get_identity_query(),
+        oidc_validator: oidc_validator.map(Arc::new),
+        nsc_store_dir,
+        nats_probe_creds_path: get_nats_probe_creds_path(),
+        user_policy,
+        plans,
+        email_sender: delivery.email_sender.clone(),
+        dedup
     };
+
+    let shutdown = broadcast_shutdown(shutdown_signal());
+    let mut background_workers = OutboundWorker {
+        postgres_client: Arc::clone(&postgres_client),
+        creds_base_path: state.creds_base_path.clone(),
+        operators: state.operators.clone(),
+        nats_url: state.nats_url.clone(),
+        subject: state.main_topic.clone(),
+        nats_payload,
+        delivery,
+    }.spawn(&outbound_queue_config, shutdown.clone());
+    background_workers.push(DigestScheduler { postgres_client: Arc::clone(&postgres_client), dedup: state.dedup.clone() }.spawn(&digest_scheduler_config, shutdown.clone()));
+    background_workers.push(HistoryPruner { postgres_client: Arc::clone(&postgres_client) }.spawn(&history_retention, shutdown.clone()));
+
+    // Startup check: only report, the repair is done with the reconcile command
+    match reconcile_operators(Arc::clone(&postgres_client), &state.operators, &state.creds_base_path, &state.nsc_store_dir, &state.account_resolver, false).await {
+        Ok(drifts) if drifts.is_empty() => tracing::info!("No drift detected between nsc, the creds and the database"),
+        Ok(drifts) => {
+            for drift in &drifts {
+                tracing::warn!(account = drift.account_name, kind = ?drift.kind, "Drift detected, run the reconcile command with --repair to fix it");
+            }
+        }
+        Err(err) => tracing::error!("Failed to check the drift: {}", err),
+    }
     
+    // Routes provisioning the users, meant to be called by the other backends only
+    let admin_routes = Router::new()
+        .route("/user/:user_id/nsc/create", post(create_nsc_user))
+        .route("/user/:user_id/nsc/delete", post(delete_nsc_user))
+        .route("/user/:user_id/plan", post(change_plan));
+    let admin_routes = match tls_config.as_ref().and_then(|tls_config| tls_config.client_ca_path.as_ref()) {
+        Some(_) => admin_routes.route_layer(from_fn(require_client_certificate)),
+        None => admin_routes,
+    };
+
     // Set up the router
     let app_state = state.clone();
     let app = Router::new()
         .route("/send/:user_id", post(send_message))
+        // Minted with an api key or the OIDC token of the user (ex: the dashboard), the first one with `admin keys create`
+        .route("/user/:user_id/api-keys/create", post(create_api_key))
+        // Devices are registered by the users themselves (ex: from the tray app), with their api key or token
+        .route("/user/:user_id/devices", get(list_user_devices))
+        .route("/user/:user_id/devices/create", post(create_device))
+        .route("/user/:user_id/devices/:device_name/revoke", post(revoke_user_device))
+        // Webhooks and the other outputs of the notifications besides NATS
+        .route("/user/:user_id/targets", get(list_user_targets))
+        .route("/user/:user_id/targets/create", post(create_user_target))
+        .route("/user/:user_id/targets/:target_id/confirm", post(confirm_user_target))
+        .route("/user/:user_id/targets/:target_id/delete", post(delete_user_target))
+        // Deliveries that failed for good, they can be queued again
+        .route("/user/:user_id/dead-letters", get(list_user_dead_letters))
+        .route("/user/:user_id/dead-letters/:dead_letter_id/retry", post(retry_user_dead_letter))
+        // Rules deciding where the notifications go, and what was decided for the last ones
+        .route("/user/:user_id/routing-rules", get(list_user_routing_rules))
+        .route("/user/:user_id/routing-rules/create", post(create_user_routing_rule))
+        .route("/user/:user_id/routing-rules/:rule_id/update", post(update_user_routing_rule))
+        .route("/user/:user_id/routing-rules/:rule_id/delete", post(delete_user_routing_rule))
+        .route("/user/:user_id/history", get(list_user_history))
+        // Do-not-disturb window, the notifications sent during it being delivered as a digest
+        .route("/user/:user_id/quiet-hours", get(get_user_quiet_hours))
+        .route("/user/:user_id/quiet-hours/update", post(update_user_quiet_hours))
+        .route("/user/:user_id/quiet-hours/delete", post(delete_user_quiet_hours))
         .layer(from_fn(move |path, request, next| {
             let state = app_state.clone();
-            auth_middleware::<Body>(axum::extract::State(state), path, request, next)
+            auth_middleware(axum::extract::State(state), path, request, next)
         }))
-        .route("/user/:user_id/api-keys/create", post(create_api_key))
-        .route("/user/:user_id/nsc/create", post(create_nsc_user))
-        // TODO: It seems the deletion is not working for the removal of the file
-        .route("/user/:user_id/nsc/delete", post(delete_nsc_user))
+        .merge(admin_routes)
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .fallback(route_not_found)
+        .layer(from_fn(method_not_allowed_middleware))
+        // A panicking handler only fails its own request, inside the request id scope to report it
+        .layer(CatchPanicLayer::custom(panic_response))
+        .layer(from_fn(metrics_middleware))
+        .layer(TraceLayer::new_for_http()
+            .make_span_with(|request: &Request| {
+                let request_id = request.headers()
+                    .get(REQUEST_ID_HEADER)
+                    .and_then(|header| header.to_str().ok())
+                    .unwrap_or_default();
+                tracing::info_span!("request", method = %request.method(), uri = %request.uri(), request_id)
+            })
+            .on_response(DefaultOnResponse::new().level(Level::INFO)))
+        .layer(from_fn(request_id_middleware))
         .with_state(state);
     
     // Define the server address
     let addr = SocketAddr::from(([127, 0, 0, 1], 9090));
-    println!("Listening on {}", addr);
+
+    // A single deadline from the signal for the in-flight requests and then the workers
+    let handle = Handle::new();
+    let shutdown_deadline = spawn_graceful_shutdown(handle.clone(), wait_for_shutdown(shutdown.clone()), shutdown_config.timeout);
 
     // Start the server
-    axum_server::bind(addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    match tls_config {
+        Some(tls_config) => {
+            let server_config = load_server_config(&tls_config).expect("Failed to load the TLS certificates");
+            let rustls_config = RustlsConfig::from_config(Arc::new(server_config));
+            spawn_tls_reload(rustls_config.clone(), tls_config, TLS_RELOAD_INTERVAL);
+
+            tracing::info!("Listening on https://{}", addr);
+            axum_server::bind(addr)
+                .handle(handle)
+                .acceptor(ClientCertAcceptor::new(rustls_config))
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        None => {
+            tracing::info!("Listening on http://{}", addr);
+            axum_server::bind(addr)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+    }
+
+    // The workers finish their current delivery (or digest, pruning), the ones still queued are delivered after the restart
+    let shutdown_deadline = shutdown_deadline.await.expect("The graceful shutdown task failed");
+    join_workers(background_workers, shutdown_deadline).await;
+
+    // The router and its state are gone with the server, the last client closes the database connection.
+    // The NATS connections are not cached: each delivery flushed and closed its own.
+    drop(postgres_client);
+    tracing::info!("Server stopped");
 }
@@ -0,0 +1,146 @@
+use axum::{extract::{MatchedPath, Request}, middleware::Next, response::Response};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+
+use std::process::{Command, Output};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+// All the metrics are registered once in this registry, exposed on /metrics
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests_total: IntCounterVec,
+    pub http_request_duration_seconds: HistogramVec,
+    pub messages_sent_total: IntCounterVec,
+    pub send_duration_seconds: HistogramVec,
+    pub nats_connect_failures_total: IntCounter,
+    pub nsc_command_duration_seconds: HistogramVec,
+    pub database_errors_total: IntCounterVec,
+    pub deliveries_total: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new();
+
+        let http_requests_total = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled, by route and status"),
+            &["method", "route", "status"],
+        ).expect("Valid metric");
+        let http_request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Latency of the HTTP requests"),
+            &["method", "route", "status"],
+        ).expect("Valid metric");
+        let messages_sent_total = IntCounterVec::new(
+            Opts::new("messages_sent_total", "Messages queued with /send, by outcome"),
+            &["outcome"],
+        ).expect("Valid metric");
+        let send_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("send_duration_seconds", "Time to queue a message sent with /send"),
+            &["outcome"],
+        ).expect("Valid metric");
+        let nats_connect_failures_total = IntCounter::new("nats_connect_failures_total", "Failed connections to the NATS server")
+            .expect("Valid metric");
+        let nsc_command_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("nsc_command_duration_seconds", "Duration of the nsc commands"),
+            &["command", "outcome"],
+        ).expect("Valid metric");
+        let database_errors_total = IntCounterVec::new(
+            Opts::new("database_errors_total", "Failed database queries, by outcome"),
+            &["outcome"],
+        ).expect("Valid metric");
+        let deliveries_total = IntCounterVec::new(
+            Opts::new("deliveries_total", "Notifications delivered to the targets of the users, by kind and outcome"),
+            &["kind", "outcome"],
+        ).expect("Valid metric");
+
+        registry.register(Box::new(http_requests_total.clone())).expect("Metric registered once");
+        registry.register(Box::new(http_request_duration_seconds.clone())).expect("Metric registered once");
+        registry.register(Box::new(messages_sent_total.clone())).expect("Metric registered once");
+        registry.register(Box::new(send_duration_seconds.clone())).expect("Metric registered once");
+        registry.register(Box::new(nats_connect_failures_total.clone())).expect("Metric registered once");
+        registry.register(Box::new(nsc_command_duration_seconds.clone())).expect("Metric registered once");
+        registry.register(Box::new(database_errors_total.clone())).expect("Metric registered once");
+        registry.register(Box::new(deliveries_total.clone())).expect("Metric registered once");
+
+        Metrics {
+            registry,
+            http_requests_total,
+            http_request_duration_seconds,
+            messages_sent_total,
+            send_duration_seconds,
+            nats_connect_failures_total,
+            nsc_command_duration_seconds,
+            database_errors_total,
+            deliveries_total,
+        }
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+// Prometheus text format of all the metrics
+pub fn encode_metrics() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .expect("Metrics should be encodable");
+    String::from_utf8(buffer).expect("Metrics should be utf8")
+}
+
+pub fn record_message_sent(outcome: &str, started_at: Instant) {
+    METRICS.messages_sent_total.with_label_values(&[outcome]).inc();
+    METRICS.send_duration_seconds.with_label_values(&[outcome]).observe(started_at.elapsed().as_secs_f64());
+}
+
+pub fn record_nats_connect_failure() {
+    METRICS.nats_connect_failures_total.inc();
+}
+
+pub fn record_database_error(err: &tokio_postgres::Error) {
+    let outcome = if err.is_closed() { "connection_closed" } else { "query_failed" };
+    METRICS.database_errors_total.with_label_values(&[outcome]).inc();
+}
+
+pub fn record_delivery(kind: &str, outcome: &str) {
+    METRICS.deliveries_total.with_label_values(&[kind, outcome]).inc();
+}
+
+// Run an nsc command and record its duration, labeled by its subcommand (ex: "add account")
+pub fn run_nsc(command: &mut Command) -> std::io::Result<Output> {
+    let label = command.get_args()
+        .take(2)
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let started_at = Instant::now();
+    let output = command.output();
+    let outcome = match &output {
+        Ok(output) if output.status.success() => "success",
+        Ok(_) => "failure",
+        Err(_) => "spawn_error",
+    };
+    METRICS.nsc_command_duration_seconds
+        .with_label_values(&[&label, outcome])
+        .observe(started_at.elapsed().as_secs_f64());
+    output
+}
+
+// Count and time the requests, labeled by the route pattern to keep the cardinality low
+pub async fn metrics_middleware(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map(|matched_path| matched_path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let started_at = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+
+    METRICS.http_requests_total.with_label_values(&[&method, &route, &status]).inc();
+    METRICS.http_request_duration_seconds
+        .with_label_values(&[&method, &route, &status])
+        .observe(started_at.elapsed().as_secs_f64());
+    response
+}
@@ -0,0 +1,40 @@
+use crate::error::{Error, Result};
+use crate::metrics::record_nats_connect_failure;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+// The nats client has no connect timeout of its own (ex: a server accepting the connection but never answering)
+pub const NATS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Also used to push the account JWTs with the system account
+pub fn connect(nats_url: &str, creds_path: &str) -> Result<nats::Connection> {
+    let (sender, receiver) = mpsc::channel();
+    let (url, creds_path) = (nats_url.to_string(), creds_path.to_string());
+    // Past the timeout the connection attempt is left to its thread, dropped on completion as nobody receives it
+    thread::spawn(move || {
+        let _result = sender.send(nats::Options::with_credentials(creds_path).connect(&url));
+    });
+
+    let error = match receiver.recv_timeout(NATS_CONNECT_TIMEOUT) {
+        Ok(Ok(nats_client)) => return Ok(nats_client),
+        Ok(Err(err)) => err.to_string(),
+        Err(_) => format!("no answer after {:?}", NATS_CONNECT_TIMEOUT),
+    };
+    record_nats_connect_failure();
+    Err(Error::nats(format!("Failed to connect to NATS at {}, check that the server is up: {}", nats_url, error)))
+}
+
+// Publish a message on the account of a user, with its admin credentials
+pub fn publish_message(nats_url: &str, creds_path: &str, subject: &str, message: &str) -> Result<()> {
+    let nats_client = connect(nats_url, creds_path)?;
+
+    nats_client.publish(subject, message)
+        .map_err(|err| Error::nats(format!("Failed to publish on {}: {}", subject, err)))?;
+
+    // The publish is buffered, make sure it reached the server before reporting a success
+    nats_client.flush_timeout(FLUSH_TIMEOUT)
+        .map_err(|err| Error::nats(format!("Failed to flush the message: {}", err)))
+}
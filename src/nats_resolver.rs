@@ -0,0 +1,102 @@
+use std::env;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::nats_publisher::connect;
+use crate::nsc_accounts_utils::push_nsc_account_removal;
+
+// How the NATS server gets to know the accounts created by this backend
+#[derive(Clone, Debug)]
+pub enum AccountResolver {
+    // Account JWTs are handled outside of this service (ex: nats_authorization_server)
+    External,
+    // Push the JWTs to a nats-server running a `full` resolver, using the system account
+    Nats { nats_url: String, system_creds_path: String },
+    // Write the JWTs in the directory of the `full` resolver of the nats-server
+    Directory { path: String },
+}
+
+impl AccountResolver {
+    pub fn from_env() -> AccountResolver {
+        if let Ok(path) = env::var("NATS_RESOLVER_DIR") {
+            return AccountResolver::Directory { path };
+        }
+        if let Ok(system_creds_path) = env::var("NATS_SYSTEM_CREDS_PATH") {
+            return AccountResolver::Nats { nats_url: get_nats_url(), system_creds_path };
+        }
+        AccountResolver::External
+    }
+}
+
+pub fn get_nats_url() -> String {
+    env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".to_string())
+}
+
+pub fn get_account_jwt_path(resolver_dir: &str, account_id: &str) -> String {
+    format!("{}/{}.jwt", resolver_dir, account_id)
+}
+
+pub async fn push_account_jwt(account_resolver: &AccountResolver, account_id: &str, account_jwt: &str) -> Result<()> {
+    match account_resolver {
+        AccountResolver::External => Ok(()),
+        // The NATS client is blocking, and the provisioning lock is held meanwhile
+        AccountResolver::Nats { nats_url, system_creds_path } => {
+            let (nats_url, system_creds_path) = (nats_url.clone(), system_creds_path.clone());
+            let (account_id, account_jwt) = (account_id.to_string(), account_jwt.to_string());
+            tokio::task::spawn_blocking(move || push_account_jwt_to_nats(&nats_url, &system_creds_path, &account_id, &account_jwt))
+                .await
+                .map_err(|err| Error::Internal(format!("The push of the account jwt failed: {}", err)))?
+        }
+        AccountResolver::Directory { path } => write_account_jwt_to_dir(path, account_id, account_jwt),
+    }
+}
+
+pub fn remove_account_jwt(account_resolver: &AccountResolver, account_id: &str) -> Result<()> {
+    match account_resolver {
+        AccountResolver::External => Ok(()),
+        // The deletion claim has to be signed by the operator, whose keys are only known by nsc, and nsc push connects
+        // with the system user of its own keystore: the creds of NATS_SYSTEM_CREDS_PATH are only used for the updates
+        AccountResolver::Nats { nats_url, .. } => push_nsc_account_removal(account_id, nats_url).map(|_| ()),
+        AccountResolver::Directory { path } => remove_account_jwt_from_dir(path, account_id),
+    }
+}
+
+pub fn push_account_jwt_to_nats(nats_url: &str, system_creds_path: &str, account_id: &str, account_jwt: &str) -> Result<()> {
+    let nats_client = connect(nats_url, system_creds_path)?;
+
+    let subject = format!("$SYS.REQ.ACCOUNT.{}.CLAIMS.UPDATE", account_id);
+    let response = nats_client.request_timeout(&subject, account_jwt, Duration::from_secs(5))
+        .map_err(|err| Error::nats(format!("Failed to push the account jwt: {}", err)))?;
+
+    check_claims_update_response(&response.data)
+}
+
+pub fn check_claims_update_response(response: &[u8]) -> Result<()> {
+    // Success: {"data": {"account": "...", "code": 200, "message": "jwt updated"}}
+    // Failure: {"error": {"account": "...", "code": 500, "description": "..."}}
+    let response: serde_json::Value = serde_json::from_slice(response)
+        .map_err(|err| Error::nats(format!("Failed to parse the resolver response: {}", err)))?;
+
+    if let Some(error) = response.get("error") {
+        return Err(Error::nats(format!("Resolver refused the account jwt: {}", error["description"])));
+    }
+    match response["data"]["code"].as_u64() {
+        Some(200) => Ok(()),
+        _ => Err(Error::nats(format!("Unexpected resolver response: {}", response))),
+    }
+}
+
+pub fn write_account_jwt_to_dir(resolver_dir: &str, account_id: &str, account_jwt: &str) -> Result<()> {
+    std::fs::create_dir_all(resolver_dir)
+        .map_err(|err| Error::Internal(format!("Failed to create the resolver directory: {}", err)))?;
+    std::fs::write(get_account_jwt_path(resolver_dir, account_id), account_jwt)
+        .map_err(|err| Error::Internal(format!("Failed to write the account jwt: {}", err)))
+}
+
+pub fn remove_account_jwt_from_dir(resolver_dir: &str, account_id: &str) -> Result<()> {
+    match std::fs::remove_file(get_account_jwt_path(resolver_dir, account_id)) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(Error::Internal(format!("Failed to remove the account jwt: {}", err))),
+    }
+}
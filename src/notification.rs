@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use std::fmt;
+
+// Ordered, so that the targets can set the minimum priority they are delivered
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    pub fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Priority::Low => write!(f, "low"),
+            Priority::Normal => write!(f, "normal"),
+            Priority::High => write!(f, "high"),
+            Priority::Urgent => write!(f, "urgent"),
+        }
+    }
+}
+
+// Payload of /send, published as JSON on the account of the user
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    // Machine the notification comes from (ex: where notify-run ran the command)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // Kind of notification the routing rules match (ex: "backups")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    // Outcome of what is notified (ex: "success" or "failure" for notify-run)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    // Left out of the payload when normal, as it was before the priorities
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    // Set by the server when it queues the notification, the one replaces refers to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    // Notifications with the same key within the deduplication window are dropped, and counted in the history of the first one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_key: Option<String>,
+    // Each notification of a group within the window replaces the previous one (ex: the status of a pipeline)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    // Set by the server, id of the previous notification of the group, which the devices update instead of showing a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<Uuid>,
+}
+
+impl Notification {
+    // Templates of the delivery targets, with {{message}}, {{title}}, {{host}}, {{priority}} and {{user_id}},
+    // the values being escaped for the format of the template (ex: JSON)
+    pub fn render_template(&self, template: &str, user_id: Uuid, escape: fn(&str) -> String) -> String {
+        template
+            .replace("{{message}}", &escape(&self.message))
+            .replace("{{title}}", &escape(self.title.as_deref().unwrap_or_default()))
+            .replace("{{host}}", &escape(self.host.as_deref().unwrap_or_default()))
+            .replace("{{priority}}", &self.priority.to_string())
+            .replace("{{user_id}}", &user_id.to_string())
+    }
+}
@@ -0,0 +1,193 @@
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::notification::Notification;
+
+use std::collections::VecDeque;
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// Longer lines are cut in the notification, the terminal still gets the full output
+const MAX_LINE_LENGTH: usize = 300;
+
+// Settings of the notify-run client: the config file, overridden by the environment
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientConfig {
+    pub server_url: String,
+    pub user_id: String,
+    pub api_key: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ClientConfigFile {
+    server_url: Option<String>,
+    user_id: Option<String>,
+    api_key: Option<String>,
+}
+
+// $XDG_CONFIG_HOME/command_notifier/notify-run.toml, ~/.config by default
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_dir = env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()?;
+    Some(config_dir.join("command_notifier").join("notify-run.toml"))
+}
+
+// The given config file (ex: --config) must exist, the default one is optional
+pub fn load_client_config(config_path: Option<&Path>) -> Result<ClientConfig> {
+    let config_path = match config_path {
+        Some(config_path) if !config_path.exists() => {
+            return Err(Error::InvalidInput(format!("The config file {} does not exist", config_path.display())));
+        }
+        Some(config_path) => Some(config_path.to_path_buf()),
+        None => default_config_path().filter(|config_path| config_path.exists()),
+    };
+    let config_file = match config_path {
+        Some(config_path) => {
+            let content = std::fs::read_to_string(&config_path)
+                .map_err(|err| Error::InvalidInput(format!("Failed to read {}: {}", config_path.display(), err)))?;
+            toml::from_str(&content)
+                .map_err(|err| Error::InvalidInput(format!("Failed to parse {}: {}", config_path.display(), err)))?
+        }
+        None => ClientConfigFile::default(),
+    };
+
+    let missing = |name: &str| Error::InvalidInput(format!("{} is missing, set it in the config file or with NOTIFY_{}", name, name.to_uppercase()));
+    Ok(ClientConfig {
+        server_url: env::var("NOTIFY_SERVER_URL").ok()
+            .or(config_file.server_url)
+            .unwrap_or_else(|| "http://localhost:9090".to_string()),
+        user_id: env::var("NOTIFY_USER_ID").ok()
+            .or(config_file.user_id)
+            .ok_or_else(|| missing("user_id"))?,
+        api_key: env::var("NOTIFY_API_KEY").ok()
+            .or(config_file.api_key)
+            .ok_or_else(|| missing("api_key"))?,
+    })
+}
+
+#[derive(Debug)]
+pub struct CommandOutcome {
+    pub command: String,
+    // None when the command was killed by a signal
+    pub exit_code: Option<i32>,
+    pub duration: Duration,
+    pub stdout_tail: Vec<String>,
+    pub stderr_tail: Vec<String>,
+}
+
+impl CommandOutcome {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    pub fn to_notification(&self, host: Option<String>) -> Notification {
+        let status = match self.exit_code {
+            Some(0) => "succeeded".to_string(),
+            Some(exit_code) => format!("failed with exit code {}", exit_code),
+            None => "was killed".to_string(),
+        };
+        let title = format!("{} {} after {}", self.command, status, format_duration(self.duration));
+
+        let mut message = title.clone();
+        for (name, tail) in [("stdout", &self.stdout_tail), ("stderr", &self.stderr_tail)] {
+            if !tail.is_empty() {
+                message.push_str(&format!("\n\n{} (last {} lines):\n{}", name, tail.len(), tail.join("\n")));
+            }
+        }
+        let outcome = if self.success() { "success" } else { "failure" };
+        Notification { message, title: Some(title), host, status: Some(outcome.to_string()), ..Default::default() }
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+// Copy the output of the command to the terminal, and keep its last lines
+fn tee_tail<R, W>(reader: R, mut writer: W, tail_lines: usize) -> Vec<String>
+where
+    R: Read,
+    W: Write,
+{
+    let mut reader = BufReader::new(reader);
+    let mut tail = VecDeque::with_capacity(tail_lines);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let _result = writer.write_all(&line).and_then(|_| writer.flush());
+                if tail_lines == 0 {
+                    continue;
+                }
+                if tail.len() == tail_lines {
+                    tail.pop_front();
+                }
+                let text = String::from_utf8_lossy(&line);
+                tail.push_back(text.trim_end().chars().take(MAX_LINE_LENGTH).collect::<String>());
+            }
+        }
+    }
+    tail.into()
+}
+
+pub fn run_command(program: &str, args: &[String], tail_lines: usize) -> std::io::Result<CommandOutcome> {
+    let command = std::iter::once(program)
+        .chain(args.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let started_at = Instant::now();
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child.stdout.take().expect("Stdout is piped");
+    let stderr = child.stderr.take().expect("Stderr is piped");
+    let stdout_thread = std::thread::spawn(move || tee_tail(stdout, std::io::stdout(), tail_lines));
+    let stderr_thread = std::thread::spawn(move || tee_tail(stderr, std::io::stderr(), tail_lines));
+
+    let status = child.wait()?;
+    let duration = started_at.elapsed();
+
+    Ok(CommandOutcome {
+        command,
+        exit_code: status.code(),
+        duration,
+        stdout_tail: stdout_thread.join().unwrap_or_default(),
+        stderr_tail: stderr_thread.join().unwrap_or_default(),
+    })
+}
+
+pub async fn send_notification(config: &ClientConfig, notification: &Notification) -> Result<()> {
+    let url = format!("{}/send/{}", config.server_url.trim_end_matches('/'), config.user_id);
+    let response = reqwest::Client::new()
+        .post(&url)
+        .header(reqwest::header::AUTHORIZATION, &config.api_key)
+        .json(notification)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await
+        .map_err(|err| Error::Internal(format!("Failed to reach {}: {}", url, err)))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(Error::Internal(format!("The server answered {}: {}", status, body)));
+    }
+    Ok(())
+}
@@ -1,48 +1,95 @@
+use serde::{Deserialize, Serialize};
+use std::env;
 use std::process::Command;
 
+use crate::error::{Error, Result};
+use crate::metrics::run_nsc;
+
+pub fn get_nsc_store_dir() -> String {
+    if let Ok(nsc_store_dir) = env::var("NSC_STORE_DIR") {
+        return nsc_store_dir;
+    }
+    let home = env::var("HOME").unwrap_or_default();
+    format!("{}/.local/share/nats/nsc/stores", home)
+}
+
 pub fn get_creds_path(creds_base_path: &str, operator_name: &str, account_name: &str, username: &str) -> String {
     format!("{}/{}/{}/{}.creds", creds_base_path, operator_name, account_name, username)
 }
 
-pub fn check_if_creds_exists(creds_base_path: &str, operator_name: &str, account_name: &str, username: &str) -> Result<String, String> {
+pub fn get_account_creds_dir(creds_base_path: &str, operator_name: &str, account_name: &str) -> String {
+    format!("{}/{}/{}", creds_base_path, operator_name, account_name)
+}
+
+pub fn check_if_creds_exists(creds_base_path: &str, operator_name: &str, account_name: &str, username: &str) -> Result<String> {
     let path_str = get_creds_path(creds_base_path, operator_name, account_name, username);
     let path = std::path::Path::new(&path_str);
     if !path.exists() {
-        return Err(format!("Credentials file not found: {}", path_str));
+        return Err(Error::NotFound(format!("Credentials file not found: {}", path_str)));
     }
     Ok(path_str)
 }
 
-pub fn create_nsc_account(account_name: &str) -> Result<String, String> {
+// Current operator of nsc, the following commands apply to its keystore
+pub fn select_nsc_operator(operator_name: &str) -> Result<bool> {
+
+    let output = run_nsc(Command::new("nsc")
+        .arg("env")
+        .arg("--operator")
+        .arg(operator_name))
+        .map_err(|e| Error::nsc(format!("Failed to select the operator: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::nsc(format!("Failed to select the operator {}: {}", operator_name, stderr)));
+    }
+    Ok(true)
+}
+
+// The commands changing an account JWT are signed with the operator signing key when set, its seed being in the keystore
+fn signing_key_args(signing_key: Option<&str>) -> Vec<String> {
+    match signing_key {
+        Some(signing_key) => vec!["--private-key".to_string(), signing_key.to_string()],
+        None => Vec::new(),
+    }
+}
+
+pub fn create_nsc_account(account_name: &str) -> Result<String> {
+    create_nsc_account_signed(account_name, None)
+}
+
+pub fn create_nsc_account_signed(account_name: &str, signing_key: Option<&str>) -> Result<String> {
 
     // Create the NATS account
-    let account_output = Command::new("nsc")
+    let account_output = run_nsc(Command::new("nsc")
         .arg("add")
         .arg("account")
         .arg("--name")
         .arg(account_name)
-        .output()
-        .map_err(|e| format!("Failed to create NATS account: {}", e))?;
+        .args(signing_key_args(signing_key)))
+        .map_err(|e| Error::nsc(format!("Failed to create NATS account: {}", e)))?;
 
     if !account_output.status.success() {
         let stderr = String::from_utf8_lossy(&account_output.stderr);
-        return Err(format!("Failed to create NATS account: {}", stderr));
+        return Err(Error::nsc(format!("Failed to create NATS account: {}", stderr)));
     }
 
-    // Retrieve account id
+    describe_nsc_account_id(account_name)
+}
+
+pub fn describe_nsc_account_id(account_name: &str) -> Result<String> {
 
-    let account_id_output = Command::new("nsc")
+    let account_id_output = run_nsc(Command::new("nsc")
         .arg("describe")
         .arg("account")
         .arg(account_name)
         .arg("--field")
-        .arg("sub")
-        .output()
-        .map_err(|e| format!("Failed to describe account: {}", e))?;
+        .arg("sub"))
+        .map_err(|e| Error::nsc(format!("Failed to describe account: {}", e)))?;
 
     if !account_id_output.status.success() {
         let stderr = String::from_utf8_lossy(&account_id_output.stderr);
-        return Err(format!("Failed to get account id: {}", stderr));
+        return Err(Error::nsc(format!("Failed to get account id: {}", stderr)));
     }
 
     let account_id = String::from_utf8_lossy(&account_id_output.stdout)
@@ -53,36 +100,38 @@ pub fn create_nsc_account(account_name: &str) -> Result<String, String> {
     Ok(account_id)
 }
 
-pub fn delete_nsc_account(account_name: &str) -> Result<bool, String> {
+pub fn nsc_account_exists(account_name: &str) -> bool {
+    describe_nsc_account_id(account_name).is_ok()
+}
+
+pub fn delete_nsc_account(account_name: &str) -> Result<bool> {
 
-    let output = Command::new("nsc")
+    let output = run_nsc(Command::new("nsc")
         .arg("delete")
         .arg("account")
-        .arg(account_name)
-        .output()
-        .map_err(|e| format!("Failed to delete account: {}", e))?;
+        .arg(account_name))
+        .map_err(|e| Error::nsc(format!("Failed to delete account: {}", e)))?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("Failed to delete account: {}", stderr));
+        return Err(Error::nsc(format!("Failed to delete account: {}", stderr)));
     }
     Ok(true)
 
 }
 
-pub fn get_account_jwt(account_name: &str) -> Result<String, String> {
+pub fn get_account_jwt(account_name: &str) -> Result<String> {
     // nsc describe account <account_name> -- raw
-    let output = Command::new("nsc")
+    let output = run_nsc(Command::new("nsc")
         .arg("describe")
         .arg("account")
         .arg(account_name)
-        .arg("--raw")
-        .output()
-        .map_err(|e| format!("Failed to get account jwt: {}", e))?;
+        .arg("--raw"))
+        .map_err(|e| Error::nsc(format!("Failed to get account jwt: {}", e)))?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("Failed to get account jwt: {}", stderr));
+        return Err(Error::nsc(format!("Failed to get account jwt: {}", stderr)));
     }
 
     let jwt = String::from_utf8_lossy(&output.stdout)
@@ -93,42 +142,248 @@ pub fn get_account_jwt(account_name: &str) -> Result<String, String> {
 
 }
 
-pub fn create_nsc_user(account_name: &str, username: &str) -> Result<bool, String> {
+// Limits signed in the account JWT, shared by all the users of the account
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct AccountLimits {
+    pub max_connections: Option<i64>,
+    pub max_subscriptions: Option<i64>,
+    // Bytes
+    pub max_payload: Option<i64>,
+    // JetStream disk storage, in the nsc format (ex: 512M, 1G), unlimited without it
+    pub jetstream_storage: Option<String>,
+}
+
+impl AccountLimits {
+    // Every limit is set, -1 being unlimited, so that a change of plan also removes the previous limits
+    pub fn edit_account_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, limit) in [("--conns", self.max_connections), ("--subscriptions", self.max_subscriptions), ("--payload", self.max_payload)] {
+            args.push(flag.to_string());
+            args.push(limit.unwrap_or(-1).to_string());
+        }
+        // 0 would disable JetStream, which notify-listen relies on for the notifications sent while offline
+        args.push("--js-disk-storage".to_string());
+        args.push(self.jetstream_storage.clone().unwrap_or_else(|| "-1".to_string()));
+        args
+    }
+}
+
+pub fn edit_nsc_account_limits(account_name: &str, limits: &AccountLimits, signing_key: Option<&str>) -> Result<bool> {
+
+    let output = run_nsc(Command::new("nsc")
+        .arg("edit")
+        .arg("account")
+        .arg("--name")
+        .arg(account_name)
+        .args(limits.edit_account_args())
+        .args(signing_key_args(signing_key)))
+        .map_err(|e| Error::nsc(format!("Failed to edit the account limits: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::nsc(format!("Failed to edit the account limits: {}", stderr)));
+    }
+    Ok(true)
+}
+
+pub fn create_nsc_user(account_name: &str, username: &str) -> Result<bool> {
+    create_nsc_user_with_permissions(account_name, username, &UserPermissions::default())
+}
+
+// Permissions and limits signed in the user JWT, everything is allowed when empty
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserPermissions {
+    pub allow_pub: Vec<String>,
+    pub allow_sub: Vec<String>,
+    pub deny_pub: Vec<String>,
+    pub deny_sub: Vec<String>,
+    // Validity of the JWT, in the nsc format (ex: 90d, 1y, 2026-12-31)
+    pub expiry: Option<String>,
+    pub max_subscriptions: Option<i64>,
+    pub max_payload: Option<i64>,
+}
+
+impl UserPermissions {
+    pub fn add_user_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, subjects) in [("--allow-pub", &self.allow_pub), ("--allow-sub", &self.allow_sub), ("--deny-pub", &self.deny_pub), ("--deny-sub", &self.deny_sub)] {
+            if !subjects.is_empty() {
+                args.push(flag.to_string());
+                args.push(subjects.join(","));
+            }
+        }
+        if let Some(expiry) = &self.expiry {
+            args.push("--expiry".to_string());
+            args.push(expiry.clone());
+        }
+        args
+    }
+
+    // The limits can only be set with `nsc edit user`
+    pub fn edit_user_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, limit) in [("--subs", self.max_subscriptions), ("--payload", self.max_payload)] {
+            if let Some(limit) = limit {
+                args.push(flag.to_string());
+                args.push(limit.to_string());
+            }
+        }
+        args
+    }
+}
+
+pub fn create_nsc_user_with_permissions(account_name: &str, username: &str, permissions: &UserPermissions) -> Result<bool> {
 
     // Create the user
-    let output = Command::new("nsc")
+    let output = run_nsc(Command::new("nsc")
         .arg("add")
         .arg("user")
         .arg("--name")
-        .arg(&username)
+        .arg(username)
         .arg("--account")
-        .arg(&account_name)
-        .output()
-        .map_err(|e| format!("Failed to create user: {}", e))?;
+        .arg(account_name)
+        .args(permissions.add_user_args()))
+        .map_err(|e| Error::nsc(format!("Failed to create user: {}", e)))?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("Failed to create user: {}", stderr));
+        return Err(Error::nsc(format!("Failed to create user: {}", stderr)));
+    }
+
+    let edit_args = permissions.edit_user_args();
+    if edit_args.is_empty() {
+        return Ok(true);
+    }
+    let output = run_nsc(Command::new("nsc")
+        .arg("edit")
+        .arg("user")
+        .arg("--name")
+        .arg(username)
+        .arg("--account")
+        .arg(account_name)
+        .args(edit_args))
+        .map_err(|e| Error::nsc(format!("Failed to set the limits of the user: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::nsc(format!("Failed to set the limits of the user: {}", stderr)));
     }
 
     Ok(true)
 }
 
-pub fn delete_nsc_user(account_name: &str, username: &str) -> Result<bool, String> {
+// Creds of the current user JWT (ex: after an edit of the user)
+pub fn generate_nsc_user_creds(account_name: &str, username: &str) -> Result<String> {
 
-    let output = Command::new("nsc")
+    let output = run_nsc(Command::new("nsc")
+        .arg("generate")
+        .arg("creds")
+        .arg("--name")
+        .arg(username)
+        .arg("--account")
+        .arg(account_name))
+        .map_err(|e| Error::nsc(format!("Failed to generate creds: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::nsc(format!("Failed to generate creds: {}", stderr)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub fn nsc_user_exists(account_name: &str, username: &str) -> bool {
+    run_nsc(Command::new("nsc")
+        .arg("describe")
+        .arg("user")
+        .arg("--name")
+        .arg(username)
+        .arg("--account")
+        .arg(account_name))
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+pub fn delete_nsc_user(account_name: &str, username: &str) -> Result<bool> {
+
+    let output = run_nsc(Command::new("nsc")
         .arg("delete")
         .arg("user")
         .arg("--name")
         .arg(username)
         .arg("--account")
-        .arg(&account_name)
-        .output()
-        .map_err(|e| format!("Failed to delete user: {}", e))?;
+        .arg(account_name))
+        .map_err(|e| Error::nsc(format!("Failed to delete user: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::nsc(format!("Failed to delete user: {}", stderr)));
+    }
+    Ok(true)
+}
+
+// Adds the user to the revocations of the account JWT, which then has to be pushed again
+pub fn revoke_nsc_user(account_name: &str, username: &str, signing_key: Option<&str>) -> Result<bool> {
+
+    let output = run_nsc(Command::new("nsc")
+        .arg("revocations")
+        .arg("add-user")
+        .arg("--name")
+        .arg(username)
+        .arg("--account")
+        .arg(account_name)
+        .args(signing_key_args(signing_key)))
+        .map_err(|e| Error::nsc(format!("Failed to revoke user: {}", e)))?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("Failed to delete user: {}", stderr));
+        return Err(Error::nsc(format!("Failed to revoke user: {}", stderr)));
     }
     Ok(true)
-}
\ No newline at end of file
+}
+
+pub fn push_nsc_account_removal(account_id: &str, nats_url: &str) -> Result<bool> {
+
+    let nats_url = if nats_url.contains("://") { nats_url.to_string() } else { format!("nats://{}", nats_url) };
+
+    let output = run_nsc(Command::new("nsc")
+        .arg("push")
+        .arg("--account-removal")
+        .arg(account_id)
+        .arg("--account-jwt-server-url")
+        .arg(&nats_url))
+        .map_err(|e| Error::nsc(format!("Failed to push account removal: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::nsc(format!("Failed to push account removal: {}", stderr)));
+    }
+    Ok(true)
+}
+
+fn list_subdirectories(path: &str) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn list_nsc_accounts(nsc_store_dir: &str, operator_name: &str) -> Result<Vec<String>> {
+    // Layout of the keystore: <store>/<operator>/accounts/<account>/<account>.jwt
+    let accounts_dir = format!("{}/{}/accounts", nsc_store_dir, operator_name);
+    list_subdirectories(&accounts_dir)
+        .map_err(|e| Error::nsc(format!("Failed to list nsc accounts in {}: {}", accounts_dir, e)))
+}
+
+pub fn list_creds_accounts(creds_base_path: &str, operator_name: &str) -> Result<Vec<String>> {
+    let operator_creds_dir = format!("{}/{}", creds_base_path, operator_name);
+    match list_subdirectories(&operator_creds_dir) {
+        Ok(accounts) => Ok(accounts),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Error::Internal(format!("Failed to list creds accounts in {}: {}", operator_creds_dir, e))),
+    }
+}
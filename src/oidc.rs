@@ -0,0 +1,149 @@
+use jsonwebtoken::{decode, decode_header, jwk::{Jwk, JwkSet}, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::{Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::error::{Error, Result, Service};
+
+use std::env;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Avoid fetching the JWKS again for every token signed with an unknown key
+const JWKS_REFRESH_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub enum JwksSource {
+    Url(String),
+    File(String),
+}
+
+impl JwksSource {
+    pub fn from_env() -> Option<JwksSource> {
+        if let Ok(jwks_url) = env::var("OIDC_JWKS_URL") {
+            return Some(JwksSource::Url(jwks_url));
+        }
+        if let Ok(jwks_file) = env::var("OIDC_JWKS_FILE") {
+            return Some(JwksSource::File(jwks_file));
+        }
+        None
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+// Validates the JWTs issued by an OIDC provider (ex: Supabase), the `sub` claim being the user uuid
+pub struct OidcValidator {
+    jwks_source: JwksSource,
+    issuer: Option<String>,
+    audience: Option<String>,
+    jwks: RwLock<JwkSet>,
+    last_refresh: Mutex<Instant>,
+}
+
+pub async fn load_jwks(jwks_source: &JwksSource) -> Result<JwkSet> {
+    match jwks_source {
+        JwksSource::Url(jwks_url) => reqwest::get(jwks_url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| Error::Upstream(Service::IdentityProvider, format!("Failed to fetch the JWKS: {}", err)))?
+            .json::<JwkSet>()
+            .await
+            .map_err(|err| Error::Upstream(Service::IdentityProvider, format!("Failed to parse the JWKS: {}", err))),
+        JwksSource::File(jwks_file) => {
+            let content = std::fs::read_to_string(jwks_file)
+                .map_err(|err| Error::Internal(format!("Failed to read the JWKS file: {}", err)))?;
+            serde_json::from_str(&content)
+                .map_err(|err| Error::Internal(format!("Failed to parse the JWKS: {}", err)))
+        }
+    }
+}
+
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+impl OidcValidator {
+    pub async fn new(jwks_source: JwksSource, issuer: Option<String>, audience: Option<String>) -> Result<OidcValidator> {
+        let jwks = load_jwks(&jwks_source).await?;
+        Ok(OidcValidator {
+            jwks_source,
+            issuer,
+            audience,
+            jwks: RwLock::new(jwks),
+            last_refresh: Mutex::new(Instant::now()),
+        })
+    }
+
+    // None when no JWKS is configured, the JWT authentication is then disabled
+    pub async fn from_env() -> Result<Option<OidcValidator>> {
+        let Some(jwks_source) = JwksSource::from_env() else {
+            return Ok(None);
+        };
+        let validator = OidcValidator::new(jwks_source, env::var("OIDC_ISSUER").ok(), env::var("OIDC_AUDIENCE").ok()).await?;
+        Ok(Some(validator))
+    }
+
+    async fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let jwks = self.jwks.read().await;
+        match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            // Without kid, only a single key set is unambiguous
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn refresh_jwks(&self) -> Result<()> {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.elapsed() < JWKS_REFRESH_MIN_INTERVAL {
+            return Ok(());
+        }
+        *last_refresh = Instant::now();
+        let jwks = load_jwks(&self.jwks_source).await?;
+        *self.jwks.write().await = jwks;
+        Ok(())
+    }
+
+    pub async fn validate_token(&self, token: &str) -> Result<Uuid> {
+        let header = decode_header(token)
+            .map_err(|err| Error::Unauthorized(format!("Invalid JWT header: {}", err)))?;
+
+        let jwk = match self.find_key(header.kid.as_deref()).await {
+            Some(jwk) => jwk,
+            None => {
+                // The provider may have rotated its keys
+                self.refresh_jwks().await?;
+                self.find_key(header.kid.as_deref())
+                    .await
+                    .ok_or(Error::Unauthorized("No key of the JWKS matches the JWT".to_string()))?
+            }
+        };
+
+        let algorithm = match &jwk.common.key_algorithm {
+            Some(key_algorithm) => Algorithm::from_str(&key_algorithm.to_string())
+                .map_err(|err| Error::Unauthorized(format!("Unsupported key algorithm: {}", err)))?,
+            None => header.alg,
+        };
+        let decoding_key = DecodingKey::from_jwk(&jwk)
+            .map_err(|err| Error::Upstream(Service::IdentityProvider, format!("Invalid key in the JWKS: {}", err)))?;
+
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let token_data = decode::<Claims>(token, &decoding_key, &validation)
+            .map_err(|err| Error::Unauthorized(format!("Invalid JWT: {}", err)))?;
+
+        Uuid::parse_str(&token_data.claims.sub)
+            .map_err(|err| Error::Unauthorized(format!("The sub claim is not an uuid: {}", err)))
+    }
+}
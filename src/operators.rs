@@ -0,0 +1,81 @@
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::postgres::get_nsc_user_operator;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct OperatorConfig {
+    // Public key of an operator signing key, signing the account JWTs instead of the operator identity key,
+    // so that it can be rotated without issuing the operator JWT again
+    pub signing_key: Option<String>,
+}
+
+// Operators of the nsc keystore the accounts are created under, read from the toml file of OPERATORS_FILE:
+//
+// default_operator = "production"
+//
+// [operators.production]
+// signing_key = "OC..."
+//
+// [operators.staging]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Operators {
+    pub default_operator: String,
+    pub operators: BTreeMap<String, OperatorConfig>,
+}
+
+impl Operators {
+    pub fn single(operator_name: &str, signing_key: Option<String>) -> Operators {
+        Operators {
+            default_operator: operator_name.to_string(),
+            operators: BTreeMap::from([(operator_name.to_string(), OperatorConfig { signing_key })]),
+        }
+    }
+
+    // Without a file, the only operator is the one of TEST_OPERATOR_NAME
+    pub fn from_env() -> Result<Operators> {
+        if let Ok(path) = env::var("OPERATORS_FILE") {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|err| Error::InvalidInput(format!("Failed to read the operators file {}: {}", path, err)))?;
+            return Operators::parse(&content);
+        }
+        let operator_name = env::var("TEST_OPERATOR_NAME")
+            .map_err(|_| Error::InvalidInput("TEST_OPERATOR_NAME or OPERATORS_FILE must be set".to_string()))?;
+        Ok(Operators::single(&operator_name, env::var("OPERATOR_SIGNING_KEY").ok()))
+    }
+
+    pub fn parse(content: &str) -> Result<Operators> {
+        let operators: Operators = toml::from_str(content)
+            .map_err(|err| Error::InvalidInput(format!("Invalid operators file: {}", err)))?;
+        if !operators.operators.contains_key(&operators.default_operator) {
+            return Err(Error::InvalidInput(format!("The default operator {} is not defined", operators.default_operator)));
+        }
+        Ok(operators)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.operators.keys().map(String::as_str)
+    }
+
+    pub fn signing_key(&self, operator_name: &str) -> Result<Option<&str>> {
+        self.operators.get(operator_name)
+            .map(|operator| operator.signing_key.as_deref())
+            .ok_or_else(|| Error::InvalidInput(format!("Unknown operator {}", operator_name)))
+    }
+}
+
+// The users created before the operators were configured belong to the default operator
+pub async fn get_user_operator(postgres_client: Arc<tokio_postgres::Client>, operators: &Operators, user_id: Uuid) -> Result<String> {
+    let operator_name = get_nsc_user_operator(postgres_client, user_id)
+        .await?
+        .unwrap_or_else(|| operators.default_operator.clone());
+    if !operators.operators.contains_key(&operator_name) {
+        return Err(Error::Internal(format!("The operator {} of the user is not configured", operator_name)));
+    }
+    Ok(operator_name)
+}
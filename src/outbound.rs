@@ -0,0 +1,309 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::accounts_lifecycle::get_admin_creds_if_not_exists;
+use crate::dedup::{count_duplicate, find_replaced, DedupConfig};
+use crate::delivery_targets::{deliver_to_target, find_delivery_target, get_delivery_targets, DeliveryConfig, DeliveryFailure};
+use crate::error::{Error, Result};
+use crate::metrics::record_delivery;
+use crate::nats_publisher::publish_message;
+use crate::notification::Notification;
+use crate::operators::{get_user_operator, Operators};
+use crate::postgres::{
+    claim_outbound_deliveries, delete_held_notifications, delete_outbound_delivery, insert_held_notification, insert_outbound_deliveries, list_dead_letters, move_outbound_delivery_to_dead_letters,
+    requeue_dead_letter, reschedule_outbound_delivery, verify_nsc_user_exists, OutboundDeliveryRecord,
+};
+use crate::quiet_hours::is_held;
+use crate::routing::{get_routing_rules, record_history, route};
+
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// Durable queue of the notifications sent with /send, in the database: /send only queues them,
+// and the workers deliver them to NATS and to the delivery targets, each channel being retried on its own
+
+pub const NATS_CHANNEL: &str = "nats";
+
+// The deliveries are claimed one at a time, each one only locked while it is delivered
+const MAX_DELIVERIES_PER_ROUND: usize = 10;
+// Whole attempt of a delivery, the SMTP and HTTP timeouts being per operation (ex: the DNS lookup of the webhooks is not covered)
+const DELIVERY_DEADLINE: Duration = Duration::from_secs(60);
+// Past it the delivery is claimed again (ex: the server stopped), the attempt being over long before
+const DELIVERY_LOCK: Duration = Duration::from_secs(120);
+const _: () = assert!(DELIVERY_LOCK.as_secs() > DELIVERY_DEADLINE.as_secs());
+// The connection and the flush of the blocking NATS client have their own timeouts, the task may still hang (ex: the creds file)
+const NATS_PUBLISH_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Routes the notification with the rules of the user and queues it for NATS and for each delivery target it is routed to
+// confirmed and accepting it, returns the number of deliveries (0 when only kept in the history, held for the digest of the quiet hours,
+// or dropped as a duplicate)
+pub async fn enqueue_notification(postgres_client: Arc<tokio_postgres::Client>, dedup: &DedupConfig, user_id: Uuid, notification: &Notification) -> Result<usize> {
+    enqueue_releasing(postgres_client, dedup, user_id, notification, &[]).await
+}
+
+// Same as enqueue_notification for a notification releasing held ones (ex: the digest of the quiet hours), deleted along with
+// the queuing of the deliveries so that they are never sent twice
+pub async fn enqueue_releasing(postgres_client: Arc<tokio_postgres::Client>, dedup: &DedupConfig, user_id: Uuid, notification: &Notification, released_held_ids: &[Uuid]) -> Result<usize> {
+    let notification = &Notification {
+        id: Some(Uuid::new_v4()),
+        replaces: find_replaced(Arc::clone(&postgres_client), user_id, notification, dedup.window).await?,
+        ..notification.clone()
+    };
+
+    let rules = get_routing_rules(Arc::clone(&postgres_client), user_id).await?;
+    let mut decision = route(&rules, notification, SystemTime::now());
+    let payload = serde_json::to_string(notification)
+        .map_err(|err| Error::Internal(format!("Failed to serialize the notification: {}", err)))?;
+
+    let targets = get_delivery_targets(Arc::clone(&postgres_client), user_id).await?;
+    let mut deliveries: Vec<(&str, Option<Uuid>)> = Vec::new();
+    if decision.nats {
+        deliveries.push((NATS_CHANNEL, None));
+    }
+    deliveries.extend(targets.iter()
+        .filter(|target| decision.target_ids.as_ref().is_none_or(|target_ids| target_ids.contains(&target.id)))
+        .filter(|target| target.confirmed && target.config.accepts(notification))
+        .map(|target| (target.config.kind(), Some(target.id))));
+    let held = !deliveries.is_empty() && is_held(Arc::clone(&postgres_client), user_id, notification).await?;
+    if held {
+        decision.action = "held";
+    }
+    // Nothing is held or queued before the history decides whether it is a duplicate
+    if record_history(Arc::clone(&postgres_client), user_id, notification, &payload, &decision, dedup.window).await?.is_none() {
+        if let Some(dedup_key) = &notification.dedup_key {
+            count_duplicate(Arc::clone(&postgres_client), user_id, dedup_key).await?;
+        }
+        delete_held_notifications(postgres_client, released_held_ids).await?;
+        return Ok(0);
+    }
+    if held {
+        insert_held_notification(Arc::clone(&postgres_client), user_id, &payload).await?;
+    }
+    if held || deliveries.is_empty() {
+        // Nothing is delivered, at worst it is recorded again in the history
+        delete_held_notifications(postgres_client, released_held_ids).await?;
+        tracing::debug!(%user_id, rule_id = ?decision.rule_id, action = decision.action, "Notification not queued");
+        return Ok(0);
+    }
+
+    let delay_ms = decision.delay.as_millis() as f64;
+    let delivery_ids = insert_outbound_deliveries(postgres_client, user_id, &deliveries, &payload, delay_ms, released_held_ids).await?;
+    tracing::debug!(%user_id, deliveries = delivery_ids.len(), rule_id = ?decision.rule_id, delay = ?decision.delay, "Notification queued");
+    Ok(delivery_ids.len())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboundQueueConfig {
+    pub workers: usize,
+    // Wait of an idle worker before looking for due deliveries again
+    pub poll_interval: Duration,
+}
+
+impl OutboundQueueConfig {
+    pub fn from_env() -> Result<OutboundQueueConfig> {
+        let workers = match env::var("OUTBOUND_WORKERS") {
+            Ok(workers) => workers.parse()
+                .map_err(|_| Error::InvalidInput("OUTBOUND_WORKERS must be a number".to_string()))?,
+            Err(_) => 2,
+        };
+        let poll_interval = match env::var("OUTBOUND_POLL_INTERVAL_MS") {
+            Ok(poll_interval) => poll_interval.parse()
+                .map(Duration::from_millis)
+                .map_err(|_| Error::InvalidInput("OUTBOUND_POLL_INTERVAL_MS must be a number".to_string()))?,
+            Err(_) => Duration::from_secs(1),
+        };
+        Ok(OutboundQueueConfig { workers, poll_interval })
+    }
+}
+
+// What is published on NATS: the notification as JSON, or only its message for the subscribers of the first releases
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NatsPayload {
+    #[default]
+    Json,
+    Raw,
+}
+
+impl NatsPayload {
+    pub fn from_env() -> Result<NatsPayload> {
+        match env::var("NATS_PAYLOAD").as_deref() {
+            Err(_) | Ok("json") => Ok(NatsPayload::Json),
+            Ok("raw") => Ok(NatsPayload::Raw),
+            Ok(payload) => Err(Error::InvalidInput(format!("NATS_PAYLOAD must be json or raw, not {}", payload))),
+        }
+    }
+
+    pub fn encode(&self, notification: &Notification) -> serde_json::Result<String> {
+        match self {
+            NatsPayload::Json => serde_json::to_string(notification),
+            NatsPayload::Raw => Ok(notification.message.clone()),
+        }
+    }
+}
+
+// What the workers need to deliver to every channel
+#[derive(Clone, Debug)]
+pub struct OutboundWorker {
+    pub postgres_client: Arc<tokio_postgres::Client>,
+    pub creds_base_path: String,
+    pub operators: Operators,
+    pub nats_url: String,
+    pub subject: String,
+    pub nats_payload: NatsPayload,
+    pub delivery: DeliveryConfig,
+}
+
+impl OutboundWorker {
+    // Each worker finishes its current delivery once the shutdown is signaled, then stops
+    pub fn spawn(self, config: &OutboundQueueConfig, shutdown: watch::Receiver<bool>) -> Vec<JoinHandle<()>> {
+        (0..config.workers).map(|_| {
+            let worker = self.clone();
+            let poll_interval = config.poll_interval;
+            let mut shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                while !*shutdown.borrow() {
+                    let idle = match worker.process_next_delivery().await {
+                        Ok(processed) => !processed,
+                        Err(err) => {
+                            tracing::error!("Failed to claim the due deliveries: {}", err);
+                            true
+                        }
+                    };
+                    if idle {
+                        tokio::select! {
+                            _ = tokio::time::sleep(poll_interval) => {}
+                            _ = shutdown.changed() => {}
+                        }
+                    }
+                }
+                tracing::info!("Outbound worker stopped");
+            })
+        }).collect()
+    }
+
+    // Returns the number of deliveries processed, 0 when none is due
+    pub async fn process_due_deliveries(&self) -> Result<usize> {
+        let mut processed = 0;
+        while processed < MAX_DELIVERIES_PER_ROUND && self.process_next_delivery().await? {
+            processed += 1;
+        }
+        Ok(processed)
+    }
+
+    // False when no delivery is due
+    pub async fn process_next_delivery(&self) -> Result<bool> {
+        let Some(delivery) = claim_outbound_deliveries(Arc::clone(&self.postgres_client), 1, DELIVERY_LOCK.as_millis() as f64).await?.pop() else {
+            return Ok(false);
+        };
+        self.process_delivery(&delivery).await;
+        Ok(true)
+    }
+
+    async fn process_delivery(&self, delivery: &OutboundDeliveryRecord) {
+        let (delivery_id, user_id, channel) = (delivery.id, delivery.user_id, delivery.channel.as_str());
+        let attempts = u32::try_from(delivery.attempts).unwrap_or_default();
+        let retry_policy = &self.delivery.retry_policy;
+
+        let attempt = tokio::time::timeout(DELIVERY_DEADLINE, self.deliver(delivery)).await
+            .unwrap_or_else(|_| Err(DeliveryFailure::retryable(format!("The delivery did not finish within {:?}", DELIVERY_DEADLINE))));
+        let (outcome, result) = match attempt {
+            Ok(_) => {
+                tracing::debug!(%delivery_id, %user_id, channel, attempts, "Notification delivered");
+                ("success", delete_outbound_delivery(Arc::clone(&self.postgres_client), delivery_id).await)
+            }
+            Err(failure) if failure.retryable && attempts < retry_policy.max_attempts => {
+                let backoff = retry_policy.backoff(attempts);
+                tracing::warn!(%delivery_id, %user_id, channel, attempts, ?backoff, "Delivery failed, retrying: {}", failure.message);
+                ("retry", reschedule_outbound_delivery(Arc::clone(&self.postgres_client), delivery_id, backoff.as_millis() as f64, &failure.message).await)
+            }
+            Err(failure) => {
+                tracing::error!(%delivery_id, %user_id, channel, attempts, "Delivery failed, moved to the dead letters: {}", failure.message);
+                ("dead_letter", move_outbound_delivery_to_dead_letters(Arc::clone(&self.postgres_client), delivery_id, &failure.message).await)
+            }
+        };
+        record_delivery(channel, outcome);
+        // The delivery stays locked, it is claimed again once the lock expires
+        if let Err(err) = result {
+            tracing::error!(%delivery_id, "Failed to update the queued delivery: {}", err);
+        }
+    }
+
+    async fn deliver(&self, delivery: &OutboundDeliveryRecord) -> std::result::Result<(), DeliveryFailure> {
+        let notification: Notification = serde_json::from_str(&delivery.notification)
+            .map_err(|err| DeliveryFailure::permanent(format!("Invalid queued notification: {}", err)))?;
+
+        let Some(target_id) = delivery.target_id else {
+            return self.publish(delivery.user_id, &notification).await;
+        };
+        match find_delivery_target(Arc::clone(&self.postgres_client), delivery.user_id, target_id).await? {
+            Some(target) => deliver_to_target(&self.delivery, &target, &notification).await,
+            None => {
+                tracing::info!(delivery_id = %delivery.id, %target_id, "The delivery target was deleted, dropping the delivery");
+                Ok(())
+            }
+        }
+    }
+
+    async fn publish(&self, user_id: Uuid, notification: &Notification) -> std::result::Result<(), DeliveryFailure> {
+        if !verify_nsc_user_exists(Arc::clone(&self.postgres_client), user_id).await? {
+            return Err(DeliveryFailure::permanent("User not found"));
+        }
+        let operator_name = get_user_operator(Arc::clone(&self.postgres_client), &self.operators, user_id).await?;
+        let creds_admin_path = get_admin_creds_if_not_exists(Arc::clone(&self.postgres_client), &self.creds_base_path, &operator_name, &user_id.to_string()).await?;
+        let payload = self.nats_payload.encode(notification)
+            .map_err(|err| DeliveryFailure::permanent(format!("Failed to serialize the notification: {}", err)))?;
+
+        // The NATS client is blocking
+        let (nats_url, subject) = (self.nats_url.clone(), self.subject.clone());
+        let publish = tokio::task::spawn_blocking(move || publish_message(&nats_url, &creds_admin_path, &subject, &payload));
+        tokio::time::timeout(NATS_PUBLISH_TIMEOUT, publish)
+            .await
+            .map_err(|_| DeliveryFailure::retryable(format!("NATS did not answer within {:?}", NATS_PUBLISH_TIMEOUT)))?
+            .map_err(|err| DeliveryFailure::retryable(format!("The publish task failed: {}", err)))??;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub channel: String,
+    pub target_id: Option<Uuid>,
+    pub notification: serde_json::Value,
+    pub attempts: i32,
+    pub last_error: String,
+    pub created_at: String,
+    pub failed_at: String,
+}
+
+pub async fn get_dead_letters(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<DeadLetter>> {
+    Ok(list_dead_letters(postgres_client, user_id)
+        .await?
+        .into_iter()
+        .map(|record| DeadLetter {
+            id: record.id,
+            user_id: record.user_id,
+            channel: record.channel,
+            target_id: record.target_id,
+            // Kept as is when it is not valid JSON, it is what made the delivery fail
+            notification: serde_json::from_str(&record.notification).unwrap_or(serde_json::Value::String(record.notification)),
+            attempts: record.attempts,
+            last_error: record.last_error,
+            created_at: record.created_at,
+            failed_at: record.failed_at,
+        })
+        .collect())
+}
+
+// Back in the queue with its attempts reset (ex: once the webhook is fixed)
+pub async fn retry_dead_letter(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, dead_letter_id: Uuid) -> Result<()> {
+    if !requeue_dead_letter(postgres_client, user_id, dead_letter_id).await? {
+        return Err(Error::NotFound(format!("Dead letter {} not found", dead_letter_id)));
+    }
+    tracing::info!(%user_id, %dead_letter_id, "Dead letter queued again");
+    Ok(())
+}
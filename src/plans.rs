@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::accounts_lifecycle::lock_provisioning;
+use crate::error::{Error, Result};
+use crate::nats_resolver::{push_account_jwt, AccountResolver};
+use crate::nsc_accounts_utils::{edit_nsc_account_limits, get_account_jwt, select_nsc_operator, AccountLimits};
+use crate::postgres::{get_nsc_account_id, update_account_jwt, update_nsc_user_plan};
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+// Plan of the accounts when no plans file is set, without any limit
+pub const DEFAULT_PLAN_NAME: &str = "default";
+
+// Limits of the accounts by plan, read from the toml file of ACCOUNT_PLANS_FILE:
+//
+// default_plan = "free"
+//
+// [plans.free]
+// max_connections = 5
+// jetstream_storage = "100M"
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Plans {
+    pub default_plan: String,
+    pub plans: HashMap<String, AccountLimits>,
+}
+
+impl Default for Plans {
+    fn default() -> Self {
+        Plans {
+            default_plan: DEFAULT_PLAN_NAME.to_string(),
+            plans: HashMap::from([(DEFAULT_PLAN_NAME.to_string(), AccountLimits::default())]),
+        }
+    }
+}
+
+impl Plans {
+    pub fn from_env() -> Result<Plans> {
+        match env::var("ACCOUNT_PLANS_FILE") {
+            Ok(path) => {
+                let content = std::fs::read_to_string(&path)
+                    .map_err(|err| Error::InvalidInput(format!("Failed to read the plans file {}: {}", path, err)))?;
+                Plans::parse(&content)
+            }
+            Err(_) => Ok(Plans::default()),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Plans> {
+        let plans: Plans = toml::from_str(content)
+            .map_err(|err| Error::InvalidInput(format!("Invalid plans file: {}", err)))?;
+        if !plans.plans.contains_key(&plans.default_plan) {
+            return Err(Error::InvalidInput(format!("The default plan {} is not defined", plans.default_plan)));
+        }
+        Ok(plans)
+    }
+
+    pub fn get(&self, plan: &str) -> Result<&AccountLimits> {
+        self.plans.get(plan)
+            .ok_or_else(|| Error::InvalidInput(format!("Unknown plan {}", plan)))
+    }
+
+    pub fn default_limits(&self) -> &AccountLimits {
+        &self.plans[&self.default_plan]
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanChange {
+    pub user_id: Uuid,
+    pub plan: String,
+    pub limits: AccountLimits,
+}
+
+// The account JWT is signed again with the limits of the plan, and pushed so that the nats-server applies them right away
+pub async fn change_account_plan(postgres_client: Arc<tokio_postgres::Client>, operator_name: &str, signing_key: Option<&str>, account_resolver: &AccountResolver, plans: &Plans, user_id: Uuid, plan: &str) -> Result<PlanChange> {
+    let limits = plans.get(plan)?;
+
+    let _provisioning_guard = lock_provisioning().await?;
+
+    let nsc_account_id = get_nsc_account_id(Arc::clone(&postgres_client), user_id)
+        .await?
+        .ok_or(Error::NotFound("User not found".to_string()))?;
+    let account_name = user_id.to_string();
+
+    select_nsc_operator(operator_name)?;
+    edit_nsc_account_limits(&account_name, limits, signing_key)?;
+    let account_jwt = get_account_jwt(&account_name)?;
+    push_account_jwt(account_resolver, &nsc_account_id, &account_jwt).await?;
+    update_account_jwt(Arc::clone(&postgres_client), user_id, &account_jwt).await?;
+    update_nsc_user_plan(postgres_client, user_id, plan).await?;
+
+    tracing::info!(%user_id, plan, "Plan of the account changed");
+    Ok(PlanChange { user_id, plan: plan.to_string(), limits: limits.clone() })
+}
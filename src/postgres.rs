@@ -1,9 +1,32 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
 use uuid::Uuid;
-use tokio_postgres::NoTls;
+use tokio_postgres::{NoTls, Row};
 
-// Schema of nats table
-// id / nsc_account_id / creds_admin / creds_user / account_jwt / created_at 
+use crate::error::{Error, Result};
+use crate::metrics::record_database_error;
+
+// Schema of nats table (see migrations/)
+// id / nsc_account_id / creds_admin / creds_user / account_jwt / created_at / broken_reason / plan / operator_name
+// Schema of nats_devices table
+// id / user_id / name / nsc_username / creds / created_at
+// Schema of delivery_targets table
+// id / user_id / config / created_at / confirmation_token_hash / confirmed_at
+// Schema of outbound_deliveries table
+// id / user_id / channel / target_id / notification / attempts / next_attempt_at / locked_until / last_error / created_at
+// Schema of dead_letters table
+// id / user_id / channel / target_id / notification / attempts / last_error / created_at / failed_at
+// Schema of routing_rules table
+// id / user_id / position / rule / created_at
+// Schema of notification_history table
+// id / user_id / notification / rule_id / action / created_at / dedup_key / group_key / duplicates
+// Schema of notification_dedup_keys table
+// user_id / dedup_key / history_id / expires_at
+// Schema of quiet_hours table
+// user_id / settings / updated_at
+// Schema of held_notifications table
+// id / user_id / notification / claimed_until / created_at
 
 pub async fn setup_postgres_client() -> tokio_postgres::Client {
     // TODO: See if need to pass connection string in environment here or not
@@ -17,93 +40,225 @@ pub async fn setup_postgres_client() -> tokio_postgres::Client {
 
     tokio::spawn(async move {
         if let Err(e) = connection.await {
-            eprintln!("Connection error: {}", e);
+            tracing::error!("Database connection error: {}", e);
         }
     });
     postgres_client
 
 }
 
-pub async fn verify_nsc_user_exists(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<bool, tokio_postgres::Error>{
+fn query_error(context: &str, err: tokio_postgres::Error) -> Error {
+    record_database_error(&err);
+    Error::database(format!("{}: {}", context, err))
+}
+
+// Query returning a row when the uuid ($1) is a known identity, Supabase auth table by default
+pub fn get_identity_query() -> Option<String> {
+    use std::env;
+
+    match env::var("IDENTITY_QUERY") {
+        // An empty query disables the check
+        Ok(identity_query) if identity_query.trim().is_empty() => None,
+        Ok(identity_query) => Some(identity_query),
+        Err(_) => Some("SELECT 1 FROM auth.users WHERE id = $1".to_string()),
+    }
+}
+
+pub async fn verify_identity_exists(postgres_client: Arc<tokio_postgres::Client>, identity_query: &str, user_id: Uuid) -> Result<bool>{
+    let rows = postgres_client.query(identity_query, &[&user_id])
+        .await?;
+    Ok(!rows.is_empty())
+}
+
+pub async fn verify_nsc_user_exists(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<bool>{
     // Check if user exists in the database
     let rows = postgres_client.query("SELECT * FROM nats WHERE id = $1", &[&user_id])
         .await?;
-    Ok(rows.len() > 0)
+    Ok(!rows.is_empty())
 }
 
 // creds_admin / creds_user / account_jwt / created_at 
+#[allow(clippy::too_many_arguments)]
 pub async fn insert_nsc_user(
     postgres_client: Arc<tokio_postgres::Client>,
     user_id: Uuid,
     nsc_account_id: &str,
     creds_admin: &str,
     creds_user: &str,
-    account_jwt: &str
-) -> Result<bool, tokio_postgres::Error>{
+    account_jwt: &str,
+    plan: &str,
+    operator_name: &str
+) -> Result<bool>{
     // let result = postgres_client.execute("INSERT INTO nats (id) VALUES ($1)", &[&user_id])
         // .await?;
-    let result = postgres_client.execute("INSERT INTO nats (id, nsc_account_id, creds_admin, creds_user, account_jwt) VALUES ($1, $2, $3, $4, $5)", &[&user_id, &nsc_account_id, &creds_admin, &creds_user, &account_jwt])
+    let result = postgres_client.execute("INSERT INTO nats (id, nsc_account_id, creds_admin, creds_user, account_jwt, plan, operator_name) VALUES ($1, $2, $3, $4, $5, $6, $7)", &[&user_id, &nsc_account_id, &creds_admin, &creds_user, &account_jwt, &plan, &operator_name])
         .await?;
     Ok(result > 0)
 }
 
-pub async fn delete_nsc_user_from_postgres(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<bool, tokio_postgres::Error>{
+pub async fn delete_nsc_user_from_postgres(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<bool>{
     let result = postgres_client.execute("DELETE FROM nats WHERE id = $1", &[&user_id])
         .await?;
     Ok(result > 0)
 }
 
-pub async fn get_creds_admin(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<String, String>{
-    let rows = postgres_client.query("SELECT creds_admin FROM nats WHERE id = $1", &[&user_id])
+pub async fn get_creds_admin(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<String>{
+    let row = postgres_client.query_opt("SELECT creds_admin FROM nats WHERE id = $1", &[&user_id])
         .await
-        .map_err(|err| format!("Failed to run query: {}", err));
+        .map_err(|err| query_error("Failed to run query", err))?;
 
-    let rows = rows.unwrap();
-    let row = rows.get(0);
-    if let None = row {
-        return Err("No rows found".to_string());
+    match row {
+        Some(row) => Ok(row.get(0)),
+        None => Err(Error::NotFound("No rows found".to_string())),
     }
-    let creds_admin: String = row.unwrap().get(0);
-    Ok(creds_admin)
 }
 
-pub async fn update_creds_admin(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, creds_admin: &str) -> Result<bool, tokio_postgres::Error>{
+pub async fn get_nsc_account_id(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Option<String>>{
+    let row = postgres_client.query_opt("SELECT nsc_account_id FROM nats WHERE id = $1", &[&user_id])
+        .await?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+pub async fn get_creds_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Option<String>>{
+    let row = postgres_client.query_opt("SELECT creds_user FROM nats WHERE id = $1", &[&user_id])
+        .await?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+pub async fn list_nsc_users(postgres_client: Arc<tokio_postgres::Client>) -> Result<Vec<Uuid>>{
+    let rows = postgres_client.query("SELECT id FROM nats ORDER BY id", &[])
+        .await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// The rows without an operator belong to the default operator
+pub async fn list_nsc_users_of_operator(postgres_client: Arc<tokio_postgres::Client>, operator_name: &str, default_operator: &str) -> Result<Vec<Uuid>>{
+    let rows = postgres_client.query("SELECT id FROM nats WHERE COALESCE(operator_name, $2) = $1 ORDER BY id", &[&operator_name, &default_operator])
+        .await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+pub async fn get_nsc_user_operator(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Option<String>>{
+    let row = postgres_client.query_opt("SELECT operator_name FROM nats WHERE id = $1", &[&user_id])
+        .await?;
+    Ok(row.and_then(|row| row.get(0)))
+}
+
+// Timestamps are formatted by postgres, in UTC
+macro_rules! created_at_utc {
+    () => { "to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"')" };
+}
+
+const NSC_USER_RECORD_QUERY: &str = concat!("SELECT nats.id, nats.nsc_account_id, ", created_at_utc!(), ", nats.broken_reason, nats.plan, nats.operator_name, \
+    (SELECT count(*) FROM api_keys WHERE api_keys.user_id = nats.id) \
+    FROM nats");
+
+#[derive(Debug, Serialize)]
+pub struct NscUserRecord {
+    pub user_id: Uuid,
+    pub nsc_account_id: String,
+    pub created_at: String,
+    pub broken_reason: Option<String>,
+    pub plan: Option<String>,
+    pub operator_name: Option<String>,
+    pub api_keys: i64,
+}
+
+fn nsc_user_record(row: &Row) -> NscUserRecord {
+    NscUserRecord {
+        user_id: row.get(0),
+        nsc_account_id: row.get(1),
+        created_at: row.get(2),
+        broken_reason: row.get(3),
+        plan: row.get(4),
+        operator_name: row.get(5),
+        api_keys: row.get(6),
+    }
+}
+
+pub async fn list_nsc_user_records(postgres_client: Arc<tokio_postgres::Client>) -> Result<Vec<NscUserRecord>>{
+    let rows = postgres_client.query(&format!("{} ORDER BY nats.created_at, nats.id", NSC_USER_RECORD_QUERY), &[])
+        .await?;
+    Ok(rows.iter().map(nsc_user_record).collect())
+}
+
+pub async fn get_nsc_user_record(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Option<NscUserRecord>>{
+    let row = postgres_client.query_opt(&format!("{} WHERE nats.id = $1", NSC_USER_RECORD_QUERY), &[&user_id])
+        .await?;
+    Ok(row.as_ref().map(nsc_user_record))
+}
+
+pub async fn mark_nsc_user_broken(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, broken_reason: &str) -> Result<bool>{
+    let result = postgres_client.execute("UPDATE nats SET broken_reason = $1 WHERE id = $2", &[&broken_reason, &user_id])
+        .await?;
+    Ok(result > 0)
+}
+
+pub async fn update_creds_admin(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, creds_admin: &str) -> Result<bool>{
     let result = postgres_client.execute("UPDATE nats SET creds_admin = $1 WHERE id = $2", &[&creds_admin, &user_id])
         .await?;
     Ok(result > 0)
 }
 
-pub async fn update_creds_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, creds_user: &str) -> Result<bool, tokio_postgres::Error>{
+pub async fn update_creds_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, creds_user: &str) -> Result<bool>{
     let result = postgres_client.execute("UPDATE nats SET creds_user = $1 WHERE id = $2", &[&creds_user, &user_id])
         .await?;
     Ok(result > 0)
 }
 
-pub async fn update_account_jwt(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, account_jwt: &str) -> Result<bool, tokio_postgres::Error>{
+pub async fn update_account_jwt(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, account_jwt: &str) -> Result<bool>{
     let result = postgres_client.execute("UPDATE nats SET account_jwt = $1 WHERE id = $2", &[&account_jwt, &user_id])
         .await?;
     Ok(result > 0)
 }
 
-pub async fn add_api_key(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, api_key_value: &str) -> Result<bool, String>{
+pub async fn update_nsc_user_plan(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, plan: &str) -> Result<bool>{
+    let result = postgres_client.execute("UPDATE nats SET plan = $1 WHERE id = $2", &[&plan, &user_id])
+        .await?;
+    Ok(result > 0)
+}
+
+pub async fn add_api_key(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, api_key_value: &str) -> Result<bool>{
     let api_key_hash = bcrypt::hash(api_key_value, bcrypt::DEFAULT_COST)
-        .map_err(|err| format!("Failed to hash the api key: {}", err))?;
+        .map_err(|err| Error::Internal(format!("Failed to hash the api key: {}", err)))?;
     let result = postgres_client.execute("INSERT INTO api_keys (user_id, api_key_hash) VALUES ($1, $2)", &[&user_id, &api_key_hash])
         .await
-        .map_err(|err| format!("Failed to insert api key: {}", err))?;
+        .map_err(|err| query_error("Failed to insert api key", err))?;
     Ok(result > 0)
 }
 
-pub async fn delete_api_key(postgres_client: Arc<tokio_postgres::Client>, api_key_id: Uuid) -> Result<bool, tokio_postgres::Error> {
+#[derive(Debug, Serialize)]
+pub struct ApiKeyRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub created_at: String,
+}
+
+// The hashes are not returned, a lost api key can only be revoked
+pub async fn list_api_keys(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<ApiKeyRecord>> {
+    let rows = postgres_client.query(concat!("SELECT id, user_id, ", created_at_utc!(), " FROM api_keys WHERE user_id = $1 ORDER BY created_at, id"), &[&user_id])
+        .await?;
+    Ok(rows.iter()
+        .map(|row| ApiKeyRecord { id: row.get(0), user_id: row.get(1), created_at: row.get(2) })
+        .collect())
+}
+
+pub async fn delete_api_key(postgres_client: Arc<tokio_postgres::Client>, api_key_id: Uuid) -> Result<bool> {
     let result = postgres_client.execute("DELETE FROM api_keys WHERE id = $1", &[&api_key_id])
         .await?;
     Ok(result > 0)
 }
 
-pub async fn verify_api_key(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, api_key_input: &str) -> Result<bool, String>{
+pub async fn delete_api_keys_of_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<u64> {
+    let result = postgres_client.execute("DELETE FROM api_keys WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(result)
+}
+
+pub async fn verify_api_key(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, api_key_input: &str) -> Result<bool>{
     let rows = postgres_client.query("SELECT api_key_hash FROM api_keys WHERE user_id = $1", &[&user_id])
         .await
-        .map_err(|err| format!("Failed to run query: {}", err))?;
+        .map_err(|err| query_error("Failed to run query", err))?;
 
     // Check if any of the row is equal to the api_key_hash
     let result = rows.iter().any(|row| {
@@ -111,4 +266,525 @@ pub async fn verify_api_key(postgres_client: Arc<tokio_postgres::Client>, user_i
         bcrypt::verify(api_key_input, api_key_hash).unwrap_or(false)
     });
     Ok(result)
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub nsc_username: String,
+    pub created_at: String,
+}
+
+const DEVICE_RECORD_QUERY: &str = concat!("SELECT id, user_id, name, nsc_username, ", created_at_utc!(), " FROM nats_devices");
+
+fn device_record(row: &Row) -> DeviceRecord {
+    DeviceRecord {
+        id: row.get(0),
+        user_id: row.get(1),
+        name: row.get(2),
+        nsc_username: row.get(3),
+        created_at: row.get(4),
+    }
+}
+
+pub async fn insert_device(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, name: &str, nsc_username: &str, creds: &str) -> Result<DeviceRecord> {
+    let row = postgres_client.query_one(
+        concat!("INSERT INTO nats_devices (user_id, name, nsc_username, creds) VALUES ($1, $2, $3, $4) \
+        RETURNING id, user_id, name, nsc_username, ", created_at_utc!()),
+        &[&user_id, &name, &nsc_username, &creds])
+        .await
+        .map_err(|err| query_error("Failed to insert device", err))?;
+    Ok(device_record(&row))
+}
+
+pub async fn list_devices(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<DeviceRecord>> {
+    let rows = postgres_client.query(&format!("{} WHERE user_id = $1 ORDER BY created_at, name", DEVICE_RECORD_QUERY), &[&user_id])
+        .await?;
+    Ok(rows.iter().map(device_record).collect())
+}
+
+pub async fn get_device(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, name: &str) -> Result<Option<DeviceRecord>> {
+    let row = postgres_client.query_opt(&format!("{} WHERE user_id = $1 AND name = $2", DEVICE_RECORD_QUERY), &[&user_id, &name])
+        .await?;
+    Ok(row.as_ref().map(device_record))
+}
+
+// (nsc_username, creds) of every device of the user
+pub async fn get_devices_creds(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<(String, String)>> {
+    let rows = postgres_client.query("SELECT nsc_username, creds FROM nats_devices WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+pub async fn delete_device(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, name: &str) -> Result<bool> {
+    let result = postgres_client.execute("DELETE FROM nats_devices WHERE user_id = $1 AND name = $2", &[&user_id, &name])
+        .await?;
+    Ok(result > 0)
+}
+
+pub async fn delete_devices_of_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<u64> {
+    let result = postgres_client.execute("DELETE FROM nats_devices WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(result)
+}
+
+// The config is the JSON of the target (ex: {"type": "webhook", "url": ...}), parsed by delivery_targets
+#[derive(Clone, Debug)]
+pub struct DeliveryTargetRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub config: String,
+    pub created_at: String,
+    pub confirmed: bool,
+}
+
+const DELIVERY_TARGET_RECORD_QUERY: &str = concat!("SELECT id, user_id, config, ", created_at_utc!(), ", confirmed_at IS NOT NULL FROM delivery_targets");
+
+fn delivery_target_record(row: &Row) -> DeliveryTargetRecord {
+    DeliveryTargetRecord {
+        id: row.get(0),
+        user_id: row.get(1),
+        config: row.get(2),
+        created_at: row.get(3),
+        confirmed: row.get(4),
+    }
+}
+
+// The tokens are random, a hash without salt is enough to keep them out of the database
+fn confirmation_token_hash(confirmation_token: &str) -> String {
+    hex::encode(Sha256::digest(confirmation_token.as_bytes()))
+}
+
+// Confirmed right away without a confirmation token
+pub async fn insert_delivery_target(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, config: &str, confirmation_token: Option<&str>) -> Result<DeliveryTargetRecord> {
+    let confirmation_token_hash = confirmation_token.map(confirmation_token_hash);
+    let row = postgres_client.query_one(
+        concat!("INSERT INTO delivery_targets (user_id, config, confirmation_token_hash, confirmed_at) \
+            VALUES ($1, $2, $3, CASE WHEN $3::text IS NULL THEN now() END) \
+            RETURNING id, user_id, config, ", created_at_utc!(), ", confirmed_at IS NOT NULL"),
+        &[&user_id, &config, &confirmation_token_hash])
+        .await?;
+    Ok(delivery_target_record(&row))
+}
+
+// False when the token does not match an unconfirmed target
+pub async fn confirm_delivery_target(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, target_id: Uuid, confirmation_token: &str) -> Result<bool> {
+    let result = postgres_client.execute(
+        "UPDATE delivery_targets SET confirmed_at = now(), confirmation_token_hash = NULL \
+            WHERE user_id = $1 AND id = $2 AND confirmed_at IS NULL AND confirmation_token_hash = $3",
+        &[&user_id, &target_id, &confirmation_token_hash(confirmation_token)])
+        .await?;
+    Ok(result > 0)
+}
+
+pub async fn list_delivery_targets(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<DeliveryTargetRecord>> {
+    let rows = postgres_client.query(&format!("{} WHERE user_id = $1 ORDER BY created_at, id", DELIVERY_TARGET_RECORD_QUERY), &[&user_id])
+        .await?;
+    Ok(rows.iter().map(delivery_target_record).collect())
+}
+
+pub async fn delete_delivery_target(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, target_id: Uuid) -> Result<bool> {
+    let result = postgres_client.execute("DELETE FROM delivery_targets WHERE user_id = $1 AND id = $2", &[&user_id, &target_id])
+        .await?;
+    Ok(result > 0)
+}
+
+pub async fn delete_delivery_targets_of_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<u64> {
+    let result = postgres_client.execute("DELETE FROM delivery_targets WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(result)
+}
+
+pub async fn get_delivery_target(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, target_id: Uuid) -> Result<Option<DeliveryTargetRecord>> {
+    let row = postgres_client.query_opt(&format!("{} WHERE user_id = $1 AND id = $2", DELIVERY_TARGET_RECORD_QUERY), &[&user_id, &target_id])
+        .await?;
+    Ok(row.as_ref().map(delivery_target_record))
+}
+
+#[derive(Clone, Debug)]
+pub struct OutboundDeliveryRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub channel: String,
+    pub target_id: Option<Uuid>,
+    pub notification: String,
+    pub attempts: i32,
+    pub created_at: String,
+}
+
+fn outbound_delivery_record(row: &Row) -> OutboundDeliveryRecord {
+    OutboundDeliveryRecord {
+        id: row.get(0),
+        user_id: row.get(1),
+        channel: row.get(2),
+        target_id: row.get(3),
+        notification: row.get(4),
+        attempts: row.get(5),
+        created_at: row.get(6),
+    }
+}
+
+// One row per (channel, target_id), all inserted by the same statement, due after delay_ms
+// The held notifications the queued one releases (ex: in a digest) are deleted by the same statement, so that they are
+// not released again whatever happens after
+pub async fn insert_outbound_deliveries(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, deliveries: &[(&str, Option<Uuid>)], notification: &str, delay_ms: f64, released_held_ids: &[Uuid]) -> Result<Vec<Uuid>> {
+    let channels: Vec<&str> = deliveries.iter().map(|(channel, _)| *channel).collect();
+    let target_ids: Vec<Option<Uuid>> = deliveries.iter().map(|(_, target_id)| *target_id).collect();
+    let rows = postgres_client.query(
+        "WITH released AS (DELETE FROM held_notifications WHERE id = ANY($6)) \
+        INSERT INTO outbound_deliveries (user_id, channel, target_id, notification, next_attempt_at) \
+        SELECT $1, channel, target_id, $4, now() + $5::float8 * interval '1 millisecond' \
+        FROM unnest($2::text[], $3::uuid[]) AS deliveries (channel, target_id) \
+        RETURNING id",
+        &[&user_id, &channels, &target_ids, &notification, &delay_ms, &released_held_ids])
+        .await
+        .map_err(|err| query_error("Failed to queue the deliveries", err))?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Locks the due deliveries for lock_ms and counts the attempt, SKIP LOCKED letting several workers (or servers) claim in parallel.
+// The due ones are selected in a CTE: in `WHERE id IN (...)` the subquery can be run again and lock more than limit deliveries
+pub async fn claim_outbound_deliveries(postgres_client: Arc<tokio_postgres::Client>, limit: i64, lock_ms: f64) -> Result<Vec<OutboundDeliveryRecord>> {
+    let rows = postgres_client.query(
+        concat!("WITH due AS (SELECT id FROM outbound_deliveries \
+            WHERE next_attempt_at <= now() AND (locked_until IS NULL OR locked_until < now()) \
+            ORDER BY next_attempt_at LIMIT $1 FOR UPDATE SKIP LOCKED) \
+        UPDATE outbound_deliveries SET attempts = attempts + 1, locked_until = now() + $2::float8 * interval '1 millisecond' \
+        FROM due WHERE outbound_deliveries.id = due.id \
+        RETURNING outbound_deliveries.id, user_id, channel, target_id, notification, attempts, ", created_at_utc!()),
+        &[&limit, &lock_ms])
+        .await?;
+    Ok(rows.iter().map(outbound_delivery_record).collect())
+}
+
+pub async fn delete_outbound_delivery(postgres_client: Arc<tokio_postgres::Client>, delivery_id: Uuid) -> Result<()> {
+    postgres_client.execute("DELETE FROM outbound_deliveries WHERE id = $1", &[&delivery_id])
+        .await?;
+    Ok(())
+}
+
+pub async fn reschedule_outbound_delivery(postgres_client: Arc<tokio_postgres::Client>, delivery_id: Uuid, delay_ms: f64, last_error: &str) -> Result<()> {
+    postgres_client.execute(
+        "UPDATE outbound_deliveries SET next_attempt_at = now() + $2::float8 * interval '1 millisecond', locked_until = NULL, last_error = $3 WHERE id = $1",
+        &[&delivery_id, &delay_ms, &last_error])
+        .await?;
+    Ok(())
+}
+
+pub async fn move_outbound_delivery_to_dead_letters(postgres_client: Arc<tokio_postgres::Client>, delivery_id: Uuid, last_error: &str) -> Result<()> {
+    postgres_client.execute(
+        "WITH moved AS (DELETE FROM outbound_deliveries WHERE id = $1 RETURNING *) \
+        INSERT INTO dead_letters (id, user_id, channel, target_id, notification, attempts, last_error, created_at) \
+        SELECT id, user_id, channel, target_id, notification, attempts, $2, created_at FROM moved",
+        &[&delivery_id, &last_error])
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct DeadLetterRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub channel: String,
+    pub target_id: Option<Uuid>,
+    pub notification: String,
+    pub attempts: i32,
+    pub last_error: String,
+    pub created_at: String,
+    pub failed_at: String,
+}
+
+pub async fn list_dead_letters(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<DeadLetterRecord>> {
+    let rows = postgres_client.query(
+        concat!("SELECT id, user_id, channel, target_id, notification, attempts, last_error, ", created_at_utc!(), ", \
+        to_char(failed_at AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"') \
+        FROM dead_letters WHERE user_id = $1 ORDER BY failed_at, id"),
+        &[&user_id])
+        .await?;
+    Ok(rows.iter()
+        .map(|row| DeadLetterRecord {
+            id: row.get(0),
+            user_id: row.get(1),
+            channel: row.get(2),
+            target_id: row.get(3),
+            notification: row.get(4),
+            attempts: row.get(5),
+            last_error: row.get(6),
+            created_at: row.get(7),
+            failed_at: row.get(8),
+        })
+        .collect())
+}
+
+// Back in the queue with its attempts reset, false when there is no such dead letter
+pub async fn requeue_dead_letter(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, dead_letter_id: Uuid) -> Result<bool> {
+    let result = postgres_client.execute(
+        "WITH moved AS (DELETE FROM dead_letters WHERE user_id = $1 AND id = $2 RETURNING *) \
+        INSERT INTO outbound_deliveries (id, user_id, channel, target_id, notification, created_at) \
+        SELECT id, user_id, channel, target_id, notification, created_at FROM moved",
+        &[&user_id, &dead_letter_id])
+        .await?;
+    Ok(result > 0)
+}
+
+// Queued deliveries and dead letters
+pub async fn delete_outbound_deliveries_of_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<u64> {
+    let queued = postgres_client.execute("DELETE FROM outbound_deliveries WHERE user_id = $1", &[&user_id])
+        .await?;
+    let dead_letters = postgres_client.execute("DELETE FROM dead_letters WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(queued + dead_letters)
+}
+
+pub struct RoutingRuleRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub position: i32,
+    pub rule: String,
+    pub created_at: String,
+}
+
+const ROUTING_RULE_RECORD_QUERY: &str = concat!("SELECT id, user_id, position, rule, ", created_at_utc!(), " FROM routing_rules");
+
+fn routing_rule_record(row: &Row) -> RoutingRuleRecord {
+    RoutingRuleRecord {
+        id: row.get(0),
+        user_id: row.get(1),
+        position: row.get(2),
+        rule: row.get(3),
+        created_at: row.get(4),
+    }
+}
+
+// In the order they are evaluated
+pub async fn list_routing_rules(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<RoutingRuleRecord>> {
+    let rows = postgres_client.query(&format!("{} WHERE user_id = $1 ORDER BY position, created_at, id", ROUTING_RULE_RECORD_QUERY), &[&user_id])
+        .await?;
+    Ok(rows.iter().map(routing_rule_record).collect())
+}
+
+pub async fn insert_routing_rule(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, position: i32, rule: &str) -> Result<RoutingRuleRecord> {
+    let row = postgres_client.query_one(
+        concat!("INSERT INTO routing_rules (user_id, position, rule) VALUES ($1, $2, $3) RETURNING id, user_id, position, rule, ", created_at_utc!()),
+        &[&user_id, &position, &rule])
+        .await?;
+    Ok(routing_rule_record(&row))
+}
+
+pub async fn update_routing_rule(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, rule_id: Uuid, position: i32, rule: &str) -> Result<Option<RoutingRuleRecord>> {
+    let row = postgres_client.query_opt(
+        concat!("UPDATE routing_rules SET position = $3, rule = $4 WHERE user_id = $1 AND id = $2 RETURNING id, user_id, position, rule, ", created_at_utc!()),
+        &[&user_id, &rule_id, &position, &rule])
+        .await?;
+    Ok(row.as_ref().map(routing_rule_record))
+}
+
+pub async fn delete_routing_rule(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, rule_id: Uuid) -> Result<bool> {
+    let result = postgres_client.execute("DELETE FROM routing_rules WHERE user_id = $1 AND id = $2", &[&user_id, &rule_id])
+        .await?;
+    Ok(result > 0)
+}
+
+pub struct HistoryRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub notification: String,
+    pub rule_id: Option<Uuid>,
+    pub action: String,
+    pub duplicates: i32,
+    pub created_at: String,
+}
+
+pub struct NewHistoryEntry<'a> {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub notification: &'a str,
+    pub rule_id: Option<Uuid>,
+    pub action: &'a str,
+    pub dedup_key: Option<&'a str>,
+    pub group_key: Option<&'a str>,
+}
+
+// With a dedup key, the entry is only inserted when it claims the key for window_ms: no unexpired entry holds it.
+// The claim and the entry are in a single statement, so that the concurrent claims of a key wait for each other
+// and the entry holding a key is always there to count its duplicates. Returns whether it was inserted
+pub async fn insert_history_entry(postgres_client: Arc<tokio_postgres::Client>, entry: &NewHistoryEntry<'_>, window_ms: f64) -> Result<bool> {
+    let result = postgres_client.execute(
+        "WITH claimed AS (INSERT INTO notification_dedup_keys (user_id, dedup_key, history_id, expires_at) \
+            SELECT $2, $6, $1, now() + $8::float8 * interval '1 millisecond' WHERE $6::text IS NOT NULL \
+            ON CONFLICT (user_id, dedup_key) DO UPDATE SET history_id = EXCLUDED.history_id, expires_at = EXCLUDED.expires_at \
+            WHERE notification_dedup_keys.expires_at <= now() \
+            RETURNING history_id) \
+        INSERT INTO notification_history (id, user_id, notification, rule_id, action, dedup_key, group_key) \
+        SELECT $1, $2, $3, $4, $5, $6, $7 WHERE $6::text IS NULL OR EXISTS (SELECT 1 FROM claimed)",
+        &[&entry.id, &entry.user_id, &entry.notification, &entry.rule_id, &entry.action, &entry.dedup_key, &entry.group_key, &window_ms])
+        .await?;
+    Ok(result > 0)
+}
+
+// The most recent first
+pub async fn list_history(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, limit: i64) -> Result<Vec<HistoryRecord>> {
+    let rows = postgres_client.query(
+        concat!("SELECT id, user_id, notification, rule_id, action, duplicates, ", created_at_utc!(), " FROM notification_history \
+        WHERE user_id = $1 ORDER BY created_at DESC, id LIMIT $2"),
+        &[&user_id, &limit])
+        .await?;
+    Ok(rows.iter()
+        .map(|row| HistoryRecord {
+            id: row.get(0),
+            user_id: row.get(1),
+            notification: row.get(2),
+            rule_id: row.get(3),
+            action: row.get(4),
+            duplicates: row.get(5),
+            created_at: row.get(6),
+        })
+        .collect())
+}
+
+// Counts a duplicate in the history of the notification holding the dedup key
+pub async fn increment_dedup_key_duplicates(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, dedup_key: &str) -> Result<Option<Uuid>> {
+    let row = postgres_client.query_opt(
+        "UPDATE notification_history SET duplicates = duplicates + 1 \
+        WHERE id = (SELECT history_id FROM notification_dedup_keys WHERE user_id = $1 AND dedup_key = $2) \
+        RETURNING id",
+        &[&user_id, &dedup_key])
+        .await?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+pub async fn delete_expired_dedup_keys(postgres_client: Arc<tokio_postgres::Client>) -> Result<u64> {
+    let result = postgres_client.execute("DELETE FROM notification_dedup_keys WHERE expires_at <= now()", &[])
+        .await?;
+    Ok(result)
+}
+
+// Last notification of the group of the user delivered in the last window_ms, including the ones a rule holds until the end
+// of its schedule: the devices never got the ones only kept in the history or held for the digest of the quiet hours
+pub async fn find_history_entry_by_group_key(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, group_key: &str, window_ms: f64) -> Result<Option<Uuid>> {
+    let row = postgres_client.query_opt(
+        "SELECT id FROM notification_history \
+        WHERE user_id = $1 AND group_key = $2 AND action IN ('deliver', 'hold') AND created_at > now() - $3::float8 * interval '1 millisecond' \
+        ORDER BY created_at DESC LIMIT 1",
+        &[&user_id, &group_key, &window_ms])
+        .await?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+// Entries older than max_age_ms, at most limit of them so that a large pruning does not hold its locks for long
+pub async fn delete_history_entries_older_than(postgres_client: Arc<tokio_postgres::Client>, max_age_ms: f64, limit: i64) -> Result<u64> {
+    let result = postgres_client.execute(
+        "WITH expired AS (SELECT id FROM notification_history WHERE created_at < now() - $1::float8 * interval '1 millisecond' LIMIT $2) \
+        DELETE FROM notification_history USING expired WHERE notification_history.id = expired.id",
+        &[&max_age_ms, &limit])
+        .await?;
+    Ok(result)
+}
+
+// Routing rules, history and dedup keys
+pub async fn delete_routing_of_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<u64> {
+    let rules = postgres_client.execute("DELETE FROM routing_rules WHERE user_id = $1", &[&user_id])
+        .await?;
+    let history = postgres_client.execute("DELETE FROM notification_history WHERE user_id = $1", &[&user_id])
+        .await?;
+    let dedup_keys = postgres_client.execute("DELETE FROM notification_dedup_keys WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(rules + history + dedup_keys)
+}
+
+pub async fn get_quiet_hours_settings(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Option<String>> {
+    let row = postgres_client.query_opt("SELECT settings FROM quiet_hours WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+pub async fn upsert_quiet_hours_settings(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, settings: &str) -> Result<()> {
+    postgres_client.execute(
+        "INSERT INTO quiet_hours (user_id, settings) VALUES ($1, $2) \
+        ON CONFLICT (user_id) DO UPDATE SET settings = EXCLUDED.settings, updated_at = now()",
+        &[&user_id, &settings])
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_quiet_hours_settings(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<bool> {
+    let result = postgres_client.execute("DELETE FROM quiet_hours WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(result > 0)
+}
+
+// Known by the timezone database of Postgres (ex: "Europe/Paris")
+pub async fn is_known_timezone(postgres_client: Arc<tokio_postgres::Client>, timezone: &str) -> Result<bool> {
+    let row = postgres_client.query_one("SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1)", &[&timezone])
+        .await?;
+    Ok(row.get(0))
+}
+
+// Day of the week (1 for monday to 7 for sunday) and seconds since midnight, now in the timezone
+pub async fn get_local_time(postgres_client: Arc<tokio_postgres::Client>, timezone: &str) -> Result<(i32, f64)> {
+    let row = postgres_client.query_one(
+        "SELECT extract(isodow FROM now() AT TIME ZONE $1)::int4, extract(epoch FROM (now() AT TIME ZONE $1)::time)::float8",
+        &[&timezone])
+        .await?;
+    Ok((row.get(0), row.get(1)))
+}
+
+pub struct HeldNotificationRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub notification: String,
+    pub created_at: String,
+}
+
+pub async fn insert_held_notification(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, notification: &str) -> Result<Uuid> {
+    let row = postgres_client.query_one(
+        "INSERT INTO held_notifications (user_id, notification) VALUES ($1, $2) RETURNING id",
+        &[&user_id, &notification])
+        .await?;
+    Ok(row.get(0))
+}
+
+// Users with held notifications that are not being sent
+pub async fn list_users_with_held_notifications(postgres_client: Arc<tokio_postgres::Client>) -> Result<Vec<Uuid>> {
+    let rows = postgres_client.query(
+        "SELECT DISTINCT user_id FROM held_notifications WHERE claimed_until IS NULL OR claimed_until < now()",
+        &[])
+        .await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// The held notifications of the user, oldest first, none when another server is sending them
+pub async fn claim_held_notifications(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, lock_ms: f64) -> Result<Vec<HeldNotificationRecord>> {
+    let rows = postgres_client.query(
+        concat!("WITH claimed AS (UPDATE held_notifications SET claimed_until = now() + $2::float8 * interval '1 millisecond' \
+            WHERE id IN (SELECT id FROM held_notifications \
+                WHERE user_id = $1 AND (claimed_until IS NULL OR claimed_until < now()) FOR UPDATE SKIP LOCKED) \
+            RETURNING id, user_id, notification, created_at) \
+        SELECT id, user_id, notification, ", created_at_utc!(), " FROM claimed ORDER BY created_at, id"),
+        &[&user_id, &lock_ms])
+        .await?;
+    Ok(rows.iter()
+        .map(|row| HeldNotificationRecord {
+            id: row.get(0),
+            user_id: row.get(1),
+            notification: row.get(2),
+            created_at: row.get(3),
+        })
+        .collect())
+}
+
+pub async fn delete_held_notifications(postgres_client: Arc<tokio_postgres::Client>, ids: &[Uuid]) -> Result<u64> {
+    Ok(postgres_client.execute("DELETE FROM held_notifications WHERE id = ANY($1)", &[&ids])
+        .await?)
+}
+
+// Quiet hours and held notifications
+pub async fn delete_quiet_hours_of_user(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<u64> {
+    let settings = postgres_client.execute("DELETE FROM quiet_hours WHERE user_id = $1", &[&user_id])
+        .await?;
+    let held = postgres_client.execute("DELETE FROM held_notifications WHERE user_id = $1", &[&user_id])
+        .await?;
+    Ok(settings + held)
+}
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::dedup::DedupConfig;
+use crate::error::{Error, Result};
+use crate::notification::{Notification, Priority};
+use crate::outbound::enqueue_releasing;
+use crate::postgres::{
+    claim_held_notifications, delete_held_notifications, delete_quiet_hours_settings, get_local_time, get_quiet_hours_settings,
+    is_known_timezone, list_users_with_held_notifications, upsert_quiet_hours_settings, verify_nsc_user_exists,
+};
+use crate::routing::{Schedule, Weekday};
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// Do-not-disturb window of a user, in its timezone: the non-urgent notifications sent during it are held in the database,
+// and the digest scheduler delivers them as a single notification once the window ends
+
+pub const DIGEST_CHANNEL: &str = "digest";
+
+const MAX_DIGEST_ITEMS: usize = 50;
+// Longer than queuing the digest, past it the held notifications are claimed again (ex: the server stopped)
+const DIGEST_LOCK: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct QuietHours {
+    // Name of the timezone database (ex: "Europe/Paris"), the one of Postgres
+    pub timezone: String,
+    #[serde(flatten)]
+    pub schedule: Schedule,
+}
+
+impl QuietHours {
+    // Time left in the window, None outside of it
+    pub async fn remaining(&self, postgres_client: Arc<tokio_postgres::Client>) -> Result<Option<Duration>> {
+        let (day, seconds_of_day) = get_local_time(postgres_client, &self.timezone).await?;
+        let weekday = u32::try_from(day).ok()
+            .and_then(Weekday::from_iso)
+            .ok_or_else(|| Error::Internal(format!("Invalid day of the week {}", day)))?;
+        Ok(self.schedule.remaining_at(weekday, seconds_of_day as u64))
+    }
+}
+
+pub async fn get_quiet_hours(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Option<QuietHours>> {
+    get_quiet_hours_settings(postgres_client, user_id)
+        .await?
+        .map(|settings| serde_json::from_str(&settings)
+            .map_err(|err| Error::Internal(format!("Invalid quiet hours for the user {}: {}", user_id, err))))
+        .transpose()
+}
+
+pub async fn set_quiet_hours(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, quiet_hours: &QuietHours) -> Result<()> {
+    quiet_hours.schedule.validate()?;
+    if !is_known_timezone(Arc::clone(&postgres_client), &quiet_hours.timezone).await? {
+        return Err(Error::InvalidInput(format!("Unknown timezone {}", quiet_hours.timezone)));
+    }
+    if !verify_nsc_user_exists(Arc::clone(&postgres_client), user_id).await? {
+        return Err(Error::NotFound("User not found".to_string()));
+    }
+
+    let settings = serde_json::to_string(quiet_hours)
+        .map_err(|err| Error::Internal(format!("Failed to serialize the quiet hours: {}", err)))?;
+    upsert_quiet_hours_settings(postgres_client, user_id, &settings).await
+}
+
+// The held notifications are delivered by the next run of the digest scheduler
+pub async fn remove_quiet_hours(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<()> {
+    if !delete_quiet_hours_settings(postgres_client, user_id).await? {
+        return Err(Error::NotFound("No quiet hours set".to_string()));
+    }
+    Ok(())
+}
+
+// Whether the notification is to be held: the user is in its quiet hours and it is not urgent
+pub async fn is_held(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, notification: &Notification) -> Result<bool> {
+    if notification.priority >= Priority::Urgent {
+        return Ok(false);
+    }
+    let Some(quiet_hours) = get_quiet_hours(Arc::clone(&postgres_client), user_id).await? else {
+        return Ok(false);
+    };
+    Ok(quiet_hours.remaining(postgres_client).await?.is_some())
+}
+
+// One line per notification, oldest first, with the highest of their priorities
+pub fn build_digest(notifications: &[Notification]) -> Notification {
+    let mut lines: Vec<String> = notifications.iter()
+        .take(MAX_DIGEST_ITEMS)
+        .map(|notification| {
+            let first_line = notification.message.lines().next().unwrap_or_default();
+            let mut line = match &notification.title {
+                Some(title) => format!("- {}: {}", title, first_line),
+                None => format!("- {}", first_line),
+            };
+            if let Some(host) = &notification.host {
+                line.push_str(&format!(" ({})", host));
+            }
+            line
+        })
+        .collect();
+    if notifications.len() > MAX_DIGEST_ITEMS {
+        lines.push(format!("... and {} more", notifications.len() - MAX_DIGEST_ITEMS));
+    }
+
+    let count = notifications.len();
+    Notification {
+        message: lines.join("\n"),
+        title: Some(format!("{} notification{} during quiet hours", count, if count == 1 { "" } else { "s" })),
+        channel: Some(DIGEST_CHANNEL.to_string()),
+        priority: notifications.iter().map(|notification| notification.priority).max().unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DigestSchedulerConfig {
+    // Wait between two looks for the quiet hours that ended
+    pub interval: Duration,
+}
+
+impl DigestSchedulerConfig {
+    pub fn from_env() -> Result<DigestSchedulerConfig> {
+        let interval = match env::var("DIGEST_INTERVAL_MS") {
+            Ok(interval) => interval.parse()
+                .map(Duration::from_millis)
+                .map_err(|_| Error::InvalidInput("DIGEST_INTERVAL_MS must be a number".to_string()))?,
+            Err(_) => Duration::from_secs(60),
+        };
+        Ok(DigestSchedulerConfig { interval })
+    }
+}
+
+// Queues the digests of the users whose quiet hours ended, through the routing and the outbound queue as any notification
+#[derive(Clone, Debug)]
+pub struct DigestScheduler {
+    pub postgres_client: Arc<tokio_postgres::Client>,
+    pub dedup: DedupConfig,
+}
+
+impl DigestScheduler {
+    // Finishes the digest being queued once the shutdown is signaled, then stops
+    pub fn spawn(self, config: &DigestSchedulerConfig, mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        let interval = config.interval;
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = shutdown.changed() => {}
+                }
+                if *shutdown.borrow() {
+                    break;
+                }
+                if let Err(err) = self.send_due_digests_until(&shutdown).await {
+                    tracing::error!("Failed to look for the due digests: {}", err);
+                }
+            }
+            tracing::info!("Digest scheduler stopped");
+        })
+    }
+
+    // Returns the number of digests queued
+    pub async fn send_due_digests(&self) -> Result<usize> {
+        let (_never_signaled, shutdown) = watch::channel(false);
+        self.send_due_digests_until(&shutdown).await
+    }
+
+    // Stops between two users once the shutdown is signaled, the others being sent after the restart
+    async fn send_due_digests_until(&self, shutdown: &watch::Receiver<bool>) -> Result<usize> {
+        let mut digests = 0;
+        for user_id in list_users_with_held_notifications(Arc::clone(&self.postgres_client)).await? {
+            if *shutdown.borrow() {
+                break;
+            }
+            match self.send_digest(user_id).await {
+                Ok(true) => digests += 1,
+                Ok(false) => {}
+                // The held notifications stay claimed, they are sent again once the claim expires
+                Err(err) => tracing::error!(%user_id, "Failed to send the digest: {}", err),
+            }
+        }
+        Ok(digests)
+    }
+
+    async fn send_digest(&self, user_id: Uuid) -> Result<bool> {
+        if let Some(quiet_hours) = get_quiet_hours(Arc::clone(&self.postgres_client), user_id).await? {
+            if quiet_hours.remaining(Arc::clone(&self.postgres_client)).await?.is_some() {
+                return Ok(false);
+            }
+        }
+        let held = claim_held_notifications(Arc::clone(&self.postgres_client), user_id, DIGEST_LOCK.as_millis() as f64).await?;
+        if held.is_empty() {
+            return Ok(false);
+        }
+
+        let notifications: Vec<Notification> = held.iter()
+            .filter_map(|record| match serde_json::from_str(&record.notification) {
+                Ok(notification) => Some(notification),
+                Err(err) => {
+                    tracing::warn!(%user_id, held_id = %record.id, "Invalid held notification, left out of the digest: {}", err);
+                    None
+                }
+            })
+            .collect();
+        // Released by the statement queuing the digest: if it fails, or the server stops before, the digest is sent once the claim expires
+        let ids: Vec<Uuid> = held.iter().map(|record| record.id).collect();
+        if notifications.is_empty() {
+            delete_held_notifications(Arc::clone(&self.postgres_client), &ids).await?;
+        } else {
+            enqueue_releasing(Arc::clone(&self.postgres_client), &self.dedup, user_id, &build_digest(&notifications), &ids).await?;
+        }
+        tracing::info!(%user_id, notifications = notifications.len(), "Digest of the quiet hours queued");
+        Ok(true)
+    }
+}
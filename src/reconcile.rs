@@ -0,0 +1,169 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::accounts_lifecycle::{lock_provisioning, rollback_user_creation};
+use crate::nats_resolver::AccountResolver;
+use crate::operators::Operators;
+use crate::nsc_accounts_utils::{check_if_creds_exists, describe_nsc_account_id, get_account_creds_dir, get_creds_path, list_creds_accounts, list_nsc_accounts, select_nsc_operator};
+use crate::postgres::{get_creds_admin, get_creds_user, get_devices_creds, list_nsc_users_of_operator, mark_nsc_user_broken};
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+// The state of an account lives in three places: the nsc keystore, the creds directory and the nats table.
+// Only the accounts named after a user uuid are considered, the other ones (ex: SYS) are not managed by this service.
+// Each operator is reconciled on its own, with the database rows of its users.
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftKind {
+    // In the database and the keystore, but the creds files are missing: downloaded again from the database
+    MissingCreds,
+    // In the keystore without a database row: the account is deleted
+    OrphanedNscAccount,
+    // Only a creds directory is left: the directory is deleted
+    OrphanedCreds,
+    // In the database but not in the keystore, the keys are lost: the row is marked as broken
+    MissingNscAccount,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum RepairStatus {
+    Repaired,
+    Failed(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountDrift {
+    pub account_name: String,
+    pub kind: DriftKind,
+    pub repair: Option<RepairStatus>,
+}
+
+pub struct ReconcileConfig<'a> {
+    pub creds_base_path: &'a str,
+    pub operator_name: &'a str,
+    // The database rows without an operator belong to it
+    pub default_operator: &'a str,
+    pub nsc_store_dir: &'a str,
+    pub account_resolver: &'a AccountResolver,
+}
+
+fn managed_accounts(account_names: Vec<String>) -> BTreeSet<Uuid> {
+    account_names.iter()
+        .filter_map(|account_name| Uuid::parse_str(account_name).ok())
+        .collect()
+}
+
+fn has_all_creds(config: &ReconcileConfig<'_>, account_name: &str) -> bool {
+    ["admin_01", "user_01"].iter()
+        .all(|username| check_if_creds_exists(config.creds_base_path, config.operator_name, account_name, username).is_ok())
+}
+
+pub async fn detect_drift(postgres_client: Arc<tokio_postgres::Client>, config: &ReconcileConfig<'_>) -> Result<Vec<AccountDrift>> {
+    let database_accounts: BTreeSet<Uuid> = list_nsc_users_of_operator(postgres_client, config.operator_name, config.default_operator)
+        .await?
+        .into_iter()
+        .collect();
+    let nsc_accounts = managed_accounts(list_nsc_accounts(config.nsc_store_dir, config.operator_name)?);
+    let creds_accounts = managed_accounts(list_creds_accounts(config.creds_base_path, config.operator_name)?);
+
+    let all_accounts: BTreeSet<&Uuid> = database_accounts.iter()
+        .chain(nsc_accounts.iter())
+        .chain(creds_accounts.iter())
+        .collect();
+
+    let mut drifts = Vec::new();
+    for account in all_accounts {
+        let account_name = account.to_string();
+        let kind = match (database_accounts.contains(account), nsc_accounts.contains(account)) {
+            (true, true) if !has_all_creds(config, &account_name) => DriftKind::MissingCreds,
+            (true, true) => continue,
+            (true, false) => DriftKind::MissingNscAccount,
+            (false, true) => DriftKind::OrphanedNscAccount,
+            (false, false) => DriftKind::OrphanedCreds,
+        };
+        drifts.push(AccountDrift { account_name, kind, repair: None });
+    }
+    Ok(drifts)
+}
+
+async fn restore_creds_from_database(postgres_client: Arc<tokio_postgres::Client>, config: &ReconcileConfig<'_>, account_name: &str, user_id: Uuid) -> Result<()> {
+    let creds_admin = get_creds_admin(Arc::clone(&postgres_client), user_id).await?;
+    let creds_user = get_creds_user(Arc::clone(&postgres_client), user_id)
+        .await?
+        .ok_or(Error::NotFound(format!("No creds_user found for {}", user_id)))?;
+
+    std::fs::create_dir_all(get_account_creds_dir(config.creds_base_path, config.operator_name, account_name))
+        .map_err(|err| Error::Internal(format!("Failed to create the creds directory: {}", err)))?;
+
+    let mut all_creds = vec![("admin_01".to_string(), creds_admin), ("user_01".to_string(), creds_user)];
+    all_creds.extend(get_devices_creds(postgres_client, user_id).await?);
+    for (username, creds) in all_creds {
+        std::fs::write(get_creds_path(config.creds_base_path, config.operator_name, account_name, &username), creds)
+            .map_err(|err| Error::Internal(format!("Failed to write the creds of {}: {}", username, err)))?;
+    }
+    Ok(())
+}
+
+async fn repair_drift(postgres_client: Arc<tokio_postgres::Client>, config: &ReconcileConfig<'_>, drift: &AccountDrift) -> Result<()> {
+    let user_id = Uuid::parse_str(&drift.account_name)
+        .map_err(|err| Error::InvalidInput(format!("Failed to parse account name as an uuid: {}", err)))?;
+
+    match drift.kind {
+        DriftKind::MissingCreds => restore_creds_from_database(postgres_client, config, &drift.account_name, user_id).await,
+        DriftKind::OrphanedNscAccount => {
+            let nsc_account_id = describe_nsc_account_id(&drift.account_name).ok();
+            rollback_user_creation(config.creds_base_path, config.operator_name, config.account_resolver, &drift.account_name, nsc_account_id.as_deref());
+            let _result = std::fs::remove_dir_all(get_account_creds_dir(config.creds_base_path, config.operator_name, &drift.account_name));
+            match list_nsc_accounts(config.nsc_store_dir, config.operator_name)?.contains(&drift.account_name) {
+                true => Err(Error::nsc("Nsc account is still in the keystore")),
+                false => Ok(()),
+            }
+        }
+        DriftKind::OrphanedCreds => std::fs::remove_dir_all(get_account_creds_dir(config.creds_base_path, config.operator_name, &drift.account_name))
+            .map_err(|err| Error::Internal(format!("Failed to remove the creds directory: {}", err))),
+        DriftKind::MissingNscAccount => mark_nsc_user_broken(postgres_client, user_id, "nsc account missing from the keystore")
+            .await
+            .map(|_| ()),
+    }
+}
+
+pub async fn reconcile(postgres_client: Arc<tokio_postgres::Client>, config: &ReconcileConfig<'_>, repair: bool) -> Result<Vec<AccountDrift>> {
+    // An account being provisioned would look like an orphan
+    let _provisioning_guard = lock_provisioning().await?;
+
+    if repair {
+        select_nsc_operator(config.operator_name)?;
+    }
+    let mut drifts = detect_drift(Arc::clone(&postgres_client), config).await?;
+
+    if repair {
+        for drift in drifts.iter_mut() {
+            let status = match repair_drift(Arc::clone(&postgres_client), config, drift).await {
+                Ok(_) => RepairStatus::Repaired,
+                Err(err) => RepairStatus::Failed(err.to_string()),
+            };
+            drift.repair = Some(status);
+        }
+    }
+    Ok(drifts)
+}
+
+// Every configured operator in turn, the drifts being reported together
+pub async fn reconcile_operators(postgres_client: Arc<tokio_postgres::Client>, operators: &Operators, creds_base_path: &str, nsc_store_dir: &str, account_resolver: &AccountResolver, repair: bool) -> Result<Vec<AccountDrift>> {
+    let mut drifts = Vec::new();
+    for operator_name in operators.names() {
+        let config = ReconcileConfig {
+            creds_base_path,
+            operator_name,
+            default_operator: &operators.default_operator,
+            nsc_store_dir,
+            account_resolver,
+        };
+        drifts.extend(reconcile(Arc::clone(&postgres_client), &config, repair).await?);
+    }
+    Ok(drifts)
+}
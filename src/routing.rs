@@ -0,0 +1,431 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::delivery_targets::get_delivery_targets;
+use crate::error::{Error, Result};
+use crate::notification::{Notification, Priority};
+use crate::postgres::{
+    delete_expired_dedup_keys, delete_history_entries_older_than, delete_routing_rule, insert_history_entry, insert_routing_rule, list_history, list_routing_rules, update_routing_rule,
+    verify_nsc_user_exists, NewHistoryEntry, RoutingRuleRecord,
+};
+
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// Rules of a user deciding where its notifications go, stored in the database and applied by /send:
+// the first matching rule (by position) applies, the notification being delivered everywhere without one
+
+const MAX_RULES_PER_USER: usize = 50;
+const HISTORY_LIMIT: i64 = 100;
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const HISTORY_PRUNE_BATCH_SIZE: i64 = 10_000;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun];
+
+    // Days since the epoch, which was a thursday
+    fn from_epoch_day(day: u64) -> Weekday {
+        Weekday::ALL[((day + 3) % 7) as usize]
+    }
+
+    // From 1 (monday) to 7 (sunday), as the isodow of Postgres
+    pub fn from_iso(day: u32) -> Option<Weekday> {
+        Weekday::ALL.get(day.checked_sub(1)? as usize).copied()
+    }
+
+    fn previous(self) -> Weekday {
+        Weekday::ALL[(self as usize + 6) % 7]
+    }
+}
+
+// Daily window in UTC, from start to end ("HH:MM"), ending the next day when end is before start (ex: 22:00 to 07:00)
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Schedule {
+    pub start: String,
+    pub end: String,
+    // Days the window starts, every day when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+}
+
+fn parse_time(time: &str) -> Result<u64> {
+    let invalid = || Error::InvalidInput(format!("Invalid time {}, expected HH:MM", time));
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let (hours, minutes): (u64, u64) = (hours.parse().map_err(|_| invalid())?, minutes.parse().map_err(|_| invalid())?);
+    if hours >= 24 || minutes >= 60 || time.len() != 5 {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<()> {
+        if parse_time(&self.start)? == parse_time(&self.end)? {
+            return Err(Error::InvalidInput("The start and the end of a schedule must differ".to_string()));
+        }
+        Ok(())
+    }
+
+    fn starts_on(&self, weekday: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&weekday)
+    }
+
+    // Time left until the end of the window, None outside of it
+    pub fn remaining(&self, now: SystemTime) -> Option<Duration> {
+        let seconds = now.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        self.remaining_at(Weekday::from_epoch_day(seconds / 86400), seconds % 86400)
+    }
+
+    // Same as remaining, at a time of the day (ex: in the timezone of a user)
+    pub fn remaining_at(&self, weekday: Weekday, seconds_of_day: u64) -> Option<Duration> {
+        let (start, end) = (parse_time(&self.start).ok()?, parse_time(&self.end).ok()?);
+        let minute = seconds_of_day / 60 % MINUTES_PER_DAY;
+
+        let minutes_left = if start < end {
+            (minute >= start && minute < end && self.starts_on(weekday)).then(|| end - minute)
+        } else if minute >= start {
+            self.starts_on(weekday).then(|| MINUTES_PER_DAY - minute + end)
+        } else {
+            // After midnight, in the window started the day before
+            (minute < end && self.starts_on(weekday.previous())).then(|| end - minute)
+        }?;
+        Some(Duration::from_secs(minutes_left * 60 - seconds_of_day % 60))
+    }
+}
+
+// Every condition set must match, a rule without conditions matches every notification
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RuleConditions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_priority: Option<Priority>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_priority: Option<Priority>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+}
+
+impl RuleConditions {
+    pub fn matches(&self, notification: &Notification, now: SystemTime) -> bool {
+        let equals = |condition: &Option<String>, value: &Option<String>| condition.is_none() || condition == value;
+        equals(&self.channel, &notification.channel)
+            && equals(&self.status, &notification.status)
+            && equals(&self.host, &notification.host)
+            && self.min_priority.is_none_or(|min_priority| notification.priority >= min_priority)
+            && self.max_priority.is_none_or(|max_priority| notification.priority <= max_priority)
+            && self.schedule.as_ref().is_none_or(|schedule| schedule.remaining(now).is_some())
+    }
+}
+
+fn default_nats() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    // Only kept in the history
+    History,
+    // To NATS (the devices) and to the given delivery targets, all of them by default,
+    // the targets still filtering on their own settings (ex: the minimum priority of the emails)
+    Deliver {
+        #[serde(default = "default_nats")]
+        nats: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target_ids: Option<Vec<Uuid>>,
+    },
+    // Delivered everywhere at the end of the schedule of the rule
+    Hold,
+}
+
+impl RuleAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RuleAction::History => "history",
+            RuleAction::Deliver { .. } => "deliver",
+            RuleAction::Hold => "hold",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub conditions: RuleConditions,
+    pub action: RuleAction,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RoutingRule {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub position: i32,
+    #[serde(flatten)]
+    pub rule: Rule,
+    pub created_at: String,
+}
+
+impl RoutingRule {
+    fn from_record(record: RoutingRuleRecord) -> Result<RoutingRule> {
+        let rule = serde_json::from_str(&record.rule)
+            .map_err(|err| Error::Internal(format!("Invalid routing rule {}: {}", record.id, err)))?;
+        Ok(RoutingRule { id: record.id, user_id: record.user_id, position: record.position, rule, created_at: record.created_at })
+    }
+}
+
+// Body of the creation and of the update of a rule, added after the other rules (or kept at its position) without a position
+#[derive(Clone, Debug, Deserialize)]
+pub struct RoutingRuleRequest {
+    #[serde(flatten)]
+    pub rule: Rule,
+    pub position: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutingDecision {
+    // None without a matching rule
+    pub rule_id: Option<Uuid>,
+    pub action: &'static str,
+    pub nats: bool,
+    // None for all the delivery targets
+    pub target_ids: Option<Vec<Uuid>>,
+    pub delay: Duration,
+}
+
+// Rules in the order they are evaluated
+pub fn route(rules: &[RoutingRule], notification: &Notification, now: SystemTime) -> RoutingDecision {
+    let mut decision = RoutingDecision { rule_id: None, action: "deliver", nats: true, target_ids: None, delay: Duration::ZERO };
+    let Some(rule) = rules.iter().find(|rule| rule.rule.conditions.matches(notification, now)) else {
+        return decision;
+    };
+    decision.rule_id = Some(rule.id);
+    decision.action = rule.rule.action.name();
+    match &rule.rule.action {
+        RuleAction::History => {
+            decision.nats = false;
+            decision.target_ids = Some(Vec::new());
+        }
+        RuleAction::Deliver { nats, target_ids } => {
+            decision.nats = *nats;
+            decision.target_ids = target_ids.clone();
+        }
+        RuleAction::Hold => {
+            decision.delay = rule.rule.conditions.schedule.as_ref()
+                .and_then(|schedule| schedule.remaining(now))
+                .unwrap_or_default();
+        }
+    }
+    decision
+}
+
+async fn validate_rule(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, rule: &Rule) -> Result<()> {
+    let conditions = &rule.conditions;
+    if let Some(schedule) = &conditions.schedule {
+        schedule.validate()?;
+    }
+    if let (Some(min_priority), Some(max_priority)) = (conditions.min_priority, conditions.max_priority) {
+        if min_priority > max_priority {
+            return Err(Error::InvalidInput("min_priority can not be above max_priority".to_string()));
+        }
+    }
+    match &rule.action {
+        RuleAction::Hold if conditions.schedule.is_none() => {
+            Err(Error::InvalidInput("A hold rule needs a schedule, the notifications being held until its end".to_string()))
+        }
+        RuleAction::Deliver { target_ids: Some(target_ids), .. } => {
+            let targets = get_delivery_targets(postgres_client, user_id).await?;
+            match target_ids.iter().find(|target_id| !targets.iter().any(|target| target.id == **target_id)) {
+                Some(target_id) => Err(Error::InvalidInput(format!("Delivery target {} not found", target_id))),
+                None => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+fn serialize_rule(rule: &Rule) -> Result<String> {
+    serde_json::to_string(rule)
+        .map_err(|err| Error::Internal(format!("Failed to serialize the routing rule: {}", err)))
+}
+
+pub async fn create_routing_rule(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, request: &RoutingRuleRequest) -> Result<RoutingRule> {
+    if !verify_nsc_user_exists(Arc::clone(&postgres_client), user_id).await? {
+        return Err(Error::NotFound("User not found".to_string()));
+    }
+    validate_rule(Arc::clone(&postgres_client), user_id, &request.rule).await?;
+    let rules = list_routing_rules(Arc::clone(&postgres_client), user_id).await?;
+    if rules.len() >= MAX_RULES_PER_USER {
+        return Err(Error::Conflict(format!("A user can not have more than {} routing rules", MAX_RULES_PER_USER)));
+    }
+
+    let position = request.position
+        .unwrap_or_else(|| rules.iter().map(|rule| rule.position + 1).max().unwrap_or_default());
+    RoutingRule::from_record(insert_routing_rule(postgres_client, user_id, position, &serialize_rule(&request.rule)?).await?)
+}
+
+pub async fn get_routing_rules(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<RoutingRule>> {
+    list_routing_rules(postgres_client, user_id)
+        .await?
+        .into_iter()
+        .map(RoutingRule::from_record)
+        .collect()
+}
+
+pub async fn replace_routing_rule(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, rule_id: Uuid, request: &RoutingRuleRequest) -> Result<RoutingRule> {
+    let not_found = || Error::NotFound(format!("Routing rule {} not found", rule_id));
+    let position = match request.position {
+        Some(position) => position,
+        None => list_routing_rules(Arc::clone(&postgres_client), user_id)
+            .await?
+            .iter()
+            .find(|rule| rule.id == rule_id)
+            .ok_or_else(not_found)?
+            .position,
+    };
+    validate_rule(Arc::clone(&postgres_client), user_id, &request.rule).await?;
+
+    let record = update_routing_rule(postgres_client, user_id, rule_id, position, &serialize_rule(&request.rule)?)
+        .await?
+        .ok_or_else(not_found)?;
+    RoutingRule::from_record(record)
+}
+
+pub async fn remove_routing_rule(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, rule_id: Uuid) -> Result<()> {
+    if !delete_routing_rule(postgres_client, user_id, rule_id).await? {
+        return Err(Error::NotFound(format!("Routing rule {} not found", rule_id)));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub notification: serde_json::Value,
+    pub rule_id: Option<Uuid>,
+    pub action: String,
+    // Dropped as duplicates of this notification
+    pub duplicates: i32,
+    pub created_at: String,
+}
+
+// Under the id of the notification when it is set, payload being its JSON. None when it is a duplicate:
+// a notification with its dedup key was recorded in the dedup window, see insert_history_entry
+pub async fn record_history(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid, notification: &Notification, payload: &str, decision: &RoutingDecision, dedup_window: Duration) -> Result<Option<Uuid>> {
+    let id = notification.id.unwrap_or_else(Uuid::new_v4);
+    let entry = NewHistoryEntry {
+        id,
+        user_id,
+        notification: payload,
+        rule_id: decision.rule_id,
+        action: decision.action,
+        dedup_key: notification.dedup_key.as_deref(),
+        group_key: notification.group.as_deref(),
+    };
+    let recorded = insert_history_entry(postgres_client, &entry, dedup_window.as_millis() as f64).await?;
+    Ok(recorded.then_some(id))
+}
+
+// The last notifications of the user, the most recent first
+pub async fn get_history(postgres_client: Arc<tokio_postgres::Client>, user_id: Uuid) -> Result<Vec<HistoryEntry>> {
+    Ok(list_history(postgres_client, user_id, HISTORY_LIMIT)
+        .await?
+        .into_iter()
+        .map(|record| HistoryEntry {
+            id: record.id,
+            user_id: record.user_id,
+            notification: serde_json::from_str(&record.notification).unwrap_or(serde_json::Value::String(record.notification)),
+            rule_id: record.rule_id,
+            action: record.action,
+            duplicates: record.duplicates,
+            created_at: record.created_at,
+        })
+        .collect())
+}
+
+// How long the history is kept, the older entries being pruned in the background
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryRetention {
+    pub max_age: Duration,
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        HistoryRetention { max_age: Duration::from_secs(30 * 24 * 60 * 60) }
+    }
+}
+
+impl HistoryRetention {
+    pub fn from_env() -> Result<HistoryRetention> {
+        match env::var("HISTORY_RETENTION_DAYS") {
+            Ok(days) => days.parse::<u64>()
+                .ok()
+                .filter(|days| *days > 0)
+                .map(|days| HistoryRetention { max_age: Duration::from_secs(days * 24 * 60 * 60) })
+                .ok_or(Error::InvalidInput("HISTORY_RETENTION_DAYS must be a positive number".to_string())),
+            Err(_) => Ok(HistoryRetention::default()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryPruner {
+    pub postgres_client: Arc<tokio_postgres::Client>,
+}
+
+impl HistoryPruner {
+    // Prunes at startup then every hour, until the shutdown, with the expired dedup keys
+    pub fn spawn(self, retention: &HistoryRetention, mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        let max_age = retention.max_age;
+        tokio::spawn(async move {
+            while !*shutdown.borrow() {
+                match self.prune(max_age).await {
+                    Ok(0) => {}
+                    Ok(pruned) => tracing::info!(pruned, "History pruned"),
+                    Err(err) => tracing::error!("Failed to prune the history: {}", err),
+                }
+                // Their windows ended, they would be claimed again anyway
+                if let Err(err) = delete_expired_dedup_keys(Arc::clone(&self.postgres_client)).await {
+                    tracing::error!("Failed to delete the expired dedup keys: {}", err);
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(HISTORY_PRUNE_INTERVAL) => {}
+                    _ = shutdown.changed() => {}
+                }
+            }
+        })
+    }
+
+    // Returns the number of entries removed
+    pub async fn prune(&self, max_age: Duration) -> Result<u64> {
+        let mut pruned = 0;
+        loop {
+            let deleted = delete_history_entries_older_than(Arc::clone(&self.postgres_client), max_age.as_millis() as f64, HISTORY_PRUNE_BATCH_SIZE).await?;
+            pruned += deleted;
+            if deleted < HISTORY_PRUNE_BATCH_SIZE as u64 {
+                return Ok(pruned);
+            }
+        }
+    }
+}
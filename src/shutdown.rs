@@ -0,0 +1,110 @@
+use axum_server::Handle;
+
+use crate::error::{Error, Result};
+
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+// Time given to the in-flight requests (ex: /send queuing a notification) and then to the workers to finish after SIGTERM
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShutdownConfig {
+    pub timeout: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { timeout: Duration::from_secs(30) }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn from_env() -> Result<ShutdownConfig> {
+        match env::var("SHUTDOWN_TIMEOUT_SECS") {
+            Ok(seconds) => seconds.parse()
+                .map(|seconds| ShutdownConfig { timeout: Duration::from_secs(seconds) })
+                .map_err(|_| Error::InvalidInput("SHUTDOWN_TIMEOUT_SECS must be a number".to_string())),
+            Err(_) => Ok(ShutdownConfig::default()),
+        }
+    }
+}
+
+// Resolves on SIGTERM (ex: rolling deploy) or Ctrl+C
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen to Ctrl+C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("Failed to listen to SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Ctrl+C received"),
+        _ = terminate => tracing::info!("SIGTERM received"),
+    }
+}
+
+// Stop accepting connections once the signal resolves, and let the in-flight requests finish until the deadline,
+// which the task returns so that the rest of the shutdown shares it
+pub fn spawn_graceful_shutdown<F>(handle: Handle, signal: F, timeout: Duration) -> JoinHandle<Instant>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        signal.await;
+        let deadline = Instant::now() + timeout;
+        tracing::info!(
+            connections = handle.connection_count(),
+            timeout_secs = timeout.as_secs(),
+            "Shutting down, waiting for the in-flight requests"
+        );
+        handle.graceful_shutdown(Some(timeout));
+        deadline
+    })
+}
+
+// The signal shared by the server and the background workers, true once it resolved
+pub fn broadcast_shutdown<F>(signal: F) -> watch::Receiver<bool>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        signal.await;
+        let _result = sender.send(true);
+    });
+    receiver
+}
+
+pub async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    // The sender is only dropped once the signal was sent
+    let _result = shutdown.wait_for(|stopped| *stopped).await;
+}
+
+// Let the workers finish their current task until the deadline, the unfinished ones being picked up again after the restart
+pub async fn join_workers(workers: Vec<JoinHandle<()>>, deadline: Instant) {
+    for worker in workers {
+        if tokio::time::timeout_at(deadline, worker).await.is_err() {
+            tracing::warn!("The background workers did not stop in time");
+            return;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use axum::{extract::Request, middleware::{AddExtension, Next}, response::{IntoResponse, Response}, Extension};
+use axum_server::{accept::Accept, tls_rustls::{RustlsAcceptor, RustlsConfig}};
+use rustls::{server::AllowAnyAnonymousOrAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+
+use crate::error::{Error, Result};
+
+use std::env;
+use std::future::Future;
+use std::io::BufReader;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+// How often the certificate files are checked for a renewal
+pub const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    // CA of the client certificates required on the admin routes
+    pub client_ca_path: Option<String>,
+}
+
+impl TlsConfig {
+    // None when TLS_CERT_PATH and TLS_KEY_PATH are not set, the server then serves plain HTTP
+    pub fn from_env() -> Result<Option<TlsConfig>> {
+        let client_ca_path = env::var("TLS_CLIENT_CA_PATH").ok();
+        match (env::var("TLS_CERT_PATH"), env::var("TLS_KEY_PATH")) {
+            (Ok(cert_path), Ok(key_path)) => Ok(Some(TlsConfig { cert_path, key_path, client_ca_path })),
+            (Err(_), Err(_)) if client_ca_path.is_none() => Ok(None),
+            _ => Err(Error::Internal("TLS_CERT_PATH and TLS_KEY_PATH must be set together, TLS_CLIENT_CA_PATH requires them".to_string())),
+        }
+    }
+
+    fn watched_paths(&self) -> Vec<&str> {
+        let mut paths = vec![self.cert_path.as_str(), self.key_path.as_str()];
+        paths.extend(self.client_ca_path.as_deref());
+        paths
+    }
+}
+
+fn read_certificates(path: &str) -> Result<Vec<Certificate>> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| Error::Internal(format!("Failed to open {}: {}", path, err)))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map(|certificate| certificate.map(|certificate| Certificate(certificate.to_vec())))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|err| Error::Internal(format!("Failed to parse the certificates of {}: {}", path, err)))?;
+    if certificates.is_empty() {
+        return Err(Error::Internal(format!("No certificate found in {}", path)));
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKey> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| Error::Internal(format!("Failed to open {}: {}", path, err)))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| Error::Internal(format!("Failed to parse the private key of {}: {}", path, err)))?
+        .map(|private_key| PrivateKey(private_key.secret_der().to_vec()))
+        .ok_or(Error::Internal(format!("No private key found in {}", path)))
+}
+
+pub fn load_server_config(tls_config: &TlsConfig) -> Result<ServerConfig> {
+    let certificates = read_certificates(&tls_config.cert_path)?;
+    let private_key = read_private_key(&tls_config.key_path)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &tls_config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca_path)? {
+                roots.add(&certificate)
+                    .map_err(|err| Error::Internal(format!("Invalid client CA in {}: {}", client_ca_path, err)))?;
+            }
+            // The public routes stay usable without a client certificate, see require_client_certificate
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_single_cert(certificates, private_key)
+        .map_err(|err| Error::Internal(format!("Invalid certificate or private key: {}", err)))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+fn modified_times(tls_config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    tls_config.watched_paths()
+        .into_iter()
+        .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+// Reload the certificates when one of the files changes (ex: renewed by certbot)
+pub fn spawn_tls_reload(rustls_config: RustlsConfig, tls_config: TlsConfig, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last_modified = modified_times(&tls_config);
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = modified_times(&tls_config);
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            // The previous certificates are kept if the new ones are invalid (ex: only the cert is written yet)
+            match load_server_config(&tls_config) {
+                Ok(server_config) => {
+                    rustls_config.reload_from_config(Arc::new(server_config));
+                    tracing::info!(cert_path = tls_config.cert_path, "TLS certificates reloaded");
+                }
+                Err(err) => tracing::error!("Failed to reload the TLS certificates: {}", err),
+            }
+        }
+    })
+}
+
+// Certificates sent by the client during the TLS handshake, already verified against the client CA
+#[derive(Clone, Debug, Default)]
+pub struct PeerCertificates(pub Vec<Certificate>);
+
+// Rustls acceptor exposing the client certificates to the handlers
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(rustls_config: RustlsConfig) -> ClientCertAcceptor {
+        ClientCertAcceptor { inner: RustlsAcceptor::new(rustls_config) }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, PeerCertificates>;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let peer_certificates = PeerCertificates(stream.get_ref().1.peer_certificates().unwrap_or_default().to_vec());
+            Ok((stream, Extension(peer_certificates).layer(service)))
+        })
+    }
+}
+
+// Guard of the admin routes when TLS_CLIENT_CA_PATH is set
+pub async fn require_client_certificate(request: Request, next: Next) -> Response {
+    let has_client_certificate = request.extensions()
+        .get::<PeerCertificates>()
+        .is_some_and(|peer_certificates| !peer_certificates.0.is_empty());
+
+    if !has_client_certificate {
+        return Error::Unauthorized("A client certificate is required".to_string()).into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,96 @@
+use crate::error::{Error, Result};
+use crate::nsc_accounts_utils::UserPermissions;
+
+use std::env;
+
+// JetStream API used by notify-listen to catch up from a stream and ack the notifications
+const JETSTREAM_CONSUMER_SUBJECTS: [&str; 5] = [
+    "$JS.API.STREAM.NAMES",
+    "$JS.API.CONSUMER.INFO.>",
+    "$JS.API.CONSUMER.CREATE.>",
+    "$JS.API.CONSUMER.DURABLE.CREATE.>",
+    "$JS.ACK.>",
+];
+
+// Permissions of the NATS users issued for each account:
+// the listeners (user_01 and the devices) only subscribe to the notifications, the publisher (admin_01) only publishes them
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserPolicy {
+    pub notification_subject: String,
+    // Only for the listeners, the creds of the publisher are used by the server and would need a renewal
+    pub expiry: Option<String>,
+    pub max_subscriptions: Option<i64>,
+    pub max_payload: Option<i64>,
+}
+
+impl Default for UserPolicy {
+    fn default() -> Self {
+        UserPolicy {
+            notification_subject: "topic01".to_string(),
+            expiry: None,
+            max_subscriptions: None,
+            max_payload: None,
+        }
+    }
+}
+
+fn parse_limit(name: &str) -> Result<Option<i64>> {
+    match env::var(name) {
+        Ok(value) => value.parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidInput(format!("{} must be a number", name))),
+        Err(_) => Ok(None),
+    }
+}
+
+// Same formats as `nsc add user --expiry`: a duration (ex: 90d, 12w, 6M, 1y) or a date (ex: 2026-12-31)
+pub fn is_valid_expiry(expiry: &str) -> bool {
+    let is_duration = expiry.strip_suffix(['s', 'm', 'h', 'd', 'w', 'M', 'y'])
+        .is_some_and(|amount| !amount.is_empty() && amount.chars().all(|character| character.is_ascii_digit()));
+    let is_date = expiry.len() == 10
+        && expiry.char_indices().all(|(index, character)| if index == 4 || index == 7 { character == '-' } else { character.is_ascii_digit() });
+    is_duration || is_date
+}
+
+impl UserPolicy {
+    pub fn from_env() -> Result<UserPolicy> {
+        let expiry = env::var("USER_JWT_EXPIRY").ok();
+        if let Some(expiry) = &expiry {
+            if !is_valid_expiry(expiry) {
+                return Err(Error::InvalidInput("USER_JWT_EXPIRY must be a duration (ex: 90d, 1y) or a date (ex: 2026-12-31)".to_string()));
+            }
+        }
+        Ok(UserPolicy {
+            notification_subject: env::var("NOTIFICATION_SUBJECT").unwrap_or_else(|_| "topic01".to_string()),
+            expiry,
+            max_subscriptions: parse_limit("USER_MAX_SUBSCRIPTIONS")?,
+            max_payload: parse_limit("USER_MAX_PAYLOAD")?,
+        })
+    }
+
+    pub fn listener_permissions(&self) -> UserPermissions {
+        UserPermissions {
+            // Pushed JetStream messages are delivered on an inbox
+            allow_sub: vec![self.notification_subject.clone(), "_INBOX.>".to_string()],
+            allow_pub: JETSTREAM_CONSUMER_SUBJECTS.iter().map(|subject| subject.to_string()).collect(),
+            deny_pub: Vec::new(),
+            deny_sub: Vec::new(),
+            expiry: self.expiry.clone(),
+            max_subscriptions: self.max_subscriptions,
+            max_payload: self.max_payload,
+        }
+    }
+
+    pub fn publisher_permissions(&self) -> UserPermissions {
+        UserPermissions {
+            allow_pub: vec![self.notification_subject.clone()],
+            allow_sub: Vec::new(),
+            deny_pub: Vec::new(),
+            // Without an allow list, every subject would be allowed
+            deny_sub: vec![">".to_string()],
+            expiry: None,
+            max_subscriptions: None,
+            max_payload: self.max_payload,
+        }
+    }
+}
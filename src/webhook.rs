@@ -0,0 +1,204 @@
+use hmac::{Hmac, Mac};
+use reqwest::{dns::{Addrs, Name, Resolve, Resolving}, header::{HeaderName, HeaderValue, CONTENT_TYPE}, redirect, Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::delivery_targets::DeliveryFailure;
+use crate::error::{Error, Result};
+use crate::notification::Notification;
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+// HMAC-SHA256 of the body with the secret of the target, as `sha256=<hex>`
+pub const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+const REDACTED: &str = "********";
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+// Notification delivered with an HTTP request (ex: to a chat or an incident tool)
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct WebhookTarget {
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    // See Notification::render_template, the notification as JSON without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_template: Option<String>,
+}
+
+// The values are escaped as JSON strings, the templates being JSON bodies most of the time
+fn escape_json(value: &str) -> String {
+    let quoted = serde_json::to_string(value).expect("A string is serializable");
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+impl WebhookTarget {
+    pub fn validate(&self) -> Result<()> {
+        let url = Url::parse(&self.url)
+            .map_err(|err| Error::InvalidInput(format!("Invalid webhook url: {}", err)))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Error::InvalidInput("The webhook url should be http or https".to_string()));
+        }
+        if !matches!(self.method.as_str(), "POST" | "PUT" | "PATCH") {
+            return Err(Error::InvalidInput("The webhook method should be POST, PUT or PATCH".to_string()));
+        }
+        for (name, value) in &self.headers {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::InvalidInput(format!("Invalid header name {}", name)))?;
+            HeaderValue::from_str(value)
+                .map_err(|_| Error::InvalidInput(format!("Invalid value for the header {}", name)))?;
+        }
+        Ok(())
+    }
+
+    // The secret is never returned once stored
+    pub fn redacted(&self) -> WebhookTarget {
+        WebhookTarget {
+            secret: self.secret.as_ref().map(|_| REDACTED.to_string()),
+            ..self.clone()
+        }
+    }
+
+    pub fn render_body(&self, user_id: Uuid, notification: &Notification) -> String {
+        match &self.body_template {
+            Some(template) => notification.render_template(template, user_id, escape_json),
+            None => serde_json::to_string(notification).expect("A notification is serializable"),
+        }
+    }
+}
+
+pub fn sign_body(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// The addresses a webhook can not reach: the server itself and the networks around it (ex: the metadata service of the cloud at 169.254.169.254)
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(ip);
+            }
+            // NAT64 (64:ff9b::/96) and 6to4 (2002::/16) addresses embed an IPv4 address
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public_ipv4(Ipv4Addr::from(((segments[6] as u32) << 16) | segments[7] as u32));
+            }
+            if segments[0] == 0x2002 {
+                return is_public_ipv4(Ipv4Addr::from(((segments[1] as u32) << 16) | segments[2] as u32));
+            }
+            // Unique local (fc00::/7) and link-local (fe80::/10) addresses
+            !(ip.is_loopback() || ip.is_unspecified() || (segments[0] & 0xfe00) == 0xfc00 || (segments[0] & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast()
+        // "This network" (0.0.0.0/8) and the shared address space of the carrier-grade NATs (100.64.0.0/10)
+        || octets[0] == 0
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        // IETF protocol assignments (192.0.0.0/24), benchmarking (198.18.0.0/15) and reserved (240.0.0.0/4) networks
+        || octets[..3] == [192, 0, 0]
+        || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+        || octets[0] >= 240)
+}
+
+async fn resolve_public(host: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?
+        .filter(|addr| is_public_address(addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{} has no public address", host)));
+    }
+    Ok(addrs)
+}
+
+// Only connects to the public addresses of a host, resolved again for each connection so that a DNS answer
+// changed after the check of send_webhook (ex: DNS rebinding) can not reach a private address either
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = resolve_public(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// The redirects are not followed, they could lead to a private address
+pub fn webhook_http_client_builder(allow_private_networks: bool) -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder().redirect(redirect::Policy::none());
+    if allow_private_networks {
+        builder
+    } else {
+        // A proxy of the environment (ex: HTTPS_PROXY) would resolve the host itself, without the check of the resolver
+        builder.dns_resolver(Arc::new(PublicResolver)).no_proxy()
+    }
+}
+
+// The addresses in the url are not resolved by the client, and a host only resolving to private addresses is a permanent failure
+async fn check_public_host(url: &str) -> std::result::Result<(), DeliveryFailure> {
+    let url = Url::parse(url).map_err(|err| DeliveryFailure::permanent(format!("Invalid webhook url: {}", err)))?;
+    let host = url.host_str().ok_or_else(|| DeliveryFailure::permanent("The webhook url has no host"))?;
+    // The IPv6 addresses are in brackets
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return check_public_ip(ip);
+    }
+    match resolve_public(host).await {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => Err(DeliveryFailure::permanent(format!("The webhook is not allowed: {}", err))),
+        Err(err) => Err(DeliveryFailure::retryable(format!("Failed to resolve {}: {}", host, err))),
+    }
+}
+
+fn check_public_ip(ip: IpAddr) -> std::result::Result<(), DeliveryFailure> {
+    if is_public_address(ip) {
+        Ok(())
+    } else {
+        Err(DeliveryFailure::permanent(format!("The webhook is not allowed: {} is not a public address", ip)))
+    }
+}
+
+// Server errors, rate limits and network errors are retried, the other client errors are not
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT
+}
+
+// One attempt, the retries are done by the outbound queue
+pub async fn send_webhook(http_client: &reqwest::Client, allow_private_networks: bool, target: &WebhookTarget, user_id: Uuid, notification: &Notification) -> std::result::Result<(), DeliveryFailure> {
+    if !allow_private_networks {
+        check_public_host(&target.url).await?;
+    }
+    let method = Method::from_bytes(target.method.as_bytes()).map_err(|err| DeliveryFailure::permanent(err.to_string()))?;
+    let body = target.render_body(user_id, notification);
+    let mut request = http_client.request(method, &target.url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.clone());
+    for (name, value) in &target.headers {
+        request = request.header(name, value);
+    }
+    if let Some(secret) = &target.secret {
+        request = request.header(SIGNATURE_HEADER, sign_body(secret, &body));
+    }
+
+    match request.send().await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(DeliveryFailure { retryable: is_retryable(response.status()), message: format!("The webhook answered {}", response.status()) }),
+        Err(err) => Err(DeliveryFailure::retryable(format!("Failed to call the webhook: {}", err))),
+    }
+}
@@ -1,11 +1,14 @@
 use command_notifier::accounts_lifecycle::{
     get_admin_creds_if_not_exists,
     create_and_insert_user,
-    delete_user_everywhere
+    delete_user_everywhere,
+    DeletionStatus,
+    UserProvisioning
 };
 
-use command_notifier::nsc_accounts_utils::{delete_nsc_account, delete_nsc_user, get_creds_path};
-use command_notifier::postgres::{delete_nsc_user_from_postgres, setup_postgres_client, update_creds_admin};
+use command_notifier::nats_resolver::AccountResolver;
+use command_notifier::nsc_accounts_utils::{delete_nsc_account, delete_nsc_user, get_account_creds_dir, get_creds_path, nsc_account_exists};
+use command_notifier::postgres::{add_api_key, delete_nsc_user_from_postgres, get_identity_query, setup_postgres_client, update_creds_admin};
 use uuid::Uuid;
 
 use std::env;
@@ -13,7 +16,7 @@ use std::sync::Arc;
 
 mod common;
 
-use common::utils::{insert_dummy_nsc_user, cleanup_postgres_user, get_user_uuid, check_if_jwt};
+use common::utils::{insert_dummy_nsc_user, cleanup_postgres_user, get_user_uuid, check_if_jwt, provisioning_config};
 
 #[cfg(test)]
 fn delete_creds_files_of_full_user(creds_base_path: &str, operator_name: &str, account_name: &str) {
@@ -41,7 +44,7 @@ async fn test_create_and_insert_user() {
 
     let result = tokio::spawn(async move {
 
-        let result = create_and_insert_user(Arc::clone(&postgres_client), &creds_base_path, &operator_name, username).await;
+        let result = create_and_insert_user(Arc::clone(&postgres_client), &provisioning_config(&creds_base_path, &operator_name), username).await;
         
         assert!(result.is_ok(), "Failed to create and insert user: {:?}", result);
         
@@ -70,6 +73,108 @@ async fn test_create_and_insert_user() {
     
 }
 
+#[tokio::test]
+async fn test_create_and_insert_user_twice() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let username = get_user_uuid();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_user(&username.to_string(), &operator_name, &username.to_string()).await;
+    delete_creds_files_of_full_user(&creds_base_path, &operator_name, &username.to_string());
+
+    let operator_name_cloned = operator_name.to_owned();
+    let creds_base_path_cloned = creds_base_path.to_owned();
+
+    let result = tokio::spawn(async move {
+        let result = create_and_insert_user(Arc::clone(&postgres_client), &provisioning_config(&creds_base_path, &operator_name), username).await;
+        assert_eq!(result, Ok(UserProvisioning::Created), "User should have been created");
+
+        let result = create_and_insert_user(Arc::clone(&postgres_client), &provisioning_config(&creds_base_path, &operator_name), username).await;
+        assert_eq!(result, Ok(UserProvisioning::AlreadyProvisioned), "Second creation should return the existing user");
+    }).await;
+
+    cleanup_user(&username.to_string(), &operator_name_cloned, &username.to_string()).await;
+    delete_creds_files_of_full_user(&creds_base_path_cloned, &operator_name_cloned, &username.to_string());
+
+    assert!(result.is_ok(), "Test failed");
+}
+
+#[tokio::test]
+async fn test_create_and_insert_user_already_in_database() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let username = get_user_uuid();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_postgres_user(username).await;
+
+    let result = tokio::spawn(async move {
+        let result = insert_dummy_nsc_user(username).await;
+        assert!(result.is_ok(), "Failed to insert user: {:?}", result);
+
+        // No nsc call should be made, the user is already provisioned
+        let result = create_and_insert_user(Arc::clone(&postgres_client), &provisioning_config(&creds_base_path, &operator_name), username).await;
+        assert_eq!(result, Ok(UserProvisioning::AlreadyProvisioned), "User should already be provisioned");
+    }).await;
+
+    cleanup_postgres_user(username).await;
+
+    assert!(result.is_ok(), "Test failed");
+}
+
+#[tokio::test]
+async fn test_create_and_insert_user_unknown_identity() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    // Not in the auth table
+    let username = Uuid::parse_str("0e9a2f0e-5d2b-4c1e-9a55-3c3f4c2f8d11").unwrap();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    let identity_query = get_identity_query();
+    assert!(identity_query.is_some(), "Identity check should be enabled by default");
+
+    let result = create_and_insert_user(Arc::clone(&postgres_client), &provisioning_config(&creds_base_path, &operator_name), username).await;
+
+    assert_eq!(result, Ok(UserProvisioning::UnknownIdentity), "Unknown user should not be provisioned");
+    assert!(!nsc_account_exists(&username.to_string()), "No nsc account should have been created");
+}
+
+#[tokio::test]
+async fn test_create_and_insert_user_rollback() {
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let username = get_user_uuid();
+    let account_name = username.to_string();
+    // The creds files can not be read from there, so the creation fails after the nsc account is created
+    let creds_base_path = "/nonexistent/creds";
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_user(&account_name, &operator_name, &account_name).await;
+
+    let operator_name_cloned = operator_name.to_owned();
+    let account_name_cloned = account_name.to_owned();
+
+    let result = tokio::spawn(async move {
+        let result = create_and_insert_user(Arc::clone(&postgres_client), &provisioning_config(creds_base_path, &operator_name), username).await;
+        assert!(result.is_err(), "Creation should fail: {:?}", result);
+
+        assert!(!nsc_account_exists(&account_name), "Nsc account should have been rolled back");
+
+        let rows = Arc::clone(&postgres_client).query("SELECT * FROM nats WHERE id = $1", &[&username])
+            .await
+            .unwrap();
+        assert!(rows.is_empty(), "User should not exist in the database");
+    }).await;
+
+    cleanup_user(&account_name_cloned, &operator_name_cloned, &account_name_cloned).await;
+
+    assert!(result.is_ok(), "Test failed");
+}
+
 #[tokio::test]
 async fn test_delete_user_everywhere() {
     use command_notifier::nsc_accounts_utils::check_if_creds_exists;
@@ -90,13 +195,13 @@ async fn test_delete_user_everywhere() {
 
     let result = tokio::spawn(async move {
 
-        let result = create_and_insert_user(Arc::clone(&postgres_client), &creds_base_path, &operator_name, username).await;
+        let result = create_and_insert_user(Arc::clone(&postgres_client), &provisioning_config(&creds_base_path, &operator_name), username).await;
         
         assert!(result.is_ok(), "Failed to create and insert user: {:?}", result);
 
-        let result = delete_user_everywhere(Arc::clone(&postgres_client), &creds_base_path, &operator_name, username).await;
+        let report = delete_user_everywhere(Arc::clone(&postgres_client), &creds_base_path, &operator_name, &AccountResolver::External, username).await;
         
-        assert!(result.is_ok(), "Failed to delete user: {:?}", result);
+        assert!(report.is_success(), "Failed to delete user: {:?}", report);
 
         println!("Account name: {}", account_name);
 
@@ -124,7 +229,7 @@ async fn test_delete_user_everywhere() {
         let output = std::process::Command::new("nsc")
             .arg("describe")
             .arg("account")
-            .arg(&&account_name)
+            .arg(&account_name)
             .output()
             .unwrap();
         assert!(!output.status.success(), "nsc describe user user_01 should fail");
@@ -143,7 +248,7 @@ async fn test_delete_user_everywhere() {
             .await
             .unwrap();
 
-        assert!(rows.len() == 0, "User should not exist in the database");
+        assert!(rows.is_empty(), "User should not exist in the database");
 
     }).await;
 
@@ -154,6 +259,77 @@ async fn test_delete_user_everywhere() {
     assert!(result.is_ok(), "Test failed");
 }
 
+#[tokio::test]
+async fn test_delete_user_everywhere_already_gone() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let username = get_user_uuid();
+    let account_name = username.to_string();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_user(&account_name, &operator_name, &account_name).await;
+    delete_creds_files_of_full_user(&creds_base_path, &operator_name, &account_name);
+
+    let report = delete_user_everywhere(Arc::clone(&postgres_client), &creds_base_path, &operator_name, &AccountResolver::External, username).await;
+
+    assert!(report.is_success(), "Deleting a missing user should not fail: {:?}", report);
+    assert!(report.steps.iter().all(|step| step.status == DeletionStatus::AlreadyGone), "All the steps should be already gone: {:?}", report);
+}
+
+#[tokio::test]
+async fn test_delete_user_everywhere_unknown_operator() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let username = Uuid::new_v4();
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    insert_dummy_nsc_user(username).await.unwrap();
+
+    // Nothing is deleted with the operator selected in nsc
+    let report = delete_user_everywhere(Arc::clone(&postgres_client), &creds_base_path, "UnknownOperator", &AccountResolver::External, username).await;
+    assert_eq!(report.steps.iter().map(|step| step.step.as_str()).collect::<Vec<_>>(), ["nsc_operator"]);
+    assert!(report.is_total_failure(), "{:?}", report);
+    assert_eq!(delete_nsc_user_from_postgres(Arc::clone(&postgres_client), username).await, Ok(true));
+}
+
+#[tokio::test]
+async fn test_delete_user_everywhere_removes_api_keys() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let username = get_user_uuid();
+    let account_name = username.to_string();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_user(&account_name, &operator_name, &account_name).await;
+
+    let operator_name_cloned = operator_name.to_owned();
+
+    let result = tokio::spawn(async move {
+        let result = insert_dummy_nsc_user(username).await;
+        assert!(result.is_ok(), "Failed to insert user: {:?}", result);
+        let result = add_api_key(Arc::clone(&postgres_client), username, "APIKEY123").await;
+        assert!(result.is_ok(), "Failed to add api key: {:?}", result);
+
+        let report = delete_user_everywhere(Arc::clone(&postgres_client), &creds_base_path, &operator_name, &AccountResolver::External, username).await;
+        assert!(report.is_success(), "Failed to delete user: {:?}", report);
+
+        // The user has no devices, see tests/devices.rs
+        let database_steps: Vec<_> = report.steps.iter()
+            .filter(|step| ["database_user", "database_api_keys"].contains(&step.step.as_str()))
+            .collect();
+        assert!(database_steps.iter().all(|step| step.status == DeletionStatus::Deleted), "Database rows should have been deleted: {:?}", database_steps);
+
+        let rows = Arc::clone(&postgres_client).query("SELECT id FROM api_keys WHERE user_id = $1", &[&username])
+            .await
+            .unwrap();
+        assert!(rows.is_empty(), "Api keys of the user should have been deleted");
+    }).await;
+
+    cleanup_user(&account_name, &operator_name_cloned, &account_name).await;
+
+    assert!(result.is_ok(), "Test failed");
+}
+
 #[cfg(test)]
 async fn cleanup_user(username: &str, operator_name:&str, account_name: &str) {
     let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
@@ -176,7 +352,8 @@ async fn test_get_admin_creds_not_exists_should_fail() {
 
     cleanup_user(username, &operator_name, account_name).await;
 
-    let result = get_admin_creds_if_not_exists(&creds_base_path, &operator_name, account_name).await;
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let result = get_admin_creds_if_not_exists(postgres_client, &creds_base_path, &operator_name, account_name).await;
 
     assert!(result.is_err(), "Result should be an error {:?}", result);
 }
@@ -208,7 +385,7 @@ async fn test_get_admin_creds_not_yet_exists() {
         
         let _result = update_creds_admin(Arc::clone(&postgres_client), username_uuid, creds_admin).await;
         
-        let result = get_admin_creds_if_not_exists(&creds_base_path, &operator_name, &account_name).await;
+        let result = get_admin_creds_if_not_exists(Arc::clone(&postgres_client), &creds_base_path, &operator_name, &account_name).await;
         
         let creds_path = result.unwrap();
         
@@ -260,12 +437,16 @@ async fn test_get_admin_creds_already_exists() {
         let creds_path = get_creds_path(&creds_base_path, &operator_name, &account_name, "admin_01");
         
         println!("Creds path: {}", creds_path);
+
+        std::fs::create_dir_all(get_account_creds_dir(&creds_base_path, &operator_name, &account_name))
+            .unwrap();
         
         std::fs::write(&creds_path, creds_admin)
             .map_err(|err| format!("Failed to write creds_admin to file: {}", err))
             .unwrap();
     
-        let result = get_admin_creds_if_not_exists(&creds_base_path, &operator_name, &account_name).await;
+        let postgres_client = Arc::new(setup_postgres_client().await);
+        let result = get_admin_creds_if_not_exists(postgres_client, &creds_base_path, &operator_name, &account_name).await;
         
         assert!(result.is_ok(), "Failed to get creds path: {:?}", result);
         
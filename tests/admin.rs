@@ -0,0 +1,83 @@
+use command_notifier::{
+    accounts_lifecycle::generate_api_key,
+    admin::{get_user_details, revoke_api_key, Table, TableOutput},
+    error::Error,
+    nsc_accounts_utils::AccountLimits,
+    plans::PlanChange,
+    postgres::{delete_api_keys_of_user, get_nsc_user_record, list_api_keys, list_nsc_user_records, verify_api_key},
+};
+
+use std::sync::Arc;
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{cleanup_postgres_user, insert_dummy_nsc_user, setup_postgres_client};
+
+#[test]
+fn test_render_table() {
+    let mut table = Table::new(vec!["USER ID", "STATUS"]);
+    table.push_row(vec!["a".to_string(), "created".to_string()]);
+    table.push_row(vec!["longer-user-id".to_string(), "-".to_string()]);
+
+    assert_eq!(table.render(), "\
+USER ID         STATUS
+a               created
+longer-user-id  -
+");
+}
+
+#[test]
+fn test_plan_change_table() {
+    // What is signed into the account JWT, no JetStream storage limit being unlimited
+    let limits = AccountLimits { max_connections: Some(10), max_payload: Some(1048576), ..Default::default() };
+    let change = PlanChange { user_id: Uuid::nil(), plan: "free".to_string(), limits };
+    assert_eq!(change.to_table().render(), "\
+USER ID                               PLAN  CONNECTIONS  SUBSCRIPTIONS  PAYLOAD  JETSTREAM STORAGE
+00000000-0000-0000-0000-000000000000  free  10           unlimited      1048576  unlimited
+");
+}
+
+#[tokio::test]
+async fn test_user_records_and_api_keys() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+
+    let api_key = generate_api_key(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert!(verify_api_key(Arc::clone(&postgres_client), user_id, &api_key).await.unwrap());
+
+    let record = get_nsc_user_record(Arc::clone(&postgres_client), user_id).await.unwrap().unwrap();
+    assert_eq!(record.nsc_account_id, "nsc_account_id_dummy");
+    assert_eq!(record.api_keys, 1);
+    assert!(record.created_at.ends_with('Z'), "{}", record.created_at);
+
+    let records = list_nsc_user_records(Arc::clone(&postgres_client)).await.unwrap();
+    assert!(records.iter().any(|record| record.user_id == user_id));
+    assert!(records.to_table().render().contains(&user_id.to_string()));
+
+    let api_keys = list_api_keys(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(api_keys.len(), 1);
+    let details = get_user_details(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(details.api_key_ids, [api_keys[0].id]);
+
+    revoke_api_key(Arc::clone(&postgres_client), api_keys[0].id).await.unwrap();
+    assert!(!verify_api_key(Arc::clone(&postgres_client), user_id, &api_key).await.unwrap());
+    let result = revoke_api_key(Arc::clone(&postgres_client), api_keys[0].id).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+
+    delete_api_keys_of_user(postgres_client, user_id).await.unwrap();
+    cleanup_postgres_user(user_id).await;
+}
+
+#[tokio::test]
+async fn test_unknown_user() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+
+    assert!(get_nsc_user_record(Arc::clone(&postgres_client), user_id).await.unwrap().is_none());
+    let result = get_user_details(Arc::clone(&postgres_client), user_id).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+    let result = generate_api_key(postgres_client, user_id).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+}
@@ -0,0 +1,142 @@
+use command_notifier::{
+    dedup::DedupConfig,
+    error::Error,
+    notification::Notification,
+    outbound::enqueue_notification,
+    postgres::{delete_outbound_deliveries_of_user, delete_routing_of_user},
+    routing::{create_routing_rule, get_history},
+};
+
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{cleanup_postgres_user, insert_dummy_nsc_user, setup_postgres_client};
+
+async fn queued_notifications(postgres_client: &tokio_postgres::Client, user_id: Uuid) -> Vec<Notification> {
+    let rows = postgres_client.query("SELECT notification FROM outbound_deliveries WHERE user_id = $1 ORDER BY created_at", &[&user_id]).await.unwrap();
+    rows.iter().map(|row| serde_json::from_str(row.get(0)).unwrap()).collect()
+}
+
+#[test]
+fn test_dedup_config_from_env() {
+    // The only test of this binary reading the environment
+    env::remove_var("DEDUP_WINDOW_SECS");
+    assert_eq!(DedupConfig::from_env(), Ok(DedupConfig::default()));
+    env::set_var("DEDUP_WINDOW_SECS", "5");
+    assert_eq!(DedupConfig::from_env(), Ok(DedupConfig { window: Duration::from_secs(5) }));
+    env::set_var("DEDUP_WINDOW_SECS", "10m");
+    assert!(matches!(DedupConfig::from_env(), Err(Error::InvalidInput(_))));
+    env::remove_var("DEDUP_WINDOW_SECS");
+}
+
+#[tokio::test]
+async fn test_deduplication_and_grouping() {
+    let dedup = DedupConfig { window: Duration::from_secs(1) };
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+
+    let failed = Notification { message: "Job failed".to_string(), dedup_key: Some("ci-job-42".to_string()), ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &dedup, user_id, &failed).await, Ok(1));
+    for _ in 0..3 {
+        assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &dedup, user_id, &failed).await, Ok(0));
+    }
+    let history = get_history(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].duplicates, 3);
+
+    let running = Notification { message: "Pipeline running".to_string(), group: Some("pipeline-7".to_string()), ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &dedup, user_id, &running).await, Ok(1));
+    let done = Notification { message: "Pipeline done".to_string(), ..running.clone() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &dedup, user_id, &done).await, Ok(1));
+
+    let queued = queued_notifications(&postgres_client, user_id).await;
+    assert_eq!(queued.len(), 3);
+    assert!(queued.iter().all(|notification| notification.id.is_some()));
+    assert_eq!((queued[1].replaces, queued[2].replaces), (None, queued[1].id));
+
+    // Past the window, neither a duplicate nor a replacement
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &dedup, user_id, &failed).await, Ok(1));
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &dedup, user_id, &running).await, Ok(1));
+    assert_eq!(queued_notifications(&postgres_client, user_id).await[4].replaces, None);
+
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(5));
+    // 5 history entries and the dedup key
+    assert_eq!(delete_routing_of_user(Arc::clone(&postgres_client), user_id).await, Ok(6));
+    cleanup_postgres_user(user_id).await;
+}
+
+#[tokio::test]
+async fn test_concurrent_duplicates_are_delivered_once() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+
+    let failed = Notification { message: "Job failed".to_string(), dedup_key: Some("ci-job-43".to_string()), ..Default::default() };
+    let sends: Vec<_> = (0..10).map(|_| {
+        let (postgres_client, failed) = (Arc::clone(&postgres_client), failed.clone());
+        tokio::spawn(async move { enqueue_notification(postgres_client, &DedupConfig::default(), user_id, &failed).await.unwrap() })
+    }).collect();
+    let mut queued = 0;
+    for send in sends {
+        queued += send.await.unwrap();
+    }
+    assert_eq!(queued, 1);
+    let history = get_history(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].duplicates, 9);
+
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(1));
+    assert_eq!(delete_routing_of_user(Arc::clone(&postgres_client), user_id).await, Ok(2));
+    cleanup_postgres_user(user_id).await;
+}
+
+#[tokio::test]
+async fn test_notifications_kept_in_the_history_are_not_replaced() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+    let history_only = serde_json::from_str(r#"{"conditions": {"status": "running"}, "action": {"type": "history"}}"#).unwrap();
+    create_routing_rule(Arc::clone(&postgres_client), user_id, &history_only).await.unwrap();
+
+    let running = Notification { message: "Pipeline running".to_string(), group: Some("pipeline-8".to_string()), status: Some("running".to_string()), ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &running).await, Ok(0));
+    // The devices never got the running one
+    let done = Notification { message: "Pipeline done".to_string(), status: Some("success".to_string()), ..running.clone() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &done).await, Ok(1));
+    assert_eq!(queued_notifications(&postgres_client, user_id).await[0].replaces, None);
+
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(1));
+    // The rule and the 2 history entries
+    assert_eq!(delete_routing_of_user(Arc::clone(&postgres_client), user_id).await, Ok(3));
+    cleanup_postgres_user(user_id).await;
+}
+
+#[tokio::test]
+async fn test_notifications_held_by_a_rule_are_replaced() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+    // Held until an hour from now, in UTC
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 60 % 1440;
+    let time = |minute: u64| format!("{:02}:{:02}", minute % 1440 / 60, minute % 60);
+    let hold = format!(r#"{{"conditions": {{"status": "running", "schedule": {{"start": "{}", "end": "{}"}}}}, "action": {{"type": "hold"}}}}"#, time(now + 1380), time(now + 60));
+    create_routing_rule(Arc::clone(&postgres_client), user_id, &serde_json::from_str(&hold).unwrap()).await.unwrap();
+
+    let running = Notification { message: "Pipeline running".to_string(), group: Some("pipeline-9".to_string()), status: Some("running".to_string()), ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &running).await, Ok(1));
+    let done = Notification { message: "Pipeline done".to_string(), status: Some("success".to_string()), ..running.clone() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &done).await, Ok(1));
+    let queued = queued_notifications(&postgres_client, user_id).await;
+    assert_eq!(queued[1].replaces, queued[0].id);
+
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(2));
+    // The rule and the 2 history entries
+    assert_eq!(delete_routing_of_user(Arc::clone(&postgres_client), user_id).await, Ok(3));
+    cleanup_postgres_user(user_id).await;
+}
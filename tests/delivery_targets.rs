@@ -0,0 +1,229 @@
+use axum::{http::{HeaderMap, StatusCode}, routing::post, Router};
+use command_notifier::{
+    delivery_targets::{create_delivery_target, get_delivery_targets, remove_delivery_target, DeliveryFailure, RetryPolicy, TargetConfig},
+    error::Error,
+    notification::Notification,
+    webhook::{is_public_address, send_webhook, sign_body, webhook_http_client_builder, WebhookTarget, SIGNATURE_HEADER},
+};
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{cleanup_postgres_user, insert_dummy_nsc_user, setup_postgres_client};
+
+fn webhook_target(url: &str) -> WebhookTarget {
+    WebhookTarget {
+        url: url.to_string(),
+        method: "POST".to_string(),
+        headers: BTreeMap::new(),
+        secret: None,
+        body_template: None,
+    }
+}
+
+// Local stand-in of a webhook, answering the given statuses in order then 200, and keeping the requests
+async fn spawn_webhook(statuses: Vec<StatusCode>) -> (String, Arc<Mutex<Vec<(HeaderMap, String)>>>) {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+    let received = Arc::clone(&requests);
+    let app = Router::new().route("/hook", post(move |headers: HeaderMap, body: String| {
+        let received = Arc::clone(&received);
+        let statuses = Arc::clone(&statuses);
+        async move {
+            received.lock().unwrap().push((headers, body));
+            statuses.lock().unwrap().next().unwrap_or(StatusCode::OK)
+        }
+    }));
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (format!("http://{}/hook", addr), requests)
+}
+
+#[test]
+fn test_render_body() {
+    let user_id = Uuid::new_v4();
+    let notification = Notification { message: "Build \"main\" failed".to_string(), title: None, host: Some("ci".to_string()), ..Default::default() };
+
+    let target = webhook_target("http://localhost/hook");
+    assert_eq!(target.render_body(user_id, &notification), serde_json::to_string(&notification).unwrap());
+
+    let target = WebhookTarget { body_template: Some(r#"{"text": "[{{host}}] {{message}}{{title}}", "user": "{{user_id}}"}"#.to_string()), ..target };
+    let body: serde_json::Value = serde_json::from_str(&target.render_body(user_id, &notification)).unwrap();
+    assert_eq!(body["text"], "[ci] Build \"main\" failed");
+    assert_eq!(body["user"], user_id.to_string());
+}
+
+#[test]
+fn test_sign_body() {
+    // HMAC-SHA256 test vector of RFC 4231 (test case 2)
+    assert_eq!(
+        sign_body("Jefe", "what do ya want for nothing?"),
+        "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+}
+
+#[test]
+fn test_validate_target() {
+    assert!(webhook_target("https://example.com/hook").validate().is_ok());
+
+    let invalid_targets = [
+        webhook_target("not a url"),
+        webhook_target("ftp://example.com/hook"),
+        WebhookTarget { method: "GET".to_string(), ..webhook_target("https://example.com/hook") },
+        WebhookTarget { headers: BTreeMap::from([("bad header".to_string(), "value".to_string())]), ..webhook_target("https://example.com/hook") },
+    ];
+    for target in invalid_targets {
+        assert!(matches!(target.validate(), Err(Error::InvalidInput(_))), "{:?} should be invalid", target);
+    }
+}
+
+#[test]
+fn test_retry_backoff() {
+    let retry_policy = RetryPolicy { max_attempts: 10, initial_backoff: Duration::from_millis(500), max_backoff: Duration::from_secs(3) };
+    assert_eq!(retry_policy.backoff(1), Duration::from_millis(500));
+    assert_eq!(retry_policy.backoff(2), Duration::from_secs(1));
+    assert_eq!(retry_policy.backoff(3), Duration::from_secs(2));
+    assert_eq!(retry_policy.backoff(4), Duration::from_secs(3));
+    assert_eq!(retry_policy.backoff(40), Duration::from_secs(3));
+}
+
+#[tokio::test]
+async fn test_send_webhook() {
+    let (url, requests) = spawn_webhook(vec![StatusCode::SERVICE_UNAVAILABLE]).await;
+    let target = WebhookTarget {
+        headers: BTreeMap::from([("X-Team".to_string(), "backend".to_string())]),
+        secret: Some("webhook-secret".to_string()),
+        ..webhook_target(&url)
+    };
+    let notification = Notification { message: "Done".to_string(), ..Default::default() };
+
+    let result = send_webhook(&reqwest::Client::new(), true, &target, Uuid::new_v4(), &notification).await;
+    assert!(matches!(result, Err(DeliveryFailure { retryable: true, .. })), "{:?}", result);
+    assert_eq!(send_webhook(&reqwest::Client::new(), true, &target, Uuid::new_v4(), &notification).await, Ok(()));
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    let (headers, body) = &requests[1];
+    assert_eq!(headers["x-team"], "backend");
+    assert_eq!(headers[SIGNATURE_HEADER], sign_body("webhook-secret", body).as_str());
+}
+
+#[test]
+fn test_is_public_address() {
+    let addresses = [
+        ("93.184.216.34", true),
+        ("127.0.0.1", false),
+        ("169.254.169.254", false),
+        ("10.0.0.3", false),
+        ("172.16.0.1", false),
+        ("192.168.1.1", false),
+        ("0.0.0.0", false),
+        ("0.1.2.3", false),
+        // Carrier-grade NAT, and the public addresses around it
+        ("100.64.0.1", false),
+        ("100.127.255.254", false),
+        ("100.63.255.255", true),
+        ("100.128.0.1", true),
+        ("192.0.0.8", false),
+        ("192.0.1.1", true),
+        ("198.18.0.1", false),
+        ("198.19.255.255", false),
+        ("198.20.0.1", true),
+        ("240.0.0.1", false),
+        ("255.255.255.255", false),
+        ("2606:4700:4700::1111", true),
+        ("::1", false),
+        ("::", false),
+        ("fe80::1", false),
+        ("fd00::1", false),
+        ("::ffff:127.0.0.1", false),
+        // NAT64 and 6to4 of a private and of a public address
+        ("64:ff9b::a00:3", false),
+        ("64:ff9b::5db8:d822", true),
+        ("2002:a9fe:a9fe::1", false),
+        ("2002:5db8:d822::1", true),
+    ];
+    for (ip, public) in addresses {
+        assert_eq!(is_public_address(ip.parse::<IpAddr>().unwrap()), public, "{}", ip);
+    }
+}
+
+#[tokio::test]
+async fn test_webhooks_to_private_addresses_are_rejected() {
+    let (url, requests) = spawn_webhook(Vec::new()).await;
+    let http_client = webhook_http_client_builder(false).build().unwrap();
+    let notification = Notification { message: "Done".to_string(), ..Default::default() };
+
+    let port = url.trim_start_matches("http://127.0.0.1:").trim_end_matches("/hook");
+    for url in [url.clone(), "http://169.254.169.254/latest/meta-data/".to_string(), format!("http://localhost:{}/hook", port), format!("http://[::1]:{}/hook", port)] {
+        let result = send_webhook(&http_client, false, &webhook_target(&url), Uuid::new_v4(), &notification).await;
+        assert!(matches!(&result, Err(DeliveryFailure { retryable: false, message }) if message.contains("not allowed")), "{}: {:?}", url, result);
+    }
+    assert!(requests.lock().unwrap().is_empty());
+
+    // The check of send_webhook skipped, the client still does not connect to a host resolving to the loopback
+    let result = http_client.post(format!("http://localhost:{}/hook", port)).send().await;
+    assert!(result.is_err(), "{:?}", result);
+    assert!(requests.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_webhook_redirects_are_not_followed() {
+    let (target_url, requests) = spawn_webhook(Vec::new()).await;
+    let app = Router::new().route("/hook", post(move || {
+        let target_url = target_url.clone();
+        async move { (StatusCode::TEMPORARY_REDIRECT, [(axum::http::header::LOCATION, target_url)]) }
+    }));
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let redirect_url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let notification = Notification { message: "Done".to_string(), ..Default::default() };
+
+    let http_client = webhook_http_client_builder(true).build().unwrap();
+    let result = send_webhook(&http_client, true, &webhook_target(&redirect_url), Uuid::new_v4(), &notification).await;
+    assert!(matches!(result, Err(DeliveryFailure { retryable: false, .. })), "{:?}", result);
+    assert!(requests.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_send_webhook_client_errors_are_not_retryable() {
+    let (url, _requests) = spawn_webhook(vec![StatusCode::BAD_REQUEST]).await;
+    let notification = Notification { message: "Done".to_string(), ..Default::default() };
+
+    let result = send_webhook(&reqwest::Client::new(), true, &webhook_target(&url), Uuid::new_v4(), &notification).await;
+    assert!(matches!(result, Err(DeliveryFailure { retryable: false, .. })), "{:?}", result);
+}
+
+#[tokio::test]
+async fn test_delivery_targets_crud() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    let config = TargetConfig::Webhook(WebhookTarget { secret: Some("webhook-secret".to_string()), ..webhook_target("https://example.com/hook") });
+
+    let result = create_delivery_target(Arc::clone(&postgres_client), None, user_id, &config).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+
+    insert_dummy_nsc_user(user_id).await.unwrap();
+    let target = create_delivery_target(Arc::clone(&postgres_client), None, user_id, &config).await.unwrap();
+    assert_eq!(target.config, config);
+    // Only the email targets are confirmed
+    assert!(target.confirmed);
+
+    let targets = get_delivery_targets(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(targets.len(), 1);
+    let TargetConfig::Webhook(redacted) = &targets[0].redacted().config else { panic!("Expected a webhook target") };
+    assert_eq!(redacted.secret.as_deref(), Some("********"));
+
+    remove_delivery_target(Arc::clone(&postgres_client), user_id, target.id).await.unwrap();
+    let result = remove_delivery_target(Arc::clone(&postgres_client), user_id, target.id).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+    assert!(get_delivery_targets(postgres_client, user_id).await.unwrap().is_empty());
+
+    cleanup_postgres_user(user_id).await;
+}
@@ -0,0 +1,83 @@
+use command_notifier::{
+    accounts_lifecycle::{create_and_insert_user, ProvisioningConfig, delete_user_everywhere, DeletionStatus},
+    devices::{get_device_nsc_username, register_device, revoke_device, validate_device_name},
+    error::Error,
+    nats_resolver::AccountResolver,
+    nsc_accounts_utils::{check_if_creds_exists, nsc_user_exists},
+    postgres::list_devices,
+    user_policy::UserPolicy,
+};
+
+use std::env;
+use std::sync::Arc;
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{provisioning_config, setup_postgres_client};
+
+#[test]
+fn test_validate_device_name() {
+    assert!(validate_device_name("laptop").is_ok());
+    assert!(validate_device_name("phone-2").is_ok());
+
+    for name in ["", "Laptop", "my laptop", "../creds", &"a".repeat(33)] {
+        assert!(matches!(validate_device_name(name), Err(Error::InvalidInput(_))), "{:?} should be invalid", name);
+    }
+}
+
+#[tokio::test]
+async fn test_register_and_revoke_devices() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    let account_name = user_id.to_string();
+
+    // A random user, unknown to the identity check
+    create_and_insert_user(Arc::clone(&postgres_client), &ProvisioningConfig {
+        identity_query: None,
+        ..provisioning_config(&creds_base_path, &operator_name)
+    }, user_id)
+        .await
+        .unwrap();
+
+    for name in ["laptop", "phone"] {
+        let registered_device = register_device(Arc::clone(&postgres_client), &creds_base_path, &operator_name, &UserPolicy::default(), user_id, name).await.unwrap();
+        assert_eq!(registered_device.device.nsc_username, get_device_nsc_username(name));
+        assert!(registered_device.creds.contains("NATS USER JWT"));
+    }
+    let result = register_device(Arc::clone(&postgres_client), &creds_base_path, &operator_name, &UserPolicy::default(), user_id, "laptop").await;
+    assert!(matches!(result, Err(Error::Conflict(_))), "{:?}", result);
+
+    let devices = list_devices(Arc::clone(&postgres_client), user_id).await.unwrap();
+    let names = devices.iter().map(|device| device.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["laptop", "phone"]);
+
+    // Revoking the laptop leaves the phone untouched
+    revoke_device(Arc::clone(&postgres_client), &creds_base_path, &operator_name, None, &AccountResolver::External, user_id, "laptop").await.unwrap();
+    let laptop_username = get_device_nsc_username("laptop");
+    assert!(!nsc_user_exists(&account_name, &laptop_username));
+    assert!(check_if_creds_exists(&creds_base_path, &operator_name, &account_name, &laptop_username).is_err());
+    assert!(check_if_creds_exists(&creds_base_path, &operator_name, &account_name, &get_device_nsc_username("phone")).is_ok());
+    assert_eq!(list_devices(Arc::clone(&postgres_client), user_id).await.unwrap().len(), 1);
+
+    let result = revoke_device(Arc::clone(&postgres_client), &creds_base_path, &operator_name, None, &AccountResolver::External, user_id, "laptop").await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+
+    let report = delete_user_everywhere(Arc::clone(&postgres_client), &creds_base_path, &operator_name, &AccountResolver::External, user_id).await;
+    assert!(report.is_success(), "{:?}", report);
+    let devices_step = report.steps.iter().find(|step| step.step == "database_devices").unwrap();
+    assert_eq!(devices_step.status, DeletionStatus::Deleted);
+    assert!(list_devices(postgres_client, user_id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_register_device_unknown_user() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    let result = register_device(postgres_client, &creds_base_path, &operator_name, &UserPolicy::default(), Uuid::new_v4(), "laptop").await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+}
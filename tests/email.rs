@@ -0,0 +1,139 @@
+use command_notifier::{
+    dedup::DedupConfig,
+    delivery_targets::{confirm_email_target, create_delivery_target, deliver_to_target, get_delivery_targets, DeliveryConfig, DeliveryFailure, RetryPolicy, TargetConfig},
+    email::EmailTarget,
+    error::Error,
+    notification::{Notification, Priority},
+    outbound::enqueue_notification,
+    postgres::delete_outbound_deliveries_of_user,
+};
+
+use std::sync::Arc;
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{cleanup_postgres_user, confirmation_token, email_sender, insert_dummy_nsc_user, setup_postgres_client, spawn_smtp_sink};
+
+fn email_target(address: &str) -> EmailTarget {
+    EmailTarget { address: address.to_string(), min_priority: Priority::High, subject_template: None, body_template: None }
+}
+
+#[test]
+fn test_render_email() {
+    let user_id = Uuid::new_v4();
+    let notification = Notification { message: "Disk full\nat 99%".to_string(), host: Some("db-01".to_string()), ..Default::default() };
+
+    let target = email_target("ops@example.com");
+    assert_eq!(target.render_subject(user_id, &notification), "Disk full");
+    assert_eq!(target.render_body(user_id, &notification), "Disk full\nat 99%\n\nSent from db-01");
+
+    let target = EmailTarget {
+        subject_template: Some("[{{priority}}] {{host}}: {{message}}".to_string()),
+        body_template: Some("{{message}} ({{user_id}})".to_string()),
+        ..target
+    };
+    let notification = Notification { priority: Priority::Urgent, ..notification };
+    assert_eq!(target.render_subject(user_id, &notification), "[urgent] db-01: Disk full at 99%");
+    assert_eq!(target.render_body(user_id, &notification), format!("Disk full\nat 99% ({})", user_id));
+}
+
+#[test]
+fn test_email_target_config() {
+    let target: TargetConfig = serde_json::from_str(r#"{"type": "email", "address": "ops@example.com"}"#).unwrap();
+    let TargetConfig::Email(email) = &target else { panic!("Expected an email target") };
+    assert_eq!(email.min_priority, Priority::High);
+    assert!(target.validate().is_ok());
+
+    let notification = Notification { message: "done".to_string(), ..Default::default() };
+    assert!(!target.accepts(&notification));
+    assert!(target.accepts(&Notification { priority: Priority::High, ..notification.clone() }));
+    assert!(target.accepts(&Notification { priority: Priority::Urgent, ..notification }));
+
+    assert!(matches!(email_target("not an address").validate(), Err(Error::InvalidInput(_))));
+}
+
+#[tokio::test]
+async fn test_send_email() {
+    let (port, emails, data_attempts) = spawn_smtp_sink(vec![451]).await;
+    let sender = email_sender(port);
+    let notification = Notification { message: "Disk full".to_string(), priority: Priority::High, ..Default::default() };
+
+    let result = sender.send(&email_target("ops@example.com"), Uuid::new_v4(), &notification).await;
+    assert!(matches!(result, Err(DeliveryFailure { retryable: true, .. })), "{:?}", result);
+    assert_eq!(sender.send(&email_target("ops@example.com"), Uuid::new_v4(), &notification).await, Ok(()));
+
+    let emails = emails.lock().unwrap();
+    assert_eq!(emails.len(), 1);
+    assert!(emails[0].contains("To: ops@example.com"), "{}", emails[0]);
+    assert!(emails[0].contains("Subject: Disk full"), "{}", emails[0]);
+    assert_eq!(*data_attempts.lock().unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_rejected_emails_are_not_retryable() {
+    let (port, emails, _data_attempts) = spawn_smtp_sink(vec![550]).await;
+    let notification = Notification { message: "Disk full".to_string(), priority: Priority::High, ..Default::default() };
+
+    let result = email_sender(port).send(&email_target("ops@example.com"), Uuid::new_v4(), &notification).await;
+    assert!(matches!(result, Err(DeliveryFailure { retryable: false, .. })), "{:?}", result);
+    assert!(emails.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_email_targets_are_confirmed_before_delivery() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+    let (port, emails, _data_attempts) = spawn_smtp_sink(vec![]).await;
+    let sender = email_sender(port);
+    let config = TargetConfig::Email(email_target("ops@example.com"));
+
+    let result = create_delivery_target(Arc::clone(&postgres_client), None, user_id, &config).await;
+    assert!(matches!(result, Err(Error::InvalidInput(_))), "{:?}", result);
+
+    let target = create_delivery_target(Arc::clone(&postgres_client), Some(&sender), user_id, &config).await.unwrap();
+    assert!(!target.confirmed);
+    let token = {
+        let emails = emails.lock().unwrap();
+        assert_eq!(emails.len(), 1);
+        assert!(emails[0].contains("To: ops@example.com"), "{}", emails[0]);
+        confirmation_token(&emails[0])
+    };
+
+    // Only NATS until the address is confirmed
+    let notification = Notification { message: "Disk full".to_string(), priority: Priority::Urgent, ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &notification).await, Ok(1));
+    let delivery = DeliveryConfig { http_client: reqwest::Client::new(), allow_private_networks: false, retry_policy: RetryPolicy::default(), email_sender: Some(sender) };
+    let result = deliver_to_target(&delivery, &target, &notification).await;
+    assert!(matches!(result, Err(DeliveryFailure { retryable: false, .. })), "{:?}", result);
+
+    let result = confirm_email_target(Arc::clone(&postgres_client), user_id, target.id, "not-the-token").await;
+    assert!(matches!(result, Err(Error::InvalidInput(_))), "{:?}", result);
+    assert!(confirm_email_target(Arc::clone(&postgres_client), user_id, target.id, &token).await.unwrap().confirmed);
+    let result = confirm_email_target(Arc::clone(&postgres_client), user_id, target.id, &token).await;
+    assert!(matches!(result, Err(Error::Conflict(_))), "{:?}", result);
+    assert!(get_delivery_targets(Arc::clone(&postgres_client), user_id).await.unwrap()[0].confirmed);
+
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &notification).await, Ok(2));
+
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(3));
+    postgres_client.execute("DELETE FROM delivery_targets WHERE user_id = $1", &[&user_id]).await.unwrap();
+    postgres_client.execute("DELETE FROM notification_history WHERE user_id = $1", &[&user_id]).await.unwrap();
+    cleanup_postgres_user(user_id).await;
+}
+
+#[tokio::test]
+async fn test_rejected_confirmation_removes_the_target() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+    let (port, _emails, _data_attempts) = spawn_smtp_sink(vec![550]).await;
+    let config = TargetConfig::Email(email_target("ops@example.com"));
+
+    let result = create_delivery_target(Arc::clone(&postgres_client), Some(&email_sender(port)), user_id, &config).await;
+    assert!(matches!(result, Err(Error::InvalidInput(_))), "{:?}", result);
+    assert!(get_delivery_targets(postgres_client, user_id).await.unwrap().is_empty());
+
+    cleanup_postgres_user(user_id).await;
+}
@@ -0,0 +1,154 @@
+use axum::{
+    body::{to_bytes, Body}, http::{header::CONTENT_TYPE, Request, StatusCode}, middleware::from_fn, response::IntoResponse, routing::{get, post}, Router,
+};
+use command_notifier::{
+    error::{method_not_allowed_middleware, panic_response, route_not_found, Error, JsonBody, PathParams, Service},
+    request_id::{request_id_middleware, REQUEST_ID_HEADER},
+};
+use tower::ServiceExt;
+use tower_http::catch_panic::CatchPanicLayer;
+
+async fn failing_handler() -> Result<&'static str, Error> {
+    Err(Error::Upstream(Service::Database, "connection refused to 10.0.0.3:5432".to_string()))
+}
+
+async fn panicking_handler() -> &'static str {
+    panic!("database row without creds_admin")
+}
+
+fn app() -> Router {
+    Router::new()
+        .route("/fail", get(failing_handler))
+        .route("/missing", get(|| async { Error::NotFound("User not found".to_string()).into_response() }))
+        .route("/panic", get(panicking_handler))
+        .route("/echo", post(|JsonBody(body): JsonBody<serde_json::Value>| async move { body.to_string() }))
+        .route("/items/:id", get(|PathParams(id): PathParams<u32>| async move { id.to_string() }))
+        .fallback(route_not_found)
+        .layer(from_fn(method_not_allowed_middleware))
+        .layer(CatchPanicLayer::custom(panic_response))
+        .layer(from_fn(request_id_middleware))
+}
+
+async fn response_json(response: axum::response::Response) -> serde_json::Value {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[test]
+fn test_error_status_codes() {
+    assert_eq!(Error::InvalidInput("".to_string()).status_code(), StatusCode::BAD_REQUEST);
+    assert_eq!(Error::NotFound("".to_string()).status_code(), StatusCode::NOT_FOUND);
+    assert_eq!(Error::Conflict("".to_string()).status_code(), StatusCode::CONFLICT);
+    assert_eq!(Error::Unauthorized("".to_string()).status_code(), StatusCode::UNAUTHORIZED);
+    assert_eq!(Error::Forbidden("".to_string()).status_code(), StatusCode::FORBIDDEN);
+    assert_eq!(Error::MethodNotAllowed("".to_string()).status_code(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(Error::nsc("").status_code(), StatusCode::BAD_GATEWAY);
+    assert_eq!(Error::Internal("".to_string()).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn test_error_response_hides_upstream_details() {
+    let response = app()
+        .oneshot(Request::get("/fail").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+    let body = response_json(response).await;
+
+    assert_eq!(body["error"]["code"], "upstream_error");
+    assert!(!body["error"]["message"].as_str().unwrap().contains("10.0.0.3"));
+    assert_eq!(body["error"]["request_id"], request_id);
+}
+
+#[tokio::test]
+async fn test_error_response_reuses_incoming_request_id() {
+    let request = Request::get("/missing")
+        .header(REQUEST_ID_HEADER, "proxy-1234")
+        .body(Body::empty())
+        .unwrap();
+    let response = app().oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers()[REQUEST_ID_HEADER], "proxy-1234");
+    let body = response_json(response).await;
+
+    assert_eq!(body["error"]["code"], "not_found");
+    assert_eq!(body["error"]["message"], "User not found");
+    assert_eq!(body["error"]["request_id"], "proxy-1234");
+}
+
+#[tokio::test]
+async fn test_invalid_request_id_is_replaced() {
+    let request = Request::get("/missing")
+        .header(REQUEST_ID_HEADER, "not a valid id")
+        .body(Body::empty())
+        .unwrap();
+    let response = app().oneshot(request).await.unwrap();
+
+    let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+    assert!(uuid::Uuid::parse_str(request_id).is_ok());
+}
+
+#[tokio::test]
+async fn test_panic_returns_internal_error() {
+    let app = app();
+    let response = app.clone()
+        .oneshot(Request::get("/panic").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+    let body = response_json(response).await;
+
+    assert_eq!(body["error"]["code"], "internal_error");
+    assert!(!body["error"]["message"].as_str().unwrap().contains("creds_admin"));
+    assert_eq!(body["error"]["request_id"], request_id);
+
+    // The router keeps serving the other requests
+    let response = app
+        .oneshot(Request::get("/missing").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_rejections_are_json_errors() {
+    let request = Request::post("/echo")
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from("{not json"))
+        .unwrap();
+    let response = app().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response_json(response).await["error"]["code"], "invalid_input");
+
+    let response = app()
+        .oneshot(Request::get("/items/abc").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response_json(response).await["error"]["code"], "invalid_input");
+}
+
+#[tokio::test]
+async fn test_unknown_routes_and_methods_are_json_errors() {
+    let response = app()
+        .oneshot(Request::get("/nowhere").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response_json(response).await["error"]["code"], "not_found");
+
+    let response = app()
+        .oneshot(Request::get("/echo").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+    let body = response_json(response).await;
+    assert_eq!(body["error"]["code"], "method_not_allowed");
+    assert_eq!(body["error"]["request_id"], request_id);
+}
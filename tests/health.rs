@@ -0,0 +1,112 @@
+use command_notifier::health::{
+    check_creds_writable, check_database, check_nats, check_operator_keystore, check_readiness, CheckStatus, ReadinessConfig,
+};
+
+use std::env;
+use std::sync::Arc;
+
+mod common;
+
+use common::utils::{setup_closed_postgres_client, setup_postgres_client};
+
+fn temp_dir(name: &str) -> String {
+    let path = env::temp_dir().join(format!("{}-{}", name, uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&path).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_check_database() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    assert_eq!(check_database(postgres_client).await, CheckStatus::Ok);
+
+    let postgres_client = Arc::new(setup_closed_postgres_client().await);
+    assert!(matches!(check_database(postgres_client).await, CheckStatus::Failed(_)));
+}
+
+#[tokio::test]
+async fn test_check_nats() {
+    assert!(matches!(check_nats("127.0.0.1:1", None).await, CheckStatus::Skipped(_)));
+
+    // Nothing listens on port 1
+    let status = check_nats("127.0.0.1:1", Some("/nonexistent/probe.creds")).await;
+    assert!(matches!(status, CheckStatus::Failed(_)), "Unexpected status: {:?}", status);
+}
+
+#[test]
+fn test_check_operator_keystore() {
+    let nsc_store_dir = temp_dir("health-store");
+
+    assert!(matches!(check_operator_keystore(&nsc_store_dir, "TestOperator"), CheckStatus::Failed(_)));
+
+    std::fs::create_dir_all(format!("{}/TestOperator", nsc_store_dir)).unwrap();
+    std::fs::write(format!("{}/TestOperator/TestOperator.jwt", nsc_store_dir), "operator_jwt_dummy").unwrap();
+    assert_eq!(check_operator_keystore(&nsc_store_dir, "TestOperator"), CheckStatus::Ok);
+
+    let _result = std::fs::remove_dir_all(&nsc_store_dir);
+}
+
+#[test]
+fn test_check_creds_writable() {
+    let creds_base_path = temp_dir("health-creds");
+
+    assert_eq!(check_creds_writable(&creds_base_path), CheckStatus::Ok);
+    // The probe file is removed
+    assert_eq!(std::fs::read_dir(&creds_base_path).unwrap().count(), 0);
+    assert!(matches!(check_creds_writable("/nonexistent/creds"), CheckStatus::Failed(_)));
+
+    let _result = std::fs::remove_dir_all(&creds_base_path);
+}
+
+#[tokio::test]
+async fn test_check_readiness_reports_every_check() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let creds_base_path = temp_dir("health-creds");
+    let nsc_store_dir = temp_dir("health-store");
+
+    let config = ReadinessConfig {
+        creds_base_path: &creds_base_path,
+        operator_names: vec!["TestOperator"],
+        nsc_store_dir: &nsc_store_dir,
+        nats_url: "127.0.0.1:1",
+        nats_probe_creds_path: None,
+    };
+    let report = check_readiness(postgres_client, &config).await;
+
+    let checks: Vec<&str> = report.checks.iter().map(|check| check.check.as_str()).collect();
+    assert_eq!(checks, ["database", "nats", "nsc_binary", "nsc_operator_keystore", "creds_base_path_writable"]);
+    // The operator is missing from the keystore
+    assert!(!report.ready);
+    assert!(report.failed_checks().any(|check| check.check == "nsc_operator_keystore"));
+
+    let body = serde_json::to_value(&report).unwrap();
+    assert_eq!(body["checks"][0], serde_json::json!({ "check": "database", "status": "ok" }));
+
+    let _result = std::fs::remove_dir_all(&creds_base_path);
+    let _result = std::fs::remove_dir_all(&nsc_store_dir);
+}
+
+#[tokio::test]
+async fn test_skipped_nats_check_is_not_ready() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let creds_base_path = temp_dir("health-creds");
+    let nsc_store_dir = temp_dir("health-store");
+    std::fs::create_dir_all(format!("{}/TestOperator", nsc_store_dir)).unwrap();
+    std::fs::write(format!("{}/TestOperator/TestOperator.jwt", nsc_store_dir), "operator_jwt_dummy").unwrap();
+
+    let config = ReadinessConfig {
+        creds_base_path: &creds_base_path,
+        operator_names: vec!["TestOperator"],
+        nsc_store_dir: &nsc_store_dir,
+        nats_url: "127.0.0.1:1",
+        nats_probe_creds_path: None,
+    };
+    let report = check_readiness(postgres_client, &config).await;
+
+    let nats = report.checks.iter().find(|check| check.check == "nats").unwrap();
+    assert!(matches!(nats.status, CheckStatus::Skipped(_)));
+    assert!(!report.ready);
+
+    let _result = std::fs::remove_dir_all(&creds_base_path);
+    let _result = std::fs::remove_dir_all(&nsc_store_dir);
+}
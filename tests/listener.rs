@@ -0,0 +1,142 @@
+use command_notifier::{
+    error::Result,
+    listener::{
+        connect, format_notification, listen, parse_notification, sanitize_durable_name, subscribe, GroupedNotifications, ListenerConfig, NotificationSink,
+        TerminalSink,
+    },
+    nats_resolver::get_nats_url,
+    notification::Notification,
+};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Mocked notification sink, stops the listener once the expected notifications are received
+struct RecordingSink {
+    received: Vec<Notification>,
+    expected: usize,
+    stop: Arc<AtomicBool>,
+}
+
+impl NotificationSink for RecordingSink {
+    fn display(&mut self, notification: &Notification) -> Result<()> {
+        self.received.push(notification.clone());
+        if self.received.len() == self.expected {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+fn listener_config(subject: &str) -> ListenerConfig {
+    ListenerConfig {
+        nats_url: get_nats_url(),
+        creds_path: None,
+        subject: subject.to_string(),
+        durable_name: format!("test-{}", uuid::Uuid::new_v4()),
+    }
+}
+
+#[test]
+fn test_parse_notification() {
+    let notification = parse_notification(br#"{"message":"done","title":"build","host":"ci-01"}"#);
+    assert_eq!(notification.message, "done");
+    assert_eq!(notification.title.as_deref(), Some("build"));
+    assert_eq!(notification.host.as_deref(), Some("ci-01"));
+
+    // Plain text from `nats pub`
+    let notification = parse_notification(b"plain text");
+    assert_eq!(notification, Notification { message: "plain text".to_string(), ..Default::default() });
+}
+
+#[test]
+fn test_format_notification() {
+    let notification = Notification { message: "done".to_string(), title: Some("build".to_string()), host: Some("ci-01".to_string()), ..Default::default() };
+    assert_eq!(format_notification(&notification), "build (ci-01)\ndone");
+
+    // The title of notify-run is not repeated
+    let notification = Notification { message: "make succeeded\n\nstdout".to_string(), title: Some("make succeeded".to_string()), host: Some("ci-01".to_string()), ..Default::default() };
+    assert_eq!(format_notification(&notification), "[ci-01]\nmake succeeded\n\nstdout");
+
+    let notification = Notification { message: "done".to_string(), ..Default::default() };
+    assert_eq!(format_notification(&notification), "done");
+
+    let mut output = Vec::new();
+    TerminalSink::new(&mut output).display(&notification).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "done\n\n");
+}
+
+#[test]
+fn test_grouped_notifications() {
+    let running = parse_notification(br#"{"message":"Pipeline running","group":"pipeline-7","id":"6a2f41a3-c54c-4ce8-92d2-0324e1c32e22"}"#);
+    let done = parse_notification(br#"{"message":"Pipeline done","group":"pipeline-7","replaces":"6a2f41a3-c54c-4ce8-92d2-0324e1c32e22"}"#);
+
+    // The desktop notification of the first one is updated, once
+    let mut grouped = GroupedNotifications::default();
+    assert_eq!(grouped.take_replaced(&running), None);
+    grouped.shown(&running, 7);
+    assert_eq!(grouped.take_replaced(&done), Some(7));
+    assert_eq!(grouped.take_replaced(&done), None);
+
+    // Without a group nothing replaces it
+    let alone = Notification { group: None, ..running.clone() };
+    grouped.shown(&alone, 8);
+    assert_eq!(grouped.take_replaced(&done), None);
+
+    let mut output = Vec::new();
+    let mut sink = TerminalSink::new(&mut output);
+    sink.display(&running).unwrap();
+    sink.display(&done).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "Pipeline running\n\n(update) Pipeline done\n\n");
+}
+
+#[test]
+fn test_sanitize_durable_name() {
+    assert_eq!(sanitize_durable_name("notify-listen-laptop.local"), "notify-listen-laptop-local");
+    assert_eq!(sanitize_durable_name("a*b>c_d"), "a-b-c_d");
+}
+
+#[test]
+#[ignore = "requires a local nats-server"]
+fn test_listen_core_subscription() {
+    let config = listener_config(&format!("test.{}", uuid::Uuid::new_v4()));
+    let connection = connect(&config).unwrap();
+    let subscription = subscribe(&connection, &config).unwrap();
+
+    connection.publish(&config.subject, r#"{"message":"first"}"#).unwrap();
+    connection.publish(&config.subject, "second").unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut sink = RecordingSink { received: Vec::new(), expected: 2, stop: stop.clone() };
+    listen(&subscription, &mut sink, &stop).unwrap();
+
+    let messages = sink.received.iter().map(|notification| notification.message.as_str()).collect::<Vec<_>>();
+    assert_eq!(messages, ["first", "second"]);
+}
+
+#[test]
+#[ignore = "requires a local nats-server with JetStream (nats-server -js)"]
+fn test_listen_catches_up_from_jetstream() {
+    let config = listener_config(&format!("test.{}", uuid::Uuid::new_v4()));
+    let connection = connect(&config).unwrap();
+    let jetstream = nats::jetstream::new(connection.clone());
+    let stream_name = sanitize_durable_name(&config.subject);
+    jetstream.add_stream(nats::jetstream::StreamConfig {
+        name: stream_name.clone(),
+        subjects: vec![config.subject.clone()],
+        ..Default::default()
+    }).unwrap();
+
+    // Sent while the listener is offline
+    jetstream.publish(&config.subject, r#"{"message":"missed"}"#).unwrap();
+
+    let subscription = subscribe(&connection, &config).unwrap();
+    assert!(subscription.is_jetstream());
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut sink = RecordingSink { received: Vec::new(), expected: 1, stop: stop.clone() };
+    listen(&subscription, &mut sink, &stop).unwrap();
+    assert_eq!(sink.received[0].message, "missed");
+
+    jetstream.delete_stream(&stream_name).unwrap();
+}
@@ -0,0 +1,61 @@
+use axum::{body::Body, http::Request, middleware::from_fn, routing::post, Router};
+use command_notifier::{
+    metrics::{encode_metrics, metrics_middleware, record_message_sent, run_nsc, METRICS},
+    nats_publisher::publish_message,
+};
+use tower::ServiceExt;
+
+use std::process::Command;
+use std::time::Instant;
+
+mod common;
+
+use common::utils::write_test_creds;
+
+#[tokio::test]
+async fn test_http_metrics_use_the_route_pattern() {
+    let app = Router::new()
+        .route("/send/:user_id", post(|| async { "Sent to user" }))
+        .layer(from_fn(metrics_middleware));
+
+    let response = app
+        .oneshot(Request::post("/send/7c278ecc-d624-45a0-aa87-9add7253b517").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    let metrics = encode_metrics();
+    assert!(metrics.contains(r#"http_requests_total{method="POST",route="/send/:user_id",status="200"}"#), "{}", metrics);
+    assert!(!metrics.contains("7c278ecc-d624-45a0-aa87-9add7253b517"));
+}
+
+#[test]
+fn test_send_metrics_by_outcome() {
+    let sent_before = METRICS.messages_sent_total.with_label_values(&["not_found"]).get();
+
+    record_message_sent("not_found", Instant::now());
+
+    assert_eq!(METRICS.messages_sent_total.with_label_values(&["not_found"]).get(), sent_before + 1);
+    assert!(encode_metrics().contains(r#"send_duration_seconds_count{outcome="not_found"}"#));
+}
+
+#[test]
+fn test_nats_connect_failures_are_counted() {
+    let failures_before = METRICS.nats_connect_failures_total.get();
+
+    // Nothing listens on port 1
+    let creds_path = write_test_creds("metrics-nats");
+    let result = publish_message("127.0.0.1:1", &creds_path, "topic01", "done");
+
+    assert!(result.is_err());
+    assert_eq!(METRICS.nats_connect_failures_total.get(), failures_before + 1);
+}
+
+#[test]
+fn test_nsc_commands_are_timed() {
+    // Whatever the outcome (nsc may not even be installed), the duration is recorded
+    let _result = run_nsc(Command::new("nsc").arg("describe").arg("account").arg("metrics-test-missing-account"));
+
+    let metrics = encode_metrics();
+    assert!(metrics.contains(r#"nsc_command_duration_seconds_count{command="describe account""#), "{}", metrics);
+}
@@ -0,0 +1,70 @@
+use command_notifier::nats_resolver::{
+    check_claims_update_response,
+    get_account_jwt_path,
+    push_account_jwt,
+    remove_account_jwt,
+    AccountResolver
+};
+
+use std::env;
+
+#[cfg(test)]
+fn get_resolver_dir(test_name: &str) -> String {
+    let resolver_dir = env::temp_dir().join(format!("command_notifier_resolver_{}", test_name));
+    resolver_dir.to_string_lossy().to_string()
+}
+
+#[tokio::test]
+async fn test_push_account_jwt_to_dir() {
+    let resolver_dir = get_resolver_dir("push");
+    let account_resolver = AccountResolver::Directory { path: resolver_dir.clone() };
+    let account_id = "ADUMMYACCOUNTID";
+    let account_jwt = "JWT.123.456";
+
+    let result = push_account_jwt(&account_resolver, account_id, account_jwt).await;
+    assert!(result.is_ok(), "Failed to push account jwt: {:?}", result);
+
+    let jwt_path = get_account_jwt_path(&resolver_dir, account_id);
+    let content = std::fs::read_to_string(&jwt_path);
+
+    let _result = std::fs::remove_dir_all(&resolver_dir);
+
+    assert_eq!(content.unwrap(), account_jwt, "Content of the jwt file is incorrect");
+}
+
+#[tokio::test]
+async fn test_remove_account_jwt_from_dir() {
+    let resolver_dir = get_resolver_dir("remove");
+    let account_resolver = AccountResolver::Directory { path: resolver_dir.clone() };
+    let account_id = "ADUMMYACCOUNTID";
+
+    let result = push_account_jwt(&account_resolver, account_id, "JWT.123.456").await;
+    assert!(result.is_ok(), "Failed to push account jwt: {:?}", result);
+
+    let result = remove_account_jwt(&account_resolver, account_id);
+    assert!(result.is_ok(), "Failed to remove account jwt: {:?}", result);
+
+    let jwt_exists = std::path::Path::new(&get_account_jwt_path(&resolver_dir, account_id)).exists();
+
+    // Removing an account that is already gone is not an error
+    let result = remove_account_jwt(&account_resolver, account_id);
+
+    let _result = std::fs::remove_dir_all(&resolver_dir);
+
+    assert!(!jwt_exists, "Jwt file should have been removed");
+    assert!(result.is_ok(), "Removing twice should not fail: {:?}", result);
+}
+
+#[test]
+fn test_check_claims_update_response() {
+    let success = br#"{"server":{"name":"n1"},"data":{"account":"ADUMMY","code":200,"message":"jwt updated"}}"#;
+    let result = check_claims_update_response(success);
+    assert!(result.is_ok(), "Response should be a success: {:?}", result);
+
+    let failure = br#"{"server":{"name":"n1"},"error":{"account":"ADUMMY","code":500,"description":"jwt validation failed"}}"#;
+    let result = check_claims_update_response(failure);
+    assert!(result.is_err(), "Response should be an error");
+
+    let result = check_claims_update_response(b"not json");
+    assert!(result.is_err(), "Invalid response should be an error");
+}
@@ -0,0 +1,106 @@
+use axum::{extract::Path, http::HeaderMap, routing::post, Json, Router};
+use command_notifier::{
+    error::Error,
+    notification::Notification,
+    notify_run::{format_duration, load_client_config, run_command, send_notification, ClientConfig},
+};
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn test_run_command_captures_outcome() {
+    let script = "for i in 1 2 3 4 5; do echo line$i; done; echo oops >&2; exit 3";
+    let outcome = run_command("sh", &["-c".to_string(), script.to_string()], 2).unwrap();
+
+    assert_eq!(outcome.exit_code, Some(3));
+    assert!(!outcome.success());
+    assert_eq!(outcome.stdout_tail, ["line4", "line5"]);
+    assert_eq!(outcome.stderr_tail, ["oops"]);
+    assert!(outcome.command.starts_with("sh -c"));
+}
+
+#[test]
+fn test_run_missing_command() {
+    assert!(run_command("/nonexistent/command", &[], 10).is_err());
+}
+
+#[test]
+fn test_outcome_to_notification() {
+    let outcome = run_command("sh", &["-c".to_string(), "echo done".to_string()], 10).unwrap();
+    let notification = outcome.to_notification(Some("build-01".to_string()));
+
+    let title = notification.title.unwrap();
+    assert!(title.starts_with("sh -c echo done succeeded after"), "{}", title);
+    assert!(notification.message.contains("stdout (last 1 lines):\ndone"));
+    assert!(!notification.message.contains("stderr"));
+    assert_eq!(notification.host.as_deref(), Some("build-01"));
+    assert_eq!(notification.status.as_deref(), Some("success"));
+}
+
+#[test]
+fn test_format_duration() {
+    assert_eq!(format_duration(Duration::from_millis(2500)), "2.5s");
+    assert_eq!(format_duration(Duration::from_secs(192)), "3m 12s");
+    assert_eq!(format_duration(Duration::from_secs(7380)), "2h 3m");
+}
+
+#[test]
+fn test_load_client_config_from_file() {
+    // The environment takes precedence over the file
+    if env::var("NOTIFY_USER_ID").is_ok() || env::var("NOTIFY_API_KEY").is_ok() || env::var("NOTIFY_SERVER_URL").is_ok() {
+        return;
+    }
+    let config_path = env::temp_dir().join(format!("notify-run-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(&config_path, "user_id = \"7c278ecc-d624-45a0-aa87-9add7253b517\"\napi_key = \"APIKEY123\"\n").unwrap();
+
+    let config = load_client_config(Some(&config_path)).unwrap();
+    assert_eq!(config, ClientConfig {
+        server_url: "http://localhost:9090".to_string(),
+        user_id: "7c278ecc-d624-45a0-aa87-9add7253b517".to_string(),
+        api_key: "APIKEY123".to_string(),
+    });
+
+    std::fs::write(&config_path, "user_id = \"7c278ecc-d624-45a0-aa87-9add7253b517\"\n").unwrap();
+    assert!(load_client_config(Some(&config_path)).is_err());
+
+    // A config file given by path is not replaced by the defaults when missing (ex: a typo)
+    let _result = std::fs::remove_file(&config_path);
+    let result = load_client_config(Some(&config_path));
+    assert!(matches!(result, Err(Error::InvalidInput(ref message)) if message.contains("does not exist")), "{:?}", result);
+}
+
+#[tokio::test]
+async fn test_send_notification() {
+    let received = Arc::new(Mutex::new(None));
+    let app = Router::new().route("/send/:user_id", post({
+        let received = Arc::clone(&received);
+        move |Path(user_id): Path<String>, headers: HeaderMap, Json(notification): Json<Notification>| async move {
+            let api_key = headers["authorization"].to_str().unwrap().to_string();
+            *received.lock().unwrap() = Some((user_id, api_key, notification));
+            "Sent to user"
+        }
+    }));
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let config = ClientConfig {
+        server_url: format!("http://127.0.0.1:{}/", port),
+        user_id: "7c278ecc-d624-45a0-aa87-9add7253b517".to_string(),
+        api_key: "APIKEY123".to_string(),
+    };
+    let notification = Notification { message: "done".to_string(), title: Some("make succeeded".to_string()), host: None, ..Default::default() };
+    send_notification(&config, &notification).await.unwrap();
+
+    let (user_id, api_key, received_notification) = received.lock().unwrap().take().unwrap();
+    assert_eq!(user_id, config.user_id);
+    assert_eq!(api_key, "APIKEY123");
+    assert_eq!(received_notification, notification);
+
+    // Unknown route: the error of the server is reported
+    let config = ClientConfig { server_url: format!("http://127.0.0.1:{}/missing", port), ..config };
+    assert!(send_notification(&config, &notification).await.is_err());
+}
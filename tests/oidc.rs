@@ -0,0 +1,86 @@
+use command_notifier::oidc::{is_jwt, JwksSource, OidcValidator};
+use jsonwebtoken::{encode, EncodingKey, Header, Algorithm};
+use serde::Serialize;
+
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod common;
+
+use common::utils::get_user_uuid;
+
+// HS256 key, base64url of TEST_SECRET
+const TEST_SECRET: &[u8] = b"command-notifier-test-secret-0123456789";
+const TEST_JWKS: &str = r#"{"keys": [{"kty": "oct", "kid": "test-key", "alg": "HS256", "k": "Y29tbWFuZC1ub3RpZmllci10ZXN0LXNlY3JldC0wMTIzNDU2Nzg5"}]}"#;
+const TEST_ISSUER: &str = "https://example.supabase.co/auth/v1";
+const TEST_AUDIENCE: &str = "authenticated";
+
+#[derive(Serialize)]
+struct TestClaims {
+    sub: String,
+    iss: String,
+    aud: String,
+    exp: u64,
+}
+
+#[cfg(test)]
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+fn create_token(kid: &str, secret: &[u8], sub: &str, aud: &str, exp: u64) -> String {
+    let mut header = Header::new(Algorithm::HS256);
+    header.kid = Some(kid.to_string());
+    let claims = TestClaims { sub: sub.to_string(), iss: TEST_ISSUER.to_string(), aud: aud.to_string(), exp };
+    encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+}
+
+#[cfg(test)]
+async fn setup_validator(test_name: &str) -> OidcValidator {
+    let jwks_file = env::temp_dir().join(format!("command_notifier_jwks_{}.json", test_name));
+    std::fs::write(&jwks_file, TEST_JWKS).unwrap();
+    let jwks_source = JwksSource::File(jwks_file.to_string_lossy().to_string());
+    OidcValidator::new(jwks_source, Some(TEST_ISSUER.to_string()), Some(TEST_AUDIENCE.to_string()))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_validate_token() {
+    let validator = setup_validator("valid").await;
+    let user_uuid = get_user_uuid();
+
+    let token = create_token("test-key", TEST_SECRET, &user_uuid.to_string(), TEST_AUDIENCE, now() + 3600);
+    assert!(is_jwt(&token), "Token should be detected as a jwt");
+
+    let result = validator.validate_token(&token).await;
+    assert_eq!(result, Ok(user_uuid), "Token should be valid");
+}
+
+#[tokio::test]
+async fn test_validate_token_rejected() {
+    let validator = setup_validator("rejected").await;
+    let user_id = get_user_uuid().to_string();
+
+    let wrong_secret = create_token("test-key", b"another-secret-another-secret-01234", &user_id, TEST_AUDIENCE, now() + 3600);
+    assert!(validator.validate_token(&wrong_secret).await.is_err(), "Token with a wrong signature should be rejected");
+
+    let expired = create_token("test-key", TEST_SECRET, &user_id, TEST_AUDIENCE, now() - 3600);
+    assert!(validator.validate_token(&expired).await.is_err(), "Expired token should be rejected");
+
+    let wrong_audience = create_token("test-key", TEST_SECRET, &user_id, "anon", now() + 3600);
+    assert!(validator.validate_token(&wrong_audience).await.is_err(), "Token with a wrong audience should be rejected");
+
+    let unknown_kid = create_token("rotated-key", TEST_SECRET, &user_id, TEST_AUDIENCE, now() + 3600);
+    assert!(validator.validate_token(&unknown_kid).await.is_err(), "Token with an unknown key should be rejected");
+
+    let not_uuid = create_token("test-key", TEST_SECRET, "not-an-uuid", TEST_AUDIENCE, now() + 3600);
+    assert!(validator.validate_token(&not_uuid).await.is_err(), "Token whose sub is not an uuid should be rejected");
+}
+
+#[test]
+fn test_is_jwt() {
+    assert!(!is_jwt("7c278ecc-d624-45a0-aa87-9add7253b517"), "Api key should not be detected as a jwt");
+    assert!(is_jwt("header.payload.signature"), "Jwt should be detected");
+}
@@ -0,0 +1,125 @@
+use command_notifier::{
+    accounts_lifecycle::{create_and_insert_user, delete_user_everywhere, ProvisioningConfig, UserProvisioning},
+    admin::describe_account,
+    error::Error,
+    nats_resolver::AccountResolver,
+    nsc_accounts_utils::get_nsc_store_dir,
+    operators::{get_user_operator, Operators},
+    postgres::insert_nsc_user,
+};
+
+use std::env;
+use std::fs::OpenOptions;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{cleanup_postgres_user, insert_dummy_nsc_user, provisioning_config, setup_postgres_client};
+
+fn nsc_json(args: &[&str]) -> serde_json::Value {
+    let output = Command::new("nsc").args(args).arg("--json").output().unwrap();
+    assert!(output.status.success(), "nsc {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+const OPERATORS_FILE: &str = r#"
+default_operator = "production"
+
+[operators.production]
+signing_key = "OCPRODUCTIONSIGNINGKEY"
+
+[operators.staging]
+"#;
+
+#[test]
+fn test_parse_operators() {
+    let operators = Operators::parse(OPERATORS_FILE).unwrap();
+    assert_eq!(operators.names().collect::<Vec<_>>(), ["production", "staging"]);
+    assert_eq!(operators.signing_key("production").unwrap(), Some("OCPRODUCTIONSIGNINGKEY"));
+    assert_eq!(operators.signing_key("staging").unwrap(), None);
+    assert!(matches!(operators.signing_key("tenant"), Err(Error::InvalidInput(_))));
+
+    let result = Operators::parse("default_operator = \"missing\"\n[operators.staging]\n");
+    assert!(matches!(result, Err(Error::InvalidInput(_))), "{:?}", result);
+
+    let operators = Operators::single("ServerBackend", None);
+    assert_eq!(operators.default_operator, "ServerBackend");
+    assert_eq!(operators.signing_key("ServerBackend").unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_get_user_operator() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let operators = Operators::parse(OPERATORS_FILE).unwrap();
+
+    // Unknown users and the rows created before the operators get the default operator
+    assert_eq!(get_user_operator(Arc::clone(&postgres_client), &operators, Uuid::new_v4()).await.unwrap(), "production");
+    let legacy_user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(legacy_user_id).await.unwrap();
+    postgres_client.execute("UPDATE nats SET operator_name = NULL WHERE id = $1", &[&legacy_user_id]).await.unwrap();
+    assert_eq!(get_user_operator(Arc::clone(&postgres_client), &operators, legacy_user_id).await.unwrap(), "production");
+
+    let staging_user_id = Uuid::new_v4();
+    insert_nsc_user(Arc::clone(&postgres_client), staging_user_id, "nsc_account_id_dummy", "creds_admin_dummy", "creds_user_dummy", "account_jwt_dummy", "default", "staging")
+        .await
+        .unwrap();
+    assert_eq!(get_user_operator(Arc::clone(&postgres_client), &operators, staging_user_id).await.unwrap(), "staging");
+
+    // The operator was removed from the configuration
+    let result = get_user_operator(Arc::clone(&postgres_client), &Operators::single("production", None), staging_user_id).await;
+    assert!(matches!(result, Err(Error::Internal(_))), "{:?}", result);
+
+    cleanup_postgres_user(legacy_user_id).await;
+    cleanup_postgres_user(staging_user_id).await;
+}
+
+#[tokio::test]
+async fn test_provision_with_operator_signing_key() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+
+    // A signing key of the operator, its seed kept in the keystore
+    let output = Command::new("nsc").args(["edit", "operator", "--sk", "generate"]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let signing_keys = nsc_json(&["describe", "operator"])["nats"]["signing_keys"].clone();
+    let signing_key = signing_keys.as_array().unwrap().last().unwrap().as_str().unwrap().to_string();
+
+    let result = create_and_insert_user(Arc::clone(&postgres_client), &ProvisioningConfig {
+        identity_query: None,
+        signing_key: Some(&signing_key),
+        ..provisioning_config(&creds_base_path, &operator_name)
+    }, user_id).await;
+    let account = nsc_json(&["describe", "account", &user_id.to_string()]);
+
+    let report = delete_user_everywhere(Arc::clone(&postgres_client), &creds_base_path, &operator_name, &AccountResolver::External, user_id).await;
+    let _output = Command::new("nsc").args(["edit", "operator", "--rm-sk", &signing_key]).output();
+
+    assert_eq!(result, Ok(UserProvisioning::Created));
+    // The account JWT is issued by the signing key instead of the operator identity key
+    assert_eq!(account["iss"], signing_key);
+    assert!(report.is_success(), "{:?}", report);
+}
+
+#[tokio::test]
+async fn test_nsc_keystore_lock_is_shared_with_other_processes() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    // As held by the admin CLI or another server selecting its operator
+    let store_lock = OpenOptions::new().create(true).truncate(false).write(true)
+        .open(format!("{}/.provisioning.lock", get_nsc_store_dir()))
+        .unwrap();
+    store_lock.lock().unwrap();
+    let describe = describe_account(Arc::clone(&postgres_client), &creds_base_path, &operator_name, Uuid::new_v4());
+    assert!(tokio::time::timeout(Duration::from_millis(500), describe).await.is_err(), "nsc was used while the keystore was locked");
+
+    store_lock.unlock().unwrap();
+    let result = describe_account(postgres_client, &creds_base_path, &operator_name, Uuid::new_v4()).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+}
@@ -0,0 +1,96 @@
+use command_notifier::{
+    accounts_lifecycle::get_admin_creds_if_not_exists,
+    error::{Error, Service},
+    nats_publisher::{publish_message, NATS_CONNECT_TIMEOUT},
+    nats_resolver::{push_account_jwt, AccountResolver},
+    postgres::{get_creds_admin, verify_api_key, verify_nsc_user_exists},
+};
+
+use std::env;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod common;
+
+use common::utils::{get_user_uuid, setup_closed_postgres_client, write_test_creds};
+
+fn is_upstream_error(result: &Result<impl std::fmt::Debug, Error>, service: Service) -> bool {
+    matches!(result, Err(Error::Upstream(upstream, _)) if *upstream == service)
+}
+
+#[tokio::test]
+async fn test_database_outage_returns_errors() {
+    let postgres_client = Arc::new(setup_closed_postgres_client().await);
+    let user_id = get_user_uuid();
+
+    let result = verify_nsc_user_exists(Arc::clone(&postgres_client), user_id).await;
+    assert!(is_upstream_error(&result, Service::Database), "Unexpected result: {:?}", result);
+
+    let result = verify_api_key(Arc::clone(&postgres_client), user_id, "APIKEY123").await;
+    assert!(is_upstream_error(&result, Service::Database), "Unexpected result: {:?}", result);
+
+    let result = get_creds_admin(Arc::clone(&postgres_client), user_id).await;
+    assert!(is_upstream_error(&result, Service::Database), "Unexpected result: {:?}", result);
+}
+
+#[tokio::test]
+async fn test_database_outage_when_downloading_admin_creds() {
+    let postgres_client = Arc::new(setup_closed_postgres_client().await);
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let creds_base_path = env::temp_dir().join(format!("outage-creds-{}", std::process::id()));
+    let creds_base_path = creds_base_path.to_str().unwrap();
+
+    let result = get_admin_creds_if_not_exists(postgres_client, creds_base_path, &operator_name, &get_user_uuid().to_string()).await;
+
+    assert!(is_upstream_error(&result, Service::Database), "Unexpected result: {:?}", result);
+    let _result = std::fs::remove_dir_all(creds_base_path);
+}
+
+#[test]
+fn test_nats_outage_returns_error() {
+    // Nothing listens on port 1
+    let creds_path = write_test_creds("outage-nats");
+    let result = publish_message("127.0.0.1:1", &creds_path, "topic01", "done");
+
+    assert!(is_upstream_error(&result, Service::Nats), "Unexpected result: {:?}", result);
+    assert!(!format!("{:?}", result).contains("No such file"), "Unexpected result: {:?}", result);
+}
+
+#[test]
+fn test_nats_connect_times_out() {
+    // The connection is queued by the kernel but the server never sends its INFO
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let nats_url = listener.local_addr().unwrap().to_string();
+    let creds_path = write_test_creds("timeout-nats");
+
+    let started = Instant::now();
+    let result = publish_message(&nats_url, &creds_path, "topic01", "done");
+
+    assert!(is_upstream_error(&result, Service::Nats), "Unexpected result: {:?}", result);
+    assert!(started.elapsed() < NATS_CONNECT_TIMEOUT + Duration::from_secs(2));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_account_jwt_push_times_out_without_blocking() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let account_resolver = AccountResolver::Nats { nats_url: listener.local_addr().unwrap().to_string(), system_creds_path: write_test_creds("timeout-resolver") };
+
+    // The only runtime thread keeps running the other tasks during the push
+    let ticks = tokio::spawn(async {
+        let mut ticks = 0;
+        loop {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            ticks += 1;
+            if ticks == 10 {
+                return Instant::now();
+            }
+        }
+    });
+    let started = Instant::now();
+    let result = push_account_jwt(&account_resolver, "ADUMMYACCOUNTID", "JWT.123.456").await;
+
+    assert!(is_upstream_error(&result, Service::Nats), "Unexpected result: {:?}", result);
+    assert!(started.elapsed() < NATS_CONNECT_TIMEOUT + Duration::from_secs(2));
+    assert!(ticks.await.unwrap() < started + NATS_CONNECT_TIMEOUT);
+}
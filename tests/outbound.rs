@@ -0,0 +1,179 @@
+use axum::{http::StatusCode, routing::post, Router};
+use command_notifier::{
+    dedup::DedupConfig,
+    delivery_targets::{confirm_email_target, create_delivery_target, DeliveryConfig, RetryPolicy, TargetConfig},
+    email::EmailTarget,
+    error::Error,
+    notification::{Notification, Priority},
+    operators::Operators,
+    outbound::{enqueue_notification, get_dead_letters, retry_dead_letter, NatsPayload, OutboundQueueConfig, OutboundWorker, NATS_CHANNEL},
+    postgres::{claim_outbound_deliveries, delete_outbound_deliveries_of_user, insert_outbound_deliveries},
+    shutdown::{broadcast_shutdown, join_workers},
+    webhook::WebhookTarget,
+};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{cleanup_postgres_user, confirmation_token, email_sender, insert_dummy_nsc_user, setup_postgres_client, spawn_smtp_sink};
+
+// Local stand-in of a webhook, answering the given statuses in order then 200, and counting the requests
+async fn spawn_webhook(statuses: Vec<StatusCode>) -> (String, Arc<Mutex<u32>>) {
+    let requests = Arc::new(Mutex::new(0));
+    let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+    let received = Arc::clone(&requests);
+    let app = Router::new().route("/hook", post(move || {
+        let received = Arc::clone(&received);
+        let statuses = Arc::clone(&statuses);
+        async move {
+            *received.lock().unwrap() += 1;
+            statuses.lock().unwrap().next().unwrap_or(StatusCode::OK)
+        }
+    }));
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (format!("http://{}/hook", addr), requests)
+}
+
+#[test]
+fn test_nats_payload() {
+    let notification = Notification { message: "done".to_string(), title: Some("Backup".to_string()), ..Default::default() };
+    assert_eq!(NatsPayload::Json.encode(&notification).unwrap(), r#"{"message":"done","title":"Backup"}"#);
+    // As published by the first releases
+    assert_eq!(NatsPayload::Raw.encode(&notification).unwrap(), "done");
+}
+
+#[tokio::test]
+async fn test_outbound_queue_retries_and_dead_letters() {
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let creds_base_path = env::temp_dir().join(format!("outbound-creds-{}", std::process::id()));
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+
+    let (url, webhook_requests) = spawn_webhook(vec![StatusCode::INTERNAL_SERVER_ERROR]).await;
+    let webhook = WebhookTarget { url, method: "POST".to_string(), headers: BTreeMap::new(), secret: None, body_template: None };
+    create_delivery_target(Arc::clone(&postgres_client), None, user_id, &TargetConfig::Webhook(webhook)).await.unwrap();
+    // Not queued, the notification being below its minimum priority
+    let (smtp_port, emails, _data_attempts) = spawn_smtp_sink(vec![]).await;
+    let email = EmailTarget { address: "ops@example.com".to_string(), min_priority: Priority::High, subject_template: None, body_template: None };
+    let email = create_delivery_target(Arc::clone(&postgres_client), Some(&email_sender(smtp_port)), user_id, &TargetConfig::Email(email)).await.unwrap();
+    let token = confirmation_token(&emails.lock().unwrap()[0]);
+    confirm_email_target(Arc::clone(&postgres_client), user_id, email.id, &token).await.unwrap();
+
+    let notification = Notification { message: "done".to_string(), ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &notification).await, Ok(2));
+
+    // Nothing listens on port 1, the NATS delivery fails until it runs out of attempts
+    let worker = OutboundWorker {
+        postgres_client: Arc::clone(&postgres_client),
+        creds_base_path: creds_base_path.to_str().unwrap().to_string(),
+        operators: Operators::single(&operator_name, None),
+        nats_url: "127.0.0.1:1".to_string(),
+        subject: "topic01".to_string(),
+        nats_payload: NatsPayload::Json,
+        delivery: DeliveryConfig {
+            http_client: reqwest::Client::new(),
+            // The webhook runs on the loopback
+            allow_private_networks: true,
+            retry_policy: RetryPolicy { max_attempts: 2, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) },
+            email_sender: None,
+        },
+    };
+    for _ in 0..2 {
+        worker.process_due_deliveries().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(*webhook_requests.lock().unwrap(), 2);
+
+    let dead_letters = get_dead_letters(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].channel, NATS_CHANNEL);
+    assert_eq!(dead_letters[0].attempts, 2);
+    assert_eq!(dead_letters[0].notification["message"], "done");
+
+    retry_dead_letter(Arc::clone(&postgres_client), user_id, dead_letters[0].id).await.unwrap();
+    assert!(get_dead_letters(Arc::clone(&postgres_client), user_id).await.unwrap().is_empty());
+    let result = retry_dead_letter(Arc::clone(&postgres_client), user_id, dead_letters[0].id).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+
+    // The requeued delivery and the targets
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(1));
+    postgres_client.execute("DELETE FROM delivery_targets WHERE user_id = $1", &[&user_id]).await.unwrap();
+    cleanup_postgres_user(user_id).await;
+    let _result = std::fs::remove_dir_all(creds_base_path);
+}
+
+#[tokio::test]
+async fn test_claim_locks_only_the_claimed_deliveries() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_outbound_deliveries(Arc::clone(&postgres_client), user_id, &[(NATS_CHANNEL, None), (NATS_CHANNEL, None)], r#"{"message":"done"}"#, 0.0, &[]).await.unwrap();
+
+    // One at a time, the other one staying claimable
+    for _ in 0..2 {
+        let claimed = claim_outbound_deliveries(Arc::clone(&postgres_client), 1, 60_000.0).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!((claimed[0].user_id, claimed[0].attempts), (user_id, 1));
+    }
+    assert!(claim_outbound_deliveries(Arc::clone(&postgres_client), 1, 60_000.0).await.unwrap().is_empty());
+
+    assert_eq!(delete_outbound_deliveries_of_user(postgres_client, user_id).await, Ok(2));
+}
+
+#[tokio::test]
+async fn test_workers_finish_their_delivery_on_shutdown() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+
+    let requests = Arc::new(Mutex::new(0));
+    let received = Arc::clone(&requests);
+    let app = Router::new().route("/hook", post(move || {
+        let received = Arc::clone(&received);
+        async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            *received.lock().unwrap() += 1;
+            StatusCode::OK
+        }
+    }));
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let webhook = WebhookTarget { url, method: "POST".to_string(), headers: BTreeMap::new(), secret: None, body_template: None };
+    let target = create_delivery_target(Arc::clone(&postgres_client), None, user_id, &TargetConfig::Webhook(webhook)).await.unwrap();
+    insert_outbound_deliveries(Arc::clone(&postgres_client), user_id, &[("webhook", Some(target.id))], r#"{"message":"done"}"#, 0.0, &[]).await.unwrap();
+
+    let worker = OutboundWorker {
+        postgres_client: Arc::clone(&postgres_client),
+        creds_base_path: env::temp_dir().to_str().unwrap().to_string(),
+        operators: Operators::single("TestOperator", None),
+        nats_url: "127.0.0.1:1".to_string(),
+        subject: "topic01".to_string(),
+        nats_payload: NatsPayload::Json,
+        delivery: DeliveryConfig { http_client: reqwest::Client::new(), allow_private_networks: true, retry_policy: RetryPolicy::default(), email_sender: None },
+    };
+    let (signal_sender, signal_receiver) = tokio::sync::oneshot::channel::<()>();
+    let shutdown = broadcast_shutdown(async move { let _result = signal_receiver.await; });
+    let workers = worker.spawn(&OutboundQueueConfig { workers: 2, poll_interval: Duration::from_millis(50) }, shutdown);
+
+    // Signaled while the webhook is called
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    signal_sender.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), join_workers(workers, Instant::now() + Duration::from_secs(10))).await.expect("The workers should stop");
+
+    assert_eq!(*requests.lock().unwrap(), 1);
+    // Delivered, not left locked in the queue
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(0));
+    postgres_client.execute("DELETE FROM delivery_targets WHERE user_id = $1", &[&user_id]).await.unwrap();
+    cleanup_postgres_user(user_id).await;
+}
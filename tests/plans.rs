@@ -0,0 +1,109 @@
+use command_notifier::{
+    accounts_lifecycle::{create_and_insert_user, ProvisioningConfig, delete_user_everywhere},
+    error::Error,
+    nats_resolver::AccountResolver,
+    nsc_accounts_utils::AccountLimits,
+    plans::{change_account_plan, Plans, DEFAULT_PLAN_NAME},
+    postgres::get_nsc_user_record,
+};
+
+use std::env;
+use std::process::Command;
+use std::sync::Arc;
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{provisioning_config, setup_postgres_client};
+
+const PLANS_FILE: &str = r#"
+default_plan = "free"
+
+[plans.free]
+max_connections = 5
+max_subscriptions = 100
+max_payload = 65536
+
+[plans.pro]
+max_connections = 50
+jetstream_storage = "1G"
+"#;
+
+#[test]
+fn test_parse_plans() {
+    let plans = Plans::parse(PLANS_FILE).unwrap();
+    assert_eq!(plans.default_limits().max_connections, Some(5));
+    assert_eq!(plans.get("pro").unwrap().jetstream_storage.as_deref(), Some("1G"));
+    assert!(matches!(plans.get("enterprise"), Err(Error::InvalidInput(_))));
+
+    let result = Plans::parse("default_plan = \"missing\"\n[plans.free]\n");
+    assert!(matches!(result, Err(Error::InvalidInput(_))), "{:?}", result);
+    let result = Plans::parse("default_plan = \"free\"\n[plans.free]\nmax_connections = \"many\"\n");
+    assert!(matches!(result, Err(Error::InvalidInput(_))), "{:?}", result);
+
+    assert_eq!(Plans::default().default_plan, DEFAULT_PLAN_NAME);
+    assert_eq!(*Plans::default().default_limits(), AccountLimits::default());
+}
+
+#[test]
+fn test_edit_account_args() {
+    let plans = Plans::parse(PLANS_FILE).unwrap();
+    assert_eq!(
+        plans.get("pro").unwrap().edit_account_args(),
+        ["--conns", "50", "--subscriptions", "-1", "--payload", "-1", "--js-disk-storage", "1G"]
+    );
+    assert_eq!(
+        AccountLimits::default().edit_account_args(),
+        ["--conns", "-1", "--subscriptions", "-1", "--payload", "-1", "--js-disk-storage", "-1"]
+    );
+}
+
+// Limits signed in the account JWT
+fn describe_account_limits(account_name: &str) -> serde_json::Value {
+    let output = Command::new("nsc").args(["describe", "account", account_name, "--json"]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let account: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    account["nats"]["limits"].clone()
+}
+
+#[tokio::test]
+async fn test_change_account_plan() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let plans = Plans::parse(PLANS_FILE).unwrap();
+    let user_id = Uuid::new_v4();
+
+    create_and_insert_user(Arc::clone(&postgres_client), &ProvisioningConfig {
+        identity_query: None,
+        plans: &plans,
+        ..provisioning_config(&creds_base_path, &operator_name)
+    }, user_id)
+        .await
+        .unwrap();
+    let record = get_nsc_user_record(Arc::clone(&postgres_client), user_id).await.unwrap().unwrap();
+    assert_eq!(record.plan.as_deref(), Some("free"));
+
+    let limits = describe_account_limits(&user_id.to_string());
+    assert_eq!((limits["conn"].as_i64(), limits["subs"].as_i64(), limits["payload"].as_i64()), (Some(5), Some(100), Some(65536)));
+    // JetStream stays enabled without a storage limit
+    assert_eq!(limits["disk_storage"].as_i64(), Some(-1));
+
+    let plan_change = change_account_plan(Arc::clone(&postgres_client), &operator_name, None, &AccountResolver::External, &plans, user_id, "pro").await.unwrap();
+    assert_eq!(plan_change.limits.max_connections, Some(50));
+    let record = get_nsc_user_record(Arc::clone(&postgres_client), user_id).await.unwrap().unwrap();
+    assert_eq!(record.plan.as_deref(), Some("pro"));
+
+    // The account JWT is signed again with the limits of the new plan, the previous ones removed
+    let limits = describe_account_limits(&user_id.to_string());
+    assert_eq!((limits["conn"].as_i64(), limits["subs"].as_i64(), limits["payload"].as_i64()), (Some(50), Some(-1), Some(-1)));
+    assert!(limits["disk_storage"].as_i64().unwrap() > 0, "{}", limits);
+
+    let result = change_account_plan(Arc::clone(&postgres_client), &operator_name, None, &AccountResolver::External, &plans, user_id, "enterprise").await;
+    assert!(matches!(result, Err(Error::InvalidInput(_))), "{:?}", result);
+    let result = change_account_plan(Arc::clone(&postgres_client), &operator_name, None, &AccountResolver::External, &plans, Uuid::new_v4(), "pro").await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+
+    let report = delete_user_everywhere(postgres_client, &creds_base_path, &operator_name, &AccountResolver::External, user_id).await;
+    assert!(report.is_success(), "{:?}", report);
+}
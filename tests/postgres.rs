@@ -1,13 +1,17 @@
 use command_notifier::postgres::{
     delete_nsc_user_from_postgres,
     get_creds_admin,
+    get_identity_query,
+    get_nsc_account_id,
     insert_nsc_user,
     update_account_jwt,
     update_creds_admin,
     update_creds_user,
+    verify_identity_exists,
     verify_nsc_user_exists,
     add_api_key,
     delete_api_key,
+    delete_api_keys_of_user,
     verify_api_key
 };
 
@@ -31,7 +35,7 @@ async fn test_delete_api_key() {
     let query_result = postgres_client.query("SELECT id FROM api_keys WHERE user_id = $1", &[&user_id])
         .await
         .unwrap();
-    let row = query_result.get(0);
+    let row = query_result.first();
 
     assert!(row.is_some(), "Api key should exist");
 
@@ -42,7 +46,7 @@ async fn test_delete_api_key() {
     let query_result = postgres_client.query("SELECT id FROM api_keys WHERE user_id = $1", &[&user_id])
         .await
         .unwrap();
-    let row = query_result.get(0);
+    let row = query_result.first();
 
     assert!(row.is_none(), "Api key should not exist");
 
@@ -64,14 +68,14 @@ async fn test_verify_api_key() {
     let result_test = tokio::spawn(async move {
         let result = verify_api_key(Arc::clone(&postgres_client), user_id, api_key_value).await;
         assert!(result.is_ok(), "Failed to verify api key: {:?}", result);
-        assert!(result.unwrap() == true, "Api key verifiction should be successfull");
+        assert!(result.unwrap(), "Api key verifiction should be successfull");
     }).await;
 
     // Cleanup
     let query_result = postgres_client_two.query("SELECT id FROM api_keys WHERE user_id = $1", &[&user_id])
         .await
         .unwrap();
-    let row = query_result.get(0);
+    let row = query_result.first();
     let api_key_id: Uuid = row.unwrap().get(0);
     let result_deletion = delete_api_key(Arc::clone(&postgres_client_three), api_key_id).await;
 
@@ -106,7 +110,7 @@ async fn test_add_multiple_apis() {
     }
 
     // Cleanup
-    let mut results: Vec<Result<bool, tokio_postgres::Error>> = Vec::new();
+    let mut results: Vec<command_notifier::error::Result<bool>> = Vec::new();
     for row in query_result.iter() {
         let api_key_id: Uuid = row.get(0);
         let result = delete_api_key(Arc::clone(&postgres_client), api_key_id).await;
@@ -132,8 +136,8 @@ async fn test_add_one_api() {
     let query_result = postgres_client.query("SELECT id, api_key_hash FROM api_keys WHERE user_id = $1", &[&user_id])
         .await
         .unwrap();
-    assert!(query_result.len() > 0, "No api key found");
-    let row = query_result.get(0);
+    assert!(!query_result.is_empty(), "No api key found");
+    let row = query_result.first();
     let api_key_id: Uuid = row.unwrap().get(0);
     let api_key_hash: String = row.unwrap().get(1);
 
@@ -187,15 +191,15 @@ async fn test_insert_nsc_user() {
         let creds_admin = "A12345";
         let creds_user = "U12345";
         let account_jwt = "JWT.123.456";
-        let result = insert_nsc_user(Arc::clone(&postgres_client), uuid, nsc_account_id, creds_admin, creds_user, account_jwt).await;
+        let result = insert_nsc_user(Arc::clone(&postgres_client), uuid, nsc_account_id, creds_admin, creds_user, account_jwt, "default", "ServerBackend").await;
         assert!(result.is_ok(), "Failed to insert user: {:?}", result);
-        assert!(result.unwrap() == true, "User should have been inserted");
+        assert!(result.unwrap(), "User should have been inserted");
 
         let rows = Arc::clone(&postgres_client).query("SELECT creds_admin, creds_user, account_jwt FROM nats WHERE id = $1", &[&uuid])
             .await.unwrap();
-        assert!(rows.len() > 0, "User should exist");
+        assert!(!rows.is_empty(), "User should exist");
         // Verify that fields are correct
-        let row = rows.get(0);
+        let row = rows.first();
         let creds_admin_db: String = row.unwrap().get(0);
         let creds_user_db: String = row.unwrap().get(1);
         let account_jwt_db: String = row.unwrap().get(2);
@@ -223,11 +227,11 @@ async fn test_delete_nsc_user() {
 
         let result = delete_nsc_user_from_postgres(Arc::clone(&postgres_client), uuid).await;
         assert!(result.is_ok(), "Failed to delete user: {:?}", result);
-        assert!(result.unwrap() == true, "User should have been deleted");
+        assert!(result.unwrap(), "User should have been deleted");
 
         let rows = Arc::clone(&postgres_client).query("SELECT * FROM nats WHERE id = $1", &[&uuid])
         .await.unwrap();
-        assert!(rows.len() == 0, "User should not exist")
+        assert!(rows.is_empty(), "User should not exist")
     }).await;
 
     assert!(result.is_ok(), "Failed to delete user: {:?}", result);
@@ -247,11 +251,11 @@ async fn test_update_creds_admin() {
         let creds_admin = "A12345";
         let result = update_creds_admin(Arc::clone(&postgres_client), uuid, creds_admin).await;
         assert!(result.is_ok(), "Failed to update creds_admin: {:?}", result);
-        assert!(result.unwrap() == true, "Creds_admin should have been updated");
+        assert!(result.unwrap(), "Creds_admin should have been updated");
 
         let rows = Arc::clone(&postgres_client).query("SELECT creds_admin FROM nats WHERE id = $1", &[&uuid])
         .await.unwrap();
-        let row = rows.get(0);
+        let row = rows.first();
         let creds_admin_db: String = row.unwrap().get(0);
         assert_eq!(creds_admin_db, creds_admin, "Creds_admin should have been updated");
     }).await;
@@ -275,11 +279,11 @@ async fn test_update_creds_user() {
         let creds_user = "U12345";
         let result = update_creds_user(Arc::clone(&postgres_client), uuid, creds_user).await;
         assert!(result.is_ok(), "Failed to update creds_user: {:?}", result);
-        assert!(result.unwrap() == true, "Creds_user should have been updated");
+        assert!(result.unwrap(), "Creds_user should have been updated");
 
         let rows =Arc::clone(&postgres_client).query("SELECT creds_user FROM nats WHERE id = $1", &[&uuid])
         .await.unwrap();
-        let row = rows.get(0);
+        let row = rows.first();
         let creds_user_db: String = row.unwrap().get(0);
         assert_eq!(creds_user_db, creds_user, "Creds_user should have been updated");
     }).await;
@@ -303,11 +307,11 @@ async fn test_get_account_jwt() {
         let account_jwt = "JWT12345";
         let result = update_account_jwt(Arc::clone(&postgres_client), uuid, account_jwt).await;
         assert!(result.is_ok(), "Failed to update account_jwt: {:?}", result);
-        assert!(result.unwrap() == true, "Account_jwt should have been updated");
+        assert!(result.unwrap(), "Account_jwt should have been updated");
 
         let rows = Arc::clone(&postgres_client).query("SELECT account_jwt FROM nats WHERE id = $1", &[&uuid])
             .await.unwrap();
-        let row = rows.get(0);
+        let row = rows.first();
         let account_jwt_db: String = row.unwrap().get(0);
         assert_eq!(account_jwt_db, account_jwt, "Account_jwt should have been updated");
     }).await;
@@ -325,7 +329,7 @@ async fn test_check_user_well_not_exists() {
     let uuid =  Uuid::parse_str("6f422cbc-b2d5-43eb-b61a-9c7c892d2eb2").unwrap();
     let result = verify_nsc_user_exists(Arc::new(postgres_client), uuid).await;
     assert!(result.is_ok(), "Failed to verify user exists: {:?}", result);
-    assert!(result.unwrap() == false, "User should not exist");
+    assert!(!result.unwrap(), "User should not exist");
 }
 
 #[tokio::test]
@@ -338,8 +342,70 @@ async fn test_check_user_well_exists() {
         assert!(result.is_ok(), "Failed to insert user: {:?}", result);
         let result = verify_nsc_user_exists(Arc::new(postgres_client), uuid).await;
         assert!(result.is_ok(), "Failed to verify user exists: {:?}", result);
-        assert!(result.unwrap() == true, "User should exist");
+        assert!(result.unwrap(), "User should exist");
     }).await;
     cleanup_postgres_user(uuid).await;
     assert!(result.is_ok(), "Failed the test: {:?}", result);
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_get_nsc_account_id() {
+    let postgres_client = setup_postgres_client().await;
+    let postgres_client = Arc::new(postgres_client);
+
+    let uuid = get_user_uuid();
+    cleanup_postgres_user(uuid).await;
+
+    let result = tokio::spawn(async move {
+        let result = get_nsc_account_id(Arc::clone(&postgres_client), uuid).await;
+        assert!(result.is_ok(), "Failed to get nsc_account_id: {:?}", result);
+        assert!(result.unwrap().is_none(), "Nsc_account_id should not exist yet");
+
+        let _result = insert_dummy_nsc_user(uuid).await;
+
+        let result = get_nsc_account_id(Arc::clone(&postgres_client), uuid).await;
+        assert!(result.is_ok(), "Failed to get nsc_account_id: {:?}", result);
+        assert_eq!(result.unwrap().as_deref(), Some("nsc_account_id_dummy"), "Nsc_account_id is incorrect");
+    }).await;
+
+    cleanup_postgres_user(uuid).await;
+
+    assert!(result.is_ok(), "Failed the test: {:?}", result);
+}
+
+#[tokio::test]
+async fn test_delete_api_keys_of_user() {
+    let postgres_client = setup_postgres_client().await;
+    let postgres_client = Arc::new(postgres_client);
+    let user_id = get_user_uuid();
+
+    for _ in 0..2 {
+        let result = add_api_key(Arc::clone(&postgres_client), user_id, "APIKEY123").await;
+        assert!(result.is_ok(), "Failed to add api key: {:?}", result);
+    }
+
+    let result = delete_api_keys_of_user(Arc::clone(&postgres_client), user_id).await;
+    assert!(result.is_ok(), "Failed to delete api keys: {:?}", result);
+    assert_eq!(result.unwrap(), 2, "Two api keys should have been deleted");
+
+    let query_result = postgres_client.query("SELECT id FROM api_keys WHERE user_id = $1", &[&user_id])
+        .await
+        .unwrap();
+    assert!(query_result.is_empty(), "Api keys should not exist");
+}
+
+#[tokio::test]
+async fn test_verify_identity_exists() {
+    let postgres_client = setup_postgres_client().await;
+    let postgres_client = Arc::new(postgres_client);
+    let identity_query = get_identity_query().expect("Identity check should be enabled");
+
+    let result = verify_identity_exists(Arc::clone(&postgres_client), &identity_query, get_user_uuid()).await;
+    assert!(result.is_ok(), "Failed to verify identity: {:?}", result);
+    assert!(result.unwrap(), "Test user should exist in the auth table");
+
+    let unknown_uuid = Uuid::parse_str("0e9a2f0e-5d2b-4c1e-9a55-3c3f4c2f8d11").unwrap();
+    let result = verify_identity_exists(Arc::clone(&postgres_client), &identity_query, unknown_uuid).await;
+    assert!(result.is_ok(), "Failed to verify identity: {:?}", result);
+    assert!(!result.unwrap(), "Unknown uuid should not exist in the auth table");
+}
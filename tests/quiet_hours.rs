@@ -0,0 +1,157 @@
+use command_notifier::{
+    dedup::DedupConfig,
+    error::Error,
+    notification::{Notification, Priority},
+    outbound::enqueue_notification,
+    postgres::{delete_outbound_deliveries_of_user, delete_quiet_hours_of_user, delete_routing_of_user},
+    quiet_hours::{build_digest, get_quiet_hours, remove_quiet_hours, set_quiet_hours, DigestScheduler, DigestSchedulerConfig, QuietHours, DIGEST_CHANNEL},
+    shutdown::broadcast_shutdown,
+    routing::{get_history, Schedule, Weekday},
+};
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{cleanup_postgres_user, insert_dummy_nsc_user, setup_postgres_client};
+
+// Window in UTC from the given minutes around now
+fn utc_window(from_minutes: i64, to_minutes: i64) -> QuietHours {
+    let now = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 60 % 1440) as i64;
+    let time = |offset: i64| {
+        let minute = (now + offset).rem_euclid(1440);
+        format!("{:02}:{:02}", minute / 60, minute % 60)
+    };
+    QuietHours { timezone: "UTC".to_string(), schedule: Schedule { start: time(from_minutes), end: time(to_minutes), days: Vec::new() } }
+}
+
+async fn held_count(postgres_client: &tokio_postgres::Client, user_id: Uuid) -> i64 {
+    postgres_client.query_one("SELECT count(*) FROM held_notifications WHERE user_id = $1", &[&user_id]).await.unwrap().get(0)
+}
+
+#[test]
+fn test_schedule_in_local_time() {
+    let schedule = Schedule { start: "22:00".to_string(), end: "07:00".to_string(), days: vec![Weekday::Fri] };
+    assert_eq!(schedule.remaining_at(Weekday::from_iso(5).unwrap(), 23 * 3600), Some(Duration::from_secs(8 * 3600)));
+    assert_eq!(schedule.remaining_at(Weekday::Sat, 6 * 3600 + 30), Some(Duration::from_secs(3600 - 30)));
+    assert_eq!(schedule.remaining_at(Weekday::Sat, 23 * 3600), None);
+    assert_eq!(Weekday::from_iso(8), None);
+}
+
+#[test]
+fn test_build_digest() {
+    let notifications = [
+        Notification { message: "Backup done\n12 GB".to_string(), host: Some("db-01".to_string()), ..Default::default() },
+        Notification { message: "Build failed".to_string(), title: Some("CI".to_string()), priority: Priority::High, ..Default::default() },
+        Notification { message: "Disk at 80%".to_string(), priority: Priority::Low, ..Default::default() },
+    ];
+    let digest = build_digest(&notifications);
+    assert_eq!(digest.title.as_deref(), Some("3 notifications during quiet hours"));
+    assert_eq!(digest.message, "- Backup done (db-01)\n- CI: Build failed\n- Disk at 80%");
+    assert_eq!(digest.channel.as_deref(), Some(DIGEST_CHANNEL));
+    assert_eq!(digest.priority, Priority::High);
+
+    let many = vec![Notification { message: "Ping".to_string(), ..Default::default() }; 52];
+    let digest = build_digest(&many);
+    assert_eq!(digest.message.lines().count(), 51);
+    assert!(digest.message.ends_with("... and 2 more"), "{}", digest.message);
+}
+
+#[tokio::test]
+async fn test_quiet_hours_hold_and_digest() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+
+    let result = set_quiet_hours(Arc::clone(&postgres_client), user_id, &utc_window(-60, 60)).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+    insert_dummy_nsc_user(user_id).await.unwrap();
+    let unknown_timezone = QuietHours { timezone: "Mars/Olympus_Mons".to_string(), ..utc_window(-60, 60) };
+    let result = set_quiet_hours(Arc::clone(&postgres_client), user_id, &unknown_timezone).await;
+    assert!(matches!(result, Err(Error::InvalidInput(_))), "{:?}", result);
+
+    set_quiet_hours(Arc::clone(&postgres_client), user_id, &utc_window(-60, 60)).await.unwrap();
+    assert_eq!(get_quiet_hours(Arc::clone(&postgres_client), user_id).await, Ok(Some(utc_window(-60, 60))));
+
+    let notification = Notification { message: "Backup done".to_string(), ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &notification).await, Ok(0));
+    let failed = Notification { message: "Build failed".to_string(), priority: Priority::High, ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &failed).await, Ok(0));
+    let urgent = Notification { message: "Server down".to_string(), priority: Priority::Urgent, ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &urgent).await, Ok(1));
+    let history = get_history(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(history.iter().filter(|entry| entry.action == "held").count(), 2);
+
+    // Still in the quiet hours
+    let scheduler = DigestScheduler { postgres_client: Arc::clone(&postgres_client), dedup: DedupConfig::default() };
+    scheduler.send_due_digests().await.unwrap();
+    assert_eq!(held_count(&postgres_client, user_id).await, 2);
+
+    set_quiet_hours(Arc::clone(&postgres_client), user_id, &utc_window(60, 120)).await.unwrap();
+    assert!(scheduler.send_due_digests().await.unwrap() >= 1);
+    assert_eq!(held_count(&postgres_client, user_id).await, 0);
+    let rows = postgres_client.query("SELECT notification FROM outbound_deliveries WHERE user_id = $1", &[&user_id]).await.unwrap();
+    let queued: Vec<Notification> = rows.iter().map(|row| serde_json::from_str(row.get(0)).unwrap()).collect();
+    let digest = queued.iter().find(|notification| notification.channel.as_deref() == Some(DIGEST_CHANNEL)).unwrap();
+    assert_eq!(digest.title.as_deref(), Some("2 notifications during quiet hours"));
+    assert_eq!(digest.message, "- Backup done\n- Build failed");
+
+    remove_quiet_hours(Arc::clone(&postgres_client), user_id).await.unwrap();
+    let result = remove_quiet_hours(Arc::clone(&postgres_client), user_id).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+
+    // The urgent notification and the digest, then their 4 history entries
+    assert_eq!(delete_quiet_hours_of_user(Arc::clone(&postgres_client), user_id).await, Ok(0));
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(2));
+    assert_eq!(delete_routing_of_user(Arc::clone(&postgres_client), user_id).await, Ok(4));
+    cleanup_postgres_user(user_id).await;
+}
+
+#[tokio::test]
+async fn test_digest_scheduler_stops_on_shutdown() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let (signal_sender, signal_receiver) = tokio::sync::oneshot::channel::<()>();
+    let shutdown = broadcast_shutdown(async move { let _result = signal_receiver.await; });
+    let scheduler = DigestScheduler { postgres_client, dedup: DedupConfig::default() }.spawn(&DigestSchedulerConfig { interval: Duration::from_secs(3600) }, shutdown);
+
+    // Without waiting for the next look
+    signal_sender.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(2), scheduler).await.expect("The scheduler should stop").unwrap();
+}
+
+#[tokio::test]
+async fn test_failed_release_does_not_send_the_digest() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+    set_quiet_hours(Arc::clone(&postgres_client), user_id, &utc_window(-60, 60)).await.unwrap();
+    for message in ["Backup done", "Build done"] {
+        let notification = Notification { message: message.to_string(), ..Default::default() };
+        assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &notification).await, Ok(0));
+    }
+    set_quiet_hours(Arc::clone(&postgres_client), user_id, &utc_window(60, 120)).await.unwrap();
+
+    // The deletion of the held notifications of this user fails
+    let trigger = format!("fail_held_deletion_{}", user_id.simple());
+    postgres_client.batch_execute(&format!(
+        "CREATE FUNCTION {trigger}() RETURNS trigger LANGUAGE plpgsql AS $$ BEGIN RAISE EXCEPTION 'deletion failed'; END $$; \
+        CREATE TRIGGER {trigger} BEFORE DELETE ON held_notifications FOR EACH ROW WHEN (OLD.user_id = '{user_id}') EXECUTE FUNCTION {trigger}();"))
+        .await.unwrap();
+    let scheduler = DigestScheduler { postgres_client: Arc::clone(&postgres_client), dedup: DedupConfig::default() };
+    let result = scheduler.send_due_digests().await;
+    postgres_client.batch_execute(&format!("DROP TRIGGER {trigger} ON held_notifications; DROP FUNCTION {trigger}();")).await.unwrap();
+    assert!(matches!(result, Ok(0)), "{:?}", result);
+    assert_eq!(held_count(&postgres_client, user_id).await, 2);
+
+    // Once the claim expires, the digest is queued once
+    postgres_client.execute("UPDATE held_notifications SET claimed_until = NULL WHERE user_id = $1", &[&user_id]).await.unwrap();
+    assert!(scheduler.send_due_digests().await.unwrap() >= 1);
+    assert_eq!(held_count(&postgres_client, user_id).await, 0);
+
+    // The digest, then the settings, and the history of the 2 notifications and of the 2 attempts of the digest
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(1));
+    assert_eq!(delete_quiet_hours_of_user(Arc::clone(&postgres_client), user_id).await, Ok(1));
+    assert_eq!(delete_routing_of_user(Arc::clone(&postgres_client), user_id).await, Ok(4));
+    cleanup_postgres_user(user_id).await;
+}
@@ -0,0 +1,218 @@
+use command_notifier::nats_resolver::AccountResolver;
+use command_notifier::nsc_accounts_utils::{create_nsc_account, get_account_creds_dir, get_creds_path, get_nsc_store_dir};
+use command_notifier::postgres::{setup_postgres_client, update_creds_admin, update_creds_user};
+use command_notifier::reconcile::{reconcile, AccountDrift, DriftKind, ReconcileConfig, RepairStatus};
+
+use std::env;
+use std::sync::Arc;
+
+mod common;
+
+use common::utils::{cleanup_nsc_account, cleanup_postgres_user, get_user_uuid, insert_dummy_nsc_user};
+
+// The keystore and the creds directory are faked with plain directories, so that no nsc call is needed
+
+#[cfg(test)]
+fn setup_dirs(test_name: &str) -> (String, String) {
+    let base_dir = env::temp_dir().join(format!("command_notifier_reconcile_{}", test_name));
+    let _result = std::fs::remove_dir_all(&base_dir);
+    let nsc_store_dir = base_dir.join("stores").to_string_lossy().to_string();
+    let creds_base_path = base_dir.join("creds").to_string_lossy().to_string();
+    (nsc_store_dir, creds_base_path)
+}
+
+#[cfg(test)]
+fn add_fake_nsc_account(nsc_store_dir: &str, operator_name: &str, account_name: &str) {
+    std::fs::create_dir_all(format!("{}/{}/accounts/{}", nsc_store_dir, operator_name, account_name)).unwrap();
+}
+
+#[cfg(test)]
+fn find_drift<'a>(drifts: &'a [AccountDrift], account_name: &str) -> Option<&'a AccountDrift> {
+    drifts.iter().find(|drift| drift.account_name == account_name)
+}
+
+#[tokio::test]
+async fn test_reconcile_missing_nsc_account() {
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let (nsc_store_dir, creds_base_path) = setup_dirs("missing_nsc_account");
+    let username = get_user_uuid();
+    let account_name = username.to_string();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_postgres_user(username).await;
+    std::fs::create_dir_all(format!("{}/{}/accounts", nsc_store_dir, operator_name)).unwrap();
+
+    let result = tokio::spawn(async move {
+        let result = insert_dummy_nsc_user(username).await;
+        assert!(result.is_ok(), "Failed to insert user: {:?}", result);
+
+        let config = ReconcileConfig {
+            creds_base_path: &creds_base_path,
+            operator_name: &operator_name,
+            default_operator: &operator_name,
+            nsc_store_dir: &nsc_store_dir,
+            account_resolver: &AccountResolver::External,
+        };
+
+        let drifts = reconcile(Arc::clone(&postgres_client), &config, false).await.unwrap();
+        let drift = find_drift(&drifts, &account_name).expect("Drift should be detected");
+        assert_eq!(drift.kind, DriftKind::MissingNscAccount, "Drift kind is incorrect");
+        assert!(drift.repair.is_none(), "Nothing should be repaired");
+
+        let drifts = reconcile(Arc::clone(&postgres_client), &config, true).await.unwrap();
+        let drift = find_drift(&drifts, &account_name).expect("Drift should be detected");
+        assert_eq!(drift.repair, Some(RepairStatus::Repaired), "Drift should be repaired");
+
+        let rows = postgres_client.query("SELECT broken_reason FROM nats WHERE id = $1", &[&username])
+            .await
+            .unwrap();
+        let broken_reason: Option<String> = rows[0].get(0);
+        assert!(broken_reason.is_some(), "User should be marked as broken");
+    }).await;
+
+    cleanup_postgres_user(username).await;
+
+    assert!(result.is_ok(), "Test failed");
+}
+
+#[tokio::test]
+async fn test_reconcile_missing_creds() {
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let (nsc_store_dir, creds_base_path) = setup_dirs("missing_creds");
+    let username = get_user_uuid();
+    let account_name = username.to_string();
+    let creds_admin = "creds_admin_from_database";
+    let creds_user = "creds_user_from_database";
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_postgres_user(username).await;
+    add_fake_nsc_account(&nsc_store_dir, &operator_name, &account_name);
+
+    let result = tokio::spawn(async move {
+        let _result = insert_dummy_nsc_user(username).await;
+        let _result = update_creds_admin(Arc::clone(&postgres_client), username, creds_admin).await;
+        let _result = update_creds_user(Arc::clone(&postgres_client), username, creds_user).await;
+
+        let config = ReconcileConfig {
+            creds_base_path: &creds_base_path,
+            operator_name: &operator_name,
+            default_operator: &operator_name,
+            nsc_store_dir: &nsc_store_dir,
+            account_resolver: &AccountResolver::External,
+        };
+
+        let drifts = reconcile(Arc::clone(&postgres_client), &config, true).await.unwrap();
+        let drift = find_drift(&drifts, &account_name).expect("Drift should be detected");
+        assert_eq!(drift.kind, DriftKind::MissingCreds, "Drift kind is incorrect");
+        assert_eq!(drift.repair, Some(RepairStatus::Repaired), "Drift should be repaired");
+
+        let content = std::fs::read_to_string(get_creds_path(&creds_base_path, &operator_name, &account_name, "admin_01")).unwrap();
+        assert_eq!(content, creds_admin, "Admin creds should come from the database");
+        let content = std::fs::read_to_string(get_creds_path(&creds_base_path, &operator_name, &account_name, "user_01")).unwrap();
+        assert_eq!(content, creds_user, "User creds should come from the database");
+
+        let drifts = reconcile(Arc::clone(&postgres_client), &config, false).await.unwrap();
+        assert!(find_drift(&drifts, &account_name).is_none(), "No drift should be left");
+    }).await;
+
+    cleanup_postgres_user(username).await;
+
+    assert!(result.is_ok(), "Test failed");
+}
+
+#[tokio::test]
+async fn test_reconcile_orphaned_creds() {
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let (nsc_store_dir, creds_base_path) = setup_dirs("orphaned_creds");
+    let username = get_user_uuid();
+    let account_name = username.to_string();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_postgres_user(username).await;
+    std::fs::create_dir_all(format!("{}/{}/accounts/SYS", nsc_store_dir, operator_name)).unwrap();
+    let creds_dir = get_account_creds_dir(&creds_base_path, &operator_name, &account_name);
+    std::fs::create_dir_all(&creds_dir).unwrap();
+    std::fs::write(get_creds_path(&creds_base_path, &operator_name, &account_name, "user_01"), "creds").unwrap();
+
+    let config = ReconcileConfig {
+        creds_base_path: &creds_base_path,
+        operator_name: &operator_name,
+        default_operator: &operator_name,
+        nsc_store_dir: &nsc_store_dir,
+        account_resolver: &AccountResolver::External,
+    };
+
+    let drifts = reconcile(Arc::clone(&postgres_client), &config, true).await.unwrap();
+
+    assert!(find_drift(&drifts, "SYS").is_none(), "Accounts not named after an user should be ignored");
+    let drift = find_drift(&drifts, &account_name).expect("Drift should be detected");
+    assert_eq!(drift.kind, DriftKind::OrphanedCreds, "Drift kind is incorrect");
+    assert_eq!(drift.repair, Some(RepairStatus::Repaired), "Drift should be repaired");
+    assert!(!std::path::Path::new(&creds_dir).exists(), "Creds directory should have been removed");
+}
+
+#[tokio::test]
+async fn test_reconcile_orphaned_nsc_account() {
+    let creds_base_path = env::var("CREDS_BASE_PATH").expect("CREDS_BASE_PATH must be set");
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let nsc_store_dir = get_nsc_store_dir();
+    let username = get_user_uuid();
+    let account_name = username.to_string();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_postgres_user(username).await;
+    cleanup_nsc_account(&account_name);
+
+    let result = create_nsc_account(&account_name);
+    assert!(result.is_ok(), "Failed to create nsc account: {:?}", result);
+
+    let config = ReconcileConfig {
+        creds_base_path: &creds_base_path,
+        operator_name: &operator_name,
+        default_operator: &operator_name,
+        nsc_store_dir: &nsc_store_dir,
+        account_resolver: &AccountResolver::External,
+    };
+
+    let drifts = reconcile(Arc::clone(&postgres_client), &config, true).await;
+
+    cleanup_nsc_account(&account_name);
+
+    let drifts = drifts.unwrap();
+    let drift = find_drift(&drifts, &account_name).expect("Drift should be detected");
+    assert_eq!(drift.kind, DriftKind::OrphanedNscAccount, "Drift kind is incorrect");
+    assert_eq!(drift.repair, Some(RepairStatus::Repaired), "Drift should be repaired");
+}
+
+#[tokio::test]
+async fn test_reconcile_ignores_other_operators() {
+    let operator_name = env::var("TEST_OPERATOR_NAME").expect("TEST_OPERATOR_NAME must be set");
+    let (nsc_store_dir, creds_base_path) = setup_dirs("other_operators");
+    let username = get_user_uuid();
+    let account_name = username.to_string();
+
+    let postgres_client = Arc::new(setup_postgres_client().await);
+
+    cleanup_postgres_user(username).await;
+    std::fs::create_dir_all(format!("{}/{}/accounts", nsc_store_dir, operator_name)).unwrap();
+    insert_dummy_nsc_user(username).await.unwrap();
+    postgres_client.execute("UPDATE nats SET operator_name = 'OtherOperator' WHERE id = $1", &[&username]).await.unwrap();
+
+    let config = ReconcileConfig {
+        creds_base_path: &creds_base_path,
+        operator_name: &operator_name,
+        default_operator: &operator_name,
+        nsc_store_dir: &nsc_store_dir,
+        account_resolver: &AccountResolver::External,
+    };
+    let drifts = reconcile(Arc::clone(&postgres_client), &config, false).await;
+
+    cleanup_postgres_user(username).await;
+
+    // The account lives in the keystore of the other operator
+    assert!(find_drift(&drifts.unwrap(), &account_name).is_none(), "The user of another operator should be ignored");
+}
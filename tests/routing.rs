@@ -0,0 +1,175 @@
+use command_notifier::{
+    dedup::DedupConfig,
+    delivery_targets::{create_delivery_target, TargetConfig},
+    error::Error,
+    notification::{Notification, Priority},
+    outbound::enqueue_notification,
+    postgres::{delete_outbound_deliveries_of_user, delete_routing_of_user},
+    routing::{
+        create_routing_rule, get_history, get_routing_rules, remove_routing_rule, replace_routing_rule, route, HistoryPruner, RoutingRule,
+        RoutingRuleRequest, Rule, RuleAction, RuleConditions, Schedule, Weekday,
+    },
+    webhook::WebhookTarget,
+};
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+mod common;
+
+use common::utils::{cleanup_postgres_user, insert_dummy_nsc_user, setup_postgres_client};
+
+// 2024-01-01 was a monday
+fn monday_at(hours: u64, minutes: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_704_067_200 + hours * 3600 + minutes * 60)
+}
+
+fn quiet_hours(days: Vec<Weekday>) -> Schedule {
+    Schedule { start: "22:00".to_string(), end: "07:00".to_string(), days }
+}
+
+fn routing_rule(conditions: RuleConditions, action: RuleAction) -> RoutingRule {
+    RoutingRule { id: Uuid::new_v4(), user_id: Uuid::new_v4(), position: 0, rule: Rule { conditions, action }, created_at: String::new() }
+}
+
+fn rule_request(body: &str) -> RoutingRuleRequest {
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+fn test_schedule() {
+    let schedule = quiet_hours(Vec::new());
+    assert_eq!(schedule.remaining(monday_at(21, 59)), None);
+    assert_eq!(schedule.remaining(monday_at(22, 0)), Some(Duration::from_secs(9 * 3600)));
+    assert_eq!(schedule.remaining(monday_at(6, 30)), Some(Duration::from_secs(30 * 60)));
+    assert_eq!(schedule.remaining(monday_at(7, 0)), None);
+
+    // The night from sunday to monday, not the one from monday to tuesday
+    let schedule = quiet_hours(vec![Weekday::Sun]);
+    assert_eq!(schedule.remaining(monday_at(6, 30)), Some(Duration::from_secs(30 * 60)));
+    assert_eq!(schedule.remaining(monday_at(23, 0)), None);
+
+    let schedule = Schedule { start: "09:00".to_string(), end: "17:30".to_string(), days: vec![Weekday::Mon] };
+    assert_eq!(schedule.remaining(monday_at(17, 0)), Some(Duration::from_secs(30 * 60)));
+    assert_eq!(schedule.remaining(monday_at(8, 0)), None);
+}
+
+#[test]
+fn test_route() {
+    let backups = routing_rule(
+        RuleConditions { channel: Some("backups".to_string()), status: Some("success".to_string()), ..Default::default() },
+        RuleAction::History,
+    );
+    let quiet = routing_rule(
+        RuleConditions { max_priority: Some(Priority::High), schedule: Some(quiet_hours(Vec::new())), ..Default::default() },
+        RuleAction::Hold,
+    );
+    let target_id = Uuid::new_v4();
+    let important = routing_rule(
+        RuleConditions { min_priority: Some(Priority::High), ..Default::default() },
+        RuleAction::Deliver { nats: false, target_ids: Some(vec![target_id]) },
+    );
+    let rules = [backups.clone(), quiet.clone(), important.clone()];
+
+    let notification = Notification { message: "Backup done".to_string(), channel: Some("backups".to_string()), status: Some("success".to_string()), ..Default::default() };
+    let decision = route(&rules, &notification, monday_at(12, 0));
+    assert_eq!((decision.rule_id, decision.action, decision.nats, decision.target_ids), (Some(backups.id), "history", false, Some(Vec::new())));
+
+    let failed = Notification { status: Some("failure".to_string()), ..notification.clone() };
+    let decision = route(&rules, &failed, monday_at(12, 0));
+    assert_eq!((decision.rule_id, decision.action, decision.nats, decision.target_ids), (None, "deliver", true, None));
+
+    let decision = route(&rules, &failed, monday_at(23, 0));
+    assert_eq!((decision.rule_id, decision.action, decision.delay), (Some(quiet.id), "hold", Duration::from_secs(8 * 3600)));
+
+    let urgent = Notification { priority: Priority::Urgent, ..failed };
+    let decision = route(&rules, &urgent, monday_at(23, 0));
+    assert_eq!((decision.rule_id, decision.nats, decision.target_ids, decision.delay), (Some(important.id), false, Some(vec![target_id]), Duration::ZERO));
+}
+
+#[tokio::test]
+async fn test_routing_rules_crud_and_enqueue() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    let history_only = rule_request(r#"{"conditions": {"channel": "backups", "status": "success"}, "action": {"type": "history"}}"#);
+
+    let result = create_routing_rule(Arc::clone(&postgres_client), user_id, &history_only).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+
+    insert_dummy_nsc_user(user_id).await.unwrap();
+    let invalid_requests = [
+        r#"{"action": {"type": "hold"}}"#,
+        r#"{"conditions": {"schedule": {"start": "22:00", "end": "7h"}}, "action": {"type": "hold"}}"#,
+        r#"{"conditions": {"min_priority": "urgent", "max_priority": "low"}, "action": {"type": "history"}}"#,
+        r#"{"action": {"type": "deliver", "target_ids": ["6a2f41a3-c54c-fce8-32d2-0324e1c32e22"]}}"#,
+    ];
+    for request in invalid_requests {
+        let result = create_routing_rule(Arc::clone(&postgres_client), user_id, &rule_request(request)).await;
+        assert!(matches!(result, Err(Error::InvalidInput(_))), "{} should be invalid: {:?}", request, result);
+    }
+
+    let webhook = WebhookTarget { url: "https://example.com/hook".to_string(), method: "POST".to_string(), headers: BTreeMap::new(), secret: None, body_template: None };
+    let target = create_delivery_target(Arc::clone(&postgres_client), None, user_id, &TargetConfig::Webhook(webhook)).await.unwrap();
+    let rule = create_routing_rule(Arc::clone(&postgres_client), user_id, &history_only).await.unwrap();
+    let to_webhook = rule_request(&format!(r#"{{"action": {{"type": "deliver", "nats": false, "target_ids": ["{}"]}}}}"#, target.id));
+    let fallback = create_routing_rule(Arc::clone(&postgres_client), user_id, &to_webhook).await.unwrap();
+    assert_eq!((rule.position, fallback.position), (0, 1));
+
+    let backup = Notification { message: "Backup done".to_string(), channel: Some("backups".to_string()), status: Some("success".to_string()), ..Default::default() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &backup).await, Ok(0));
+    let failed = Notification { status: Some("failure".to_string()), ..backup.clone() };
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &failed).await, Ok(1));
+
+    let history = get_history(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(history.len(), 2);
+    assert!(history.iter().any(|entry| entry.rule_id == Some(rule.id) && entry.action == "history" && entry.notification["status"] == "success"));
+    assert!(history.iter().any(|entry| entry.rule_id == Some(fallback.id) && entry.action == "deliver"));
+
+    // Moved before the history rule, the backups go to the webhook too
+    let moved = replace_routing_rule(Arc::clone(&postgres_client), user_id, fallback.id, &RoutingRuleRequest { position: Some(-1), ..to_webhook }).await.unwrap();
+    assert_eq!(moved.position, -1);
+    let rules = get_routing_rules(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(rules.iter().map(|rule| rule.id).collect::<Vec<_>>(), vec![fallback.id, rule.id]);
+    assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &backup).await, Ok(1));
+
+    remove_routing_rule(Arc::clone(&postgres_client), user_id, rule.id).await.unwrap();
+    let result = remove_routing_rule(Arc::clone(&postgres_client), user_id, rule.id).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+    let result = replace_routing_rule(Arc::clone(&postgres_client), user_id, rule.id, &history_only).await;
+    assert!(matches!(result, Err(Error::NotFound(_))), "{:?}", result);
+
+    // The remaining rule and the 3 history entries
+    assert_eq!(delete_routing_of_user(Arc::clone(&postgres_client), user_id).await, Ok(4));
+    assert_eq!(delete_outbound_deliveries_of_user(Arc::clone(&postgres_client), user_id).await, Ok(2));
+    postgres_client.execute("DELETE FROM delivery_targets WHERE user_id = $1", &[&user_id]).await.unwrap();
+    cleanup_postgres_user(user_id).await;
+}
+
+#[tokio::test]
+async fn test_history_is_pruned() {
+    let postgres_client = Arc::new(setup_postgres_client().await);
+    let user_id = Uuid::new_v4();
+    insert_dummy_nsc_user(user_id).await.unwrap();
+
+    // Only in the history
+    let history_only = rule_request(r#"{"action": {"type": "history"}}"#);
+    create_routing_rule(Arc::clone(&postgres_client), user_id, &history_only).await.unwrap();
+    for message in ["old", "recent"] {
+        let notification = Notification { message: message.to_string(), ..Default::default() };
+        assert_eq!(enqueue_notification(Arc::clone(&postgres_client), &DedupConfig::default(), user_id, &notification).await, Ok(0));
+    }
+    postgres_client.execute(
+        "UPDATE notification_history SET created_at = now() - interval '31 days' WHERE user_id = $1 AND notification LIKE '%\"old\"%'",
+        &[&user_id]).await.unwrap();
+
+    let pruner = HistoryPruner { postgres_client: Arc::clone(&postgres_client) };
+    assert_eq!(pruner.prune(Duration::from_secs(30 * 24 * 60 * 60)).await, Ok(1));
+    let history = get_history(Arc::clone(&postgres_client), user_id).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].notification["message"], "recent");
+
+    delete_routing_of_user(Arc::clone(&postgres_client), user_id).await.unwrap();
+    cleanup_postgres_user(user_id).await;
+}
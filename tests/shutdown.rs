@@ -0,0 +1,95 @@
+use axum::{routing::post, Router};
+use axum_server::Handle;
+use command_notifier::{
+    error::Error,
+    shutdown::{join_workers, spawn_graceful_shutdown, ShutdownConfig},
+};
+
+use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+async fn start_server(handler_duration: Duration, timeout: Duration) -> (u16, tokio::sync::oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
+    let app = Router::new().route("/send/:user_id", post(move || async move {
+        tokio::time::sleep(handler_duration).await;
+        "Sent to user"
+    }));
+
+    let handle = Handle::new();
+    let (signal_sender, signal_receiver) = tokio::sync::oneshot::channel::<()>();
+    spawn_graceful_shutdown(handle.clone(), async move { let _result = signal_receiver.await; }, timeout);
+
+    let server = tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            axum_server::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+    });
+    let port = handle.listening().await.unwrap().port();
+    (port, signal_sender, server)
+}
+
+#[tokio::test]
+async fn test_in_flight_send_finishes_on_shutdown() {
+    let (port, signal_sender, server) = start_server(Duration::from_millis(500), Duration::from_secs(5)).await;
+    let url = format!("http://127.0.0.1:{}/send/7c278ecc-d624-45a0-aa87-9add7253b517", port);
+
+    let in_flight = tokio::spawn({
+        let url = url.clone();
+        async move { reqwest::Client::new().post(url).send().await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    signal_sender.send(()).unwrap();
+
+    let response = in_flight.await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "Sent to user");
+
+    tokio::time::timeout(Duration::from_secs(2), server).await.expect("Server should stop").unwrap();
+
+    // No new connection is accepted
+    assert!(reqwest::Client::new().post(&url).send().await.is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown_deadline() {
+    let (port, signal_sender, server) = start_server(Duration::from_secs(30), Duration::from_millis(200)).await;
+    let url = format!("http://127.0.0.1:{}/send/7c278ecc-d624-45a0-aa87-9add7253b517", port);
+
+    let in_flight = tokio::spawn(async move { reqwest::Client::new().post(url).send().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    signal_sender.send(()).unwrap();
+
+    // The stuck request does not hold the shutdown past the deadline
+    tokio::time::timeout(Duration::from_secs(2), server).await.expect("Server should stop").unwrap();
+    assert!(in_flight.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_workers_share_the_shutdown_deadline() {
+    let signal_time = Instant::now();
+    let deadline = spawn_graceful_shutdown(Handle::new(), async {}, Duration::from_millis(300)).await.unwrap();
+    assert!(deadline >= signal_time + Duration::from_millis(300) && deadline <= Instant::now() + Duration::from_millis(300));
+
+    // The time the drain took is not given again to the stuck worker
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let stuck_worker = tokio::spawn(std::future::pending::<()>());
+    join_workers(vec![stuck_worker], deadline).await;
+    assert!(signal_time.elapsed() < Duration::from_millis(450));
+}
+
+#[test]
+fn test_shutdown_config_from_env() {
+    env::remove_var("SHUTDOWN_TIMEOUT_SECS");
+    assert_eq!(ShutdownConfig::from_env(), Ok(ShutdownConfig::default()));
+    env::set_var("SHUTDOWN_TIMEOUT_SECS", "10");
+    assert_eq!(ShutdownConfig::from_env(), Ok(ShutdownConfig { timeout: Duration::from_secs(10) }));
+    env::set_var("SHUTDOWN_TIMEOUT_SECS", "30s");
+    assert!(matches!(ShutdownConfig::from_env(), Err(Error::InvalidInput(_))));
+    env::remove_var("SHUTDOWN_TIMEOUT_SECS");
+}
@@ -0,0 +1,142 @@
+use axum::{middleware::from_fn, routing::get, Router};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use command_notifier::{
+    error::Error,
+    tls::{load_server_config, require_client_certificate, spawn_tls_reload, ClientCertAcceptor, TlsConfig},
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+struct TestPki {
+    dir: String,
+    ca: Certificate,
+}
+
+impl TestPki {
+    fn new() -> TestPki {
+        let dir = env::temp_dir().join(format!("tls-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        let pki = TestPki { dir: dir.to_str().unwrap().to_string(), ca };
+        std::fs::write(pki.path("ca.pem"), pki.ca.serialize_pem().unwrap()).unwrap();
+        pki
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.dir, name)
+    }
+
+    // Writes <name>.pem and <name>.key, signed by the CA
+    fn issue(&self, name: &str) -> (String, String) {
+        let certificate = Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()])).unwrap();
+        let cert_pem = certificate.serialize_pem_with_signer(&self.ca).unwrap();
+        let key_pem = certificate.serialize_private_key_pem();
+        std::fs::write(self.path(&format!("{}.pem", name)), &cert_pem).unwrap();
+        std::fs::write(self.path(&format!("{}.key", name)), &key_pem).unwrap();
+        (cert_pem, key_pem)
+    }
+
+    fn tls_config(&self, client_auth: bool) -> TlsConfig {
+        TlsConfig {
+            cert_path: self.path("server.pem"),
+            key_path: self.path("server.key"),
+            client_ca_path: client_auth.then(|| self.path("ca.pem")),
+        }
+    }
+}
+
+impl Drop for TestPki {
+    fn drop(&mut self) {
+        let _result = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn test_load_server_config() {
+    let pki = TestPki::new();
+    pki.issue("server");
+
+    assert!(load_server_config(&pki.tls_config(false)).is_ok());
+    assert!(load_server_config(&pki.tls_config(true)).is_ok());
+
+    std::fs::write(pki.path("server.key"), "not a key").unwrap();
+    // A configuration error of the server, not of a request
+    assert!(matches!(load_server_config(&pki.tls_config(false)), Err(Error::Internal(_))));
+}
+
+#[tokio::test]
+async fn test_client_certificate_required_on_admin_routes() {
+    let pki = TestPki::new();
+    pki.issue("server");
+    let (client_cert_pem, client_key_pem) = pki.issue("client");
+
+    let admin_routes = Router::new()
+        .route("/admin", get(|| async { "admin" }))
+        .route_layer(from_fn(require_client_certificate));
+    let app = Router::new()
+        .route("/public", get(|| async { "public" }))
+        .merge(admin_routes);
+
+    let rustls_config = RustlsConfig::from_config(Arc::new(load_server_config(&pki.tls_config(true)).unwrap()));
+    let handle = Handle::new();
+    let server = axum_server::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .handle(handle.clone())
+        .acceptor(ClientCertAcceptor::new(rustls_config))
+        .serve(app.into_make_service());
+    tokio::spawn(server);
+    let port = handle.listening().await.unwrap().port();
+
+    let ca = reqwest::Certificate::from_pem(pki.ca.serialize_pem().unwrap().as_bytes()).unwrap();
+    let anonymous_client = reqwest::Client::builder()
+        .add_root_certificate(ca.clone())
+        .build()
+        .unwrap();
+    let identity = reqwest::Identity::from_pem(format!("{}{}", client_cert_pem, client_key_pem).as_bytes()).unwrap();
+    let authenticated_client = reqwest::Client::builder()
+        .add_root_certificate(ca)
+        .identity(identity)
+        .build()
+        .unwrap();
+
+    let url = |path: &str| format!("https://localhost:{}{}", port, path);
+
+    let response = anonymous_client.get(url("/public")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let response = anonymous_client.get(url("/admin")).send().await.unwrap();
+    assert_eq!(response.status(), 401);
+    let response = authenticated_client.get(url("/admin")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_certificates_reloaded_on_change() {
+    let pki = TestPki::new();
+    pki.issue("server");
+    let tls_config = pki.tls_config(false);
+
+    let rustls_config = RustlsConfig::from_config(Arc::new(load_server_config(&tls_config).unwrap()));
+    let initial_config = rustls_config.get_inner();
+    let reload = spawn_tls_reload(rustls_config.clone(), tls_config, Duration::from_millis(50));
+
+    // Invalid files are ignored, the previous certificates are kept
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    std::fs::write(pki.path("server.key"), "not a key").unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(Arc::ptr_eq(&initial_config, &rustls_config.get_inner()));
+
+    // Renewed certificate
+    pki.issue("server");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!Arc::ptr_eq(&initial_config, &rustls_config.get_inner()));
+
+    reload.abort();
+}
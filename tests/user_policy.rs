@@ -0,0 +1,92 @@
+use command_notifier::{
+    nsc_accounts_utils::{create_nsc_account, create_nsc_user_with_permissions, UserPermissions},
+    user_policy::{is_valid_expiry, UserPolicy},
+};
+
+use std::process::Command;
+
+mod common;
+
+use common::utils::cleanup_nsc_account;
+
+#[test]
+fn test_is_valid_expiry() {
+    for expiry in ["90d", "12w", "6M", "1y", "2026-12-31"] {
+        assert!(is_valid_expiry(expiry), "{:?} should be valid", expiry);
+    }
+    for expiry in ["", "d", "90", "90x", "1.5y", "2026/12/31", "26-12-31", "1é", "é", "9０d"] {
+        assert!(!is_valid_expiry(expiry), "{:?} should be invalid", expiry);
+    }
+}
+
+#[test]
+fn test_listener_permissions() {
+    let user_policy = UserPolicy {
+        notification_subject: "notifications".to_string(),
+        expiry: Some("90d".to_string()),
+        max_subscriptions: Some(10),
+        max_payload: Some(4096),
+    };
+    let permissions = user_policy.listener_permissions();
+
+    assert_eq!(permissions.allow_sub, ["notifications", "_INBOX.>"]);
+    assert!(!permissions.allow_pub.contains(&"notifications".to_string()));
+    let add_user_args = permissions.add_user_args();
+    assert!(add_user_args.windows(2).any(|args| args == ["--allow-sub", "notifications,_INBOX.>"]), "{:?}", add_user_args);
+    assert!(add_user_args.windows(2).any(|args| args == ["--expiry", "90d"]), "{:?}", add_user_args);
+    assert_eq!(permissions.edit_user_args(), ["--subs", "10", "--payload", "4096"]);
+}
+
+#[test]
+fn test_publisher_permissions() {
+    let permissions = UserPolicy::default().publisher_permissions();
+
+    assert_eq!(permissions.allow_pub, ["topic01"]);
+    assert_eq!(permissions.deny_sub, [">"]);
+    assert_eq!(permissions.expiry, None);
+    assert!(permissions.edit_user_args().is_empty());
+}
+
+#[test]
+fn test_default_permissions_have_no_args() {
+    let permissions = UserPermissions::default();
+    assert!(permissions.add_user_args().is_empty());
+    assert!(permissions.edit_user_args().is_empty());
+}
+
+#[test]
+fn test_issued_user_jwt_permissions() {
+    let account_name = format!("policy-test-{}", std::process::id());
+    let user_policy = UserPolicy {
+        notification_subject: "notifications".to_string(),
+        expiry: Some("90d".to_string()),
+        max_subscriptions: Some(10),
+        max_payload: Some(4096),
+    };
+    create_nsc_account(&account_name).unwrap();
+    create_nsc_user_with_permissions(&account_name, "user_01", &user_policy.listener_permissions()).unwrap();
+    create_nsc_user_with_permissions(&account_name, "admin_01", &user_policy.publisher_permissions()).unwrap();
+
+    // The claims signed in the user JWTs
+    let describe_user = |username: &str| -> serde_json::Value {
+        let output = Command::new("nsc")
+            .args(["describe", "user", "--account", &account_name, "--name", username, "--json"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        serde_json::from_slice(&output.stdout).unwrap()
+    };
+    let listener = describe_user("user_01");
+    let publisher = describe_user("admin_01");
+    cleanup_nsc_account(&account_name);
+
+    assert_eq!(listener["nats"]["sub"]["allow"], serde_json::json!(["notifications", "_INBOX.>"]));
+    assert!(!listener["nats"]["pub"]["allow"].as_array().unwrap().contains(&serde_json::json!("notifications")));
+    assert_eq!((listener["nats"]["subs"].as_i64(), listener["nats"]["payload"].as_i64()), (Some(10), Some(4096)));
+    assert!(listener["exp"].is_number(), "{}", listener);
+
+    assert_eq!(publisher["nats"]["pub"]["allow"], serde_json::json!(["notifications"]));
+    assert_eq!(publisher["nats"]["sub"]["deny"], serde_json::json!([">"]));
+    assert_eq!(publisher["nats"]["payload"].as_i64(), Some(4096));
+    assert!(publisher.get("exp").is_none(), "{}", publisher);
+}